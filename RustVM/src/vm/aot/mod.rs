@@ -0,0 +1,440 @@
+﻿use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use crate::vm::compiled_module::{load_module, CompiledModule};
+use crate::vm::disassembler::decode;
+use crate::vm::entry_point::entry_name;
+use crate::vm::opcodes::VMCommand_Cmd;
+use crate::vm::predecode::{decode_program, DecodedProgram, Dst, Op, Src};
+
+// Ahead-of-time translation of module to a standalone Rust program.
+// Each function becomes a Rust function working on Memory, jumps inside of it become a loop over basic blocks.
+// Calls push the same return address as interpreter, so stack frames look the same.
+// Usage: RustVM translate <module.asc> <out.rs> [--entry <Type.Function>] [--env-allow <NAME,...>]
+// Then the program is built with: rustc --edition 2024 -O <out.rs>
+
+const RUNTIME: &str = include_str!("runtime.rs");
+const MEMORY: &str = include_str!("../memory.rs");
+const CLOCK: &str = include_str!("../clock.rs");
+const RANDOM: &str = include_str!("../random.rs");
+
+pub fn translate_command(args: &[String])
+{
+    let (module_path, out_path) = match (args.first(), args.get(1))
+    {
+        (Some(module_path), Some(out_path)) => (module_path, out_path),
+        _ => {
+            println!("Usage: translate <module.asc> <out.rs> [--entry <Type.Function>] [--env-allow <NAME,...>]");
+            return;
+        }
+    };
+
+    let mut entry: Option<String> = None;
+    let mut env_allow_list = Vec::new();
+    let mut i = 2;
+    while i < args.len()
+    {
+        let value = args.get(i + 1).unwrap_or_else(|| panic!("Option '{}' requires a value", args[i]));
+        match args[i].as_str()
+        {
+            "--entry" => entry = Some(value.clone()),
+            "--env-allow" => env_allow_list.extend(value.split(',').filter(|name| !name.is_empty()).map(String::from)),
+            arg => panic!("Unknown option '{arg}'")
+        }
+        i += 2;
+    }
+
+    let module = load_module(Path::new(module_path));
+    let entry = entry_name(&module, entry);
+
+    let source = translate(&module, entry.as_deref(), &env_allow_list);
+    fs::write(out_path, source).unwrap_or_else(|e| panic!("Failed to write {out_path}: {e}"));
+    println!("Translated {module_path} to {out_path}");
+}
+
+// Part of byte code translated to one Rust function
+struct Region
+{
+    // Instruction indexes
+    start: usize,
+    end: usize,
+    name: String,
+    title: String,
+}
+
+pub fn translate(module: &CompiledModule, entry: Option<&str>, env_allow_list: &[String]) -> String
+{
+    let bytes = &module.managed_code.bytes;
+    let program = decode_program(bytes).unwrap_or_else(|| panic!("Failed to decode byte code"));
+
+    // Functions start regions, code before the first one is startup
+    let mut starts: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    starts.insert(0, Vec::new());
+    for (function_index, function_info) in module.table.functions.iter().enumerate()
+    {
+        if function_info.pointed_module == 0
+        {
+            let index = program.index_of(function_info.pointed_opcode as usize)
+                .unwrap_or_else(|| panic!("Function '{}' points to the middle of instruction", function_info.name));
+            starts.entry(index).or_default().push(function_index);
+        }
+    }
+
+    let mut regions = Vec::new();
+    let mut function_names = BTreeMap::new();
+    let bounds: Vec<usize> = starts.keys().copied().chain([program.ops.len()]).collect();
+
+    for (i, (start, functions)) in starts.iter().enumerate()
+    {
+        let (name, title) = match functions.first()
+        {
+            Some(function_index) => {
+                let names: Vec<String> = functions.iter().map(|index| module.function_full_name(*index)).collect();
+                (format!("function_{function_index}"), names.join(", "))
+            },
+            None => (String::from("startup"), String::from("<startup>"))
+        };
+        for function_index in functions
+        {
+            function_names.insert(*function_index, name.clone());
+        }
+
+        if *start < bounds[i + 1]
+        {
+            regions.push(Region {
+                start: *start,
+                end: bounds[i + 1],
+                name,
+                title,
+            });
+        }
+    }
+
+    let mut translator = Translator {
+        module,
+        program: &program,
+        function_names,
+        code: String::new(),
+    };
+
+    let mut code = String::new();
+    writeln!(code, "// Translated by RustVM translate. Build with: rustc --edition 2024 -O <this file>").unwrap();
+    writeln!(code, "#![allow(dead_code, unused)]").unwrap();
+    writeln!(code).unwrap();
+    code.push_str(RUNTIME.trim_start_matches('\u{feff}'));
+    for (name, source) in [("memory", MEMORY), ("clock", CLOCK), ("random", RANDOM)]
+    {
+        writeln!(code, "\nmod {name}\n{{\n{}\n}}", source.trim_start_matches('\u{feff}')).unwrap();
+    }
+
+    let env_allow_list: Vec<String> = env_allow_list.iter().map(|name| format!("{name:?}")).collect();
+    writeln!(code, "\nconst ENV_ALLOW_LIST: &[&str] = &[{}];\n", env_allow_list.join(", ")).unwrap();
+
+    code.push_str(&translator.main(entry, &regions));
+    for (i, region) in regions.iter().enumerate()
+    {
+        let next = regions.get(i + 1).map(|next| next.name.as_str());
+        translator.region(region, next);
+    }
+    code.push_str(&translator.code);
+
+    code
+}
+
+struct Translator<'a>
+{
+    module: &'a CompiledModule,
+    program: &'a DecodedProgram,
+    // Rust function by guest function index
+    function_names: BTreeMap<usize, String>,
+    code: String,
+}
+
+impl Translator<'_>
+{
+    // Runs startup code, or only data section and entry function
+    fn main(&self, entry: Option<&str>, regions: &[Region]) -> String
+    {
+        let mut code = String::new();
+        writeln!(code, "fn main()\n{{").unwrap();
+        writeln!(code, "    let mut m = Memory::new();").unwrap();
+        writeln!(code, "    let mut rt = Runtime::new(&mut m);").unwrap();
+
+        match entry
+        {
+            None => {
+                writeln!(code, "    {}(&mut m, &mut rt);", regions[0].name).unwrap();
+                writeln!(code, "    std::process::exit(m.read_int(m.data_section_size));").unwrap();
+            },
+            Some(entry) => {
+                let function_index = self.module.find_function(entry).unwrap_or_else(|| panic!("Entry function '{entry}' not found"));
+                let function_info = &self.module.table.functions[function_index];
+                if !function_info.arguments.is_empty()
+                {
+                    panic!("Entry function '{entry}' with arguments is not supported")
+                }
+                if function_info.pointed_module != 0
+                {
+                    panic!("Entry function '{entry}' is not managed")
+                }
+
+                // Data section is always placed at the beginning of byte code
+                if let Some(Op::Section { data: Some(data) }) = self.program.ops.first()
+                {
+                    writeln!(code, "    m.place_data_section(&{:?});", self.program.bytes(*data)).unwrap();
+                }
+
+                // The same frame as VM::push_call_frame makes
+                let returns_size: i32 = function_info.returns.iter().map(|r| self.module.type_size(*r)).sum();
+                writeln!(code, "    let returns_address = m.allocate_stack({returns_size});").unwrap();
+                writeln!(code, "    m.write_slice(returns_address, &[0; {returns_size}]);").unwrap();
+                writeln!(code, "    m.push_int(-1);").unwrap();
+                writeln!(code).unwrap();
+
+                // Exit code is the first value returned by entry function, as read_exit_code does
+                let exit_code = match function_info.returns.first().map(|r| (self.module.type_name(*r), self.module.type_size(*r)))
+                {
+                    Some(("bool" | "byte", _)) => "m.read(returns_address, 1)[0] as i32",
+                    Some(("short", _)) => "i16::read(&m, returns_address) as i32",
+                    Some(("long", _)) => "i64::read(&m, returns_address) as i32",
+                    Some(("int", _)) => "m.read_int(returns_address)",
+                    Some(("string", _)) => "0",
+                    Some((_, 4)) => "m.read_int(returns_address)",
+                    _ => "0"
+                };
+                writeln!(code, "    let exit_code = match {}(&mut m, &mut rt)\n    {{", self.function_names[&function_index]).unwrap();
+                writeln!(code, "        Flow::Returned => {exit_code},").unwrap();
+                writeln!(code, "        Flow::Exited => m.read_int(returns_address),").unwrap();
+                writeln!(code, "    }};").unwrap();
+                writeln!(code, "    std::process::exit(exit_code);").unwrap();
+            }
+        }
+
+        writeln!(code, "}}").unwrap();
+        code
+    }
+
+    fn region(&mut self, region: &Region, next: Option<&str>)
+    {
+        // Basic blocks start at jump targets and after jumps
+        let mut blocks = BTreeMap::new();
+        blocks.insert(region.start, 0);
+        for index in region.start..region.end
+        {
+            let op = &self.program.ops[index];
+            if let Some(target) = op.target()
+            {
+                let target = target as usize;
+                if target < region.start || target >= region.end
+                {
+                    panic!("Jump at 0x{:04X} leaves {}", self.program.offsets[index], region.title)
+                }
+                blocks.insert(target, 0);
+            }
+            if matches!(op, Op::Jump { .. } | Op::JumpIfFalse { .. } | Op::Return | Op::Exit) && index + 1 < region.end
+            {
+                blocks.insert(index + 1, 0);
+            }
+        }
+        for (id, block) in blocks.values_mut().enumerate()
+        {
+            *block = id;
+        }
+
+        // Falling through the end continues in the next function, as interpreter does
+        let fall_through = match next
+        {
+            Some(next) => format!("return {next}(m, rt);"),
+            None => String::from("return Flow::Exited;")
+        };
+
+        writeln!(self.code, "\n// {}\nfn {}(m: &mut Memory, rt: &mut Runtime) -> Flow\n{{", region.title, region.name).unwrap();
+        writeln!(self.code, "    let mut block = 0;").unwrap();
+        writeln!(self.code, "    loop\n    {{\n        match block\n        {{").unwrap();
+
+        let starts: Vec<usize> = blocks.keys().copied().chain([region.end]).collect();
+        for (id, range) in starts.windows(2).enumerate()
+        {
+            writeln!(self.code, "            {id} => {{").unwrap();
+            for index in range[0]..range[1]
+            {
+                let offset = self.program.offsets[index];
+                let instruction = decode(&self.module.managed_code.bytes, offset).unwrap();
+                writeln!(self.code, "                // 0x{:04X} {}", offset, instruction.describe(self.module)).unwrap();
+
+                for line in self.statement(index, &blocks).lines()
+                {
+                    writeln!(self.code, "                {line}").unwrap();
+                }
+            }
+
+            let last = &self.program.ops[range[1] - 1];
+            if !matches!(last, Op::Jump { .. } | Op::Return | Op::Exit)
+            {
+                match range[1] < region.end
+                {
+                    true => writeln!(self.code, "                block = {};", id + 1).unwrap(),
+                    false => writeln!(self.code, "                {fall_through}").unwrap()
+                }
+            }
+            writeln!(self.code, "            }},").unwrap();
+        }
+
+        writeln!(self.code, "            _ => unreachable!()").unwrap();
+        writeln!(self.code, "        }}\n    }}\n}}").unwrap();
+    }
+
+    fn statement(&self, index: usize, blocks: &BTreeMap<usize, usize>) -> String
+    {
+        let program = self.program;
+
+        match &program.ops[index]
+        {
+            Op::Nop => String::new(),
+            Op::AllocateStack { value } => format!("allocate_stack(m, &{:?});", program.bytes(*value)),
+            Op::PushVariable { variable, size } => format!("push_variable(m, {variable}, {size});"),
+            Op::AllocateHeap { storage, size } => format!("allocate_heap(m, {storage}, {size});"),
+            Op::DeallocateStack { size } => format!("m.deallocate_stack({size});"),
+            Op::Prologue => String::from("prologue(m);"),
+            Op::Epilogue => String::from("epilogue(m);"),
+            Op::Call { function } => {
+                let function_info = &self.module.table.functions[*function as usize];
+                match self.function_names.get(&(*function as usize))
+                {
+                    Some(name) => format!("m.push_int({});\nif let Flow::Exited = {name}(m, rt) {{ return Flow::Exited }}", program.offsets[index]),
+                    None => {
+                        eprintln!("Warning: native function '{}' is not available in translated program", function_info.name);
+                        format!("panic!(\"Native function '{}' is not available in translated program\");", function_info.name)
+                    }
+                }
+            },
+            Op::Return => String::from("m.pop_int();\nreturn Flow::Returned;"),
+            Op::Jump { target } => format!("block = {};\ncontinue;", blocks[&(*target as usize)]),
+            Op::JumpIfFalse { target, condition, size } => {
+                format!("if is_false(m, {condition}, {size}) {{ block = {}; continue; }}", blocks[&(*target as usize)])
+            },
+            Op::Exit => String::from("return Flow::Exited;"),
+            Op::Mov { dst, src } => {
+                let dst = match dst
+                {
+                    Dst::Rbp(offset) => format!("m.to_abs({offset})"),
+                    Dst::Deref(offset) => format!("m.read_int(m.to_abs({offset}))"),
+                };
+                let write = match src
+                {
+                    Src::Rbp(offset, size) => format!("m.copy(m.to_abs({offset}), dst, {size});"),
+                    Src::Const(value) => format!("m.write_slice(dst, &{:?});", program.bytes(*value)),
+                    Src::Abs(address, size) => format!("m.copy({address}, dst, {size});"),
+                };
+                format!("let dst = {dst};\n{write}")
+            },
+            Op::Binary { op, a, b, result, size } => {
+                let operator = ["+", "-", "*", "/", "%", "<<", ">>", "&", "|"][*op as usize];
+                number(*size, |t| format!("binary::<{t}>(m, {a}, {b}, {result}, |a, b| a {operator} b);"))
+            },
+            Op::Unary { op, value, size } => {
+                let operator = ["+", "-"][*op as usize];
+                number(*size, |t| format!("unary::<{t}>(m, {value}, |a| a {operator} 1);"))
+            },
+            Op::Compare { a, b, size, result, op } => match ["==", "!=", ">", ">=", "<", "<="].get(*op as usize)
+            {
+                Some(operator) => number(*size, |t| format!("compare::<{t}>(m, {a}, {b}, {result}, |a, b| a {operator} b);")),
+                None => format!("panic!(\"Invalid compare operator {op}\");")
+            },
+            Op::Negate { a, result, size } => number(*size, |t| format!("negate::<{t}>(m, {a}, {result});")),
+            Op::ToPtrValueType { variable, result } => format!("m.write_int(m.to_abs({result}), m.to_abs({variable}));"),
+            Op::ToPtrRefType { variable, result } => format!("to_ptr_ref_type(m, {variable}, {result});"),
+            Op::PtrGet { pointer, result, size } => format!("ptr_get(m, {pointer}, {result}, {size});"),
+            Op::PtrSet { pointer, value, size } => format!("ptr_set(m, {pointer}, {value}, {size});"),
+            Op::PtrShift { pointer, shift } => format!("ptr_shift(m, {pointer}, {shift});"),
+            Op::PtrShiftBy { pointer, shift, additional } => format!("ptr_shift_by(m, {pointer}, {shift}, {additional});"),
+            Op::FieldAccess { base, field_offset, size, is_getter, result } => {
+                format!("field_access(m, {base}, {field_offset}, {size}, {is_getter}, {result});")
+            },
+            Op::Legacy => String::from("panic!(\"Legacy method\");"),
+            Op::Cast { variable, variable_size, result, result_size } => format!("cast(m, {variable}, {variable_size}, {result}, {result_size});"),
+            Op::Section { data } => match data
+            {
+                Some(data) => format!("m.place_data_section(&{:?});", program.bytes(*data)),
+                None => String::new()
+            },
+            Op::VMCommand { cmd, arguments } => {
+                let arguments: Vec<String> = arguments.iter().map(|a| format!("arg({}, {}, {})", a.rbp, a.size_in_bytes, a.type_index)).collect();
+                match command_function(*cmd)
+                {
+                    Ok(function) => format!("{function}(m, rt, &[{}]);", arguments.join(", ")),
+                    Err(message) => {
+                        eprintln!("Warning: {message}");
+                        format!("panic!(\"{message}\");")
+                    }
+                }
+            },
+            Op::CompareJumpIfFalse { .. } | Op::UnaryJump { .. } | Op::MovBinary { .. } | Op::CheckStack { .. } => unreachable!("Translator works on not optimized program")
+        }
+    }
+}
+
+// Number instructions panic at runtime on sizes interpreter doesn't support
+fn number(size: u8, statement: impl Fn(&str) -> String) -> String
+{
+    match size
+    {
+        1 => statement("i8"),
+        2 => statement("i16"),
+        4 => statement("i32"),
+        8 => statement("i64"),
+        _ => format!("panic!(\"Not supported number size ({size} bytes)\");")
+    }
+}
+
+// Runtime function implementing console VM command
+fn command_function(cmd: u8) -> Result<&'static str, String>
+{
+    let cmd = VMCommand_Cmd::try_from(cmd).map_err(|_| format!("Invalid VM command = {cmd}"))?;
+
+    let function = match cmd
+    {
+        VMCommand_Cmd::Print => "print",
+        VMCommand_Cmd::Sleep => "sleep",
+        VMCommand_Cmd::TimeNow => "time_now",
+        VMCommand_Cmd::TimeMonotonicMs => "time_monotonic_ms",
+        VMCommand_Cmd::TimeMonotonicNs => "time_monotonic_ns",
+        VMCommand_Cmd::TimeElapsed => "time_elapsed",
+        VMCommand_Cmd::RandomInt => "random_int",
+        VMCommand_Cmd::RandomBytes => "random_bytes",
+        VMCommand_Cmd::RandomSeed => "random_seed",
+        VMCommand_Cmd::GetArgs => "get_args",
+        VMCommand_Cmd::GetEnv => "get_env",
+        cmd => return Err(format!("VM command {:?} is not available in translated program", cmd))
+    };
+
+    Ok(function)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::env;
+    use std::process::Command;
+    use super::*;
+    use crate::vm::bench::call_module;
+
+    #[test]
+    fn translated_program_builds_and_runs()
+    {
+        let folder = env::temp_dir().join(format!("rustvm-aot-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let source_path = folder.join("program.rs");
+        let binary_path = folder.join("program");
+
+        fs::write(&source_path, translate(&call_module(100), None, &[])).unwrap();
+        let output = Command::new("rustc").arg("--edition").arg("2024").arg("-o").arg(&binary_path).arg(&source_path).output().unwrap();
+        assert!(output.status.success(), "rustc failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+        // Exit code is the loop counter
+        let status = Command::new(&binary_path).status().unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(status.code(), Some(100));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,370 @@
+﻿// Runtime of programs made by `RustVM translate`. This file is not compiled into the VM:
+// translator copies it into every generated program together with VM's memory.rs, clock.rs and random.rs.
+// Instructions and console VM commands behave the same as in vm/functions. File commands, windows and
+// native functions are not available.
+
+use std::env;
+use std::io::{stdout, Write};
+use std::ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Rem, Shl, Shr, Sub};
+use std::time::Duration;
+use clock::{Clock, SystemClock};
+use memory::Memory;
+use random::Random;
+
+pub enum Flow
+{
+    Returned,
+    // Exit instruction, the whole program stops
+    Exited,
+}
+
+pub struct Runtime
+{
+    pub clock: SystemClock,
+    pub random: Random,
+    pub args_address: i32,
+}
+
+impl Runtime
+{
+    // Places process arguments on heap the same way as VM::place_args
+    pub fn new(m: &mut Memory) -> Self
+    {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let size: i64 = 4 + args.iter().map(|arg| 8 + arg.len() as i64).sum::<i64>();
+        if size > m.heap_left() as i64
+        {
+            panic!("Arguments need {size} bytes of heap, but only {} bytes are available", m.heap_left())
+        }
+        let pointers: Vec<i32> = args.iter().map(|arg| m.allocate_heap_string(arg)).collect();
+
+        let args_address = m.allocate_heap(4 + 4 * pointers.len() as i32);
+        m.write_int(args_address, pointers.len() as i32);
+
+        for (i, pointer) in pointers.iter().enumerate()
+        {
+            m.write_int(args_address + 4 + 4 * i as i32, *pointer);
+        }
+
+        Self {
+            clock: SystemClock::new(),
+            random: Random::from_time(),
+            args_address,
+        }
+    }
+}
+
+pub trait Number: Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Rem<Output = Self>
+    + Shl<Self, Output = Self> + Shr<Self, Output = Self> + BitAnd<Output = Self> + BitOr<Output = Self> + Neg<Output = Self>
+{
+    fn read(m: &Memory, address: i32) -> Self;
+    fn write(self, m: &mut Memory, address: i32);
+}
+
+macro_rules! number {
+    ($($t:ty),*) => {
+        $(
+            impl Number for $t
+            {
+                fn read(m: &Memory, address: i32) -> Self
+                {
+                    <$t>::from_ne_bytes(m.read(address, size_of::<$t>() as i32).try_into().unwrap())
+                }
+                fn write(self, m: &mut Memory, address: i32)
+                {
+                    m.write_slice(address, &self.to_ne_bytes());
+                }
+            }
+        )*
+    };
+}
+number!(i8, i16, i32, i64);
+
+pub fn binary<T: Number>(m: &mut Memory, a: i32, b: i32, result: i32, op: fn(T, T) -> T)
+{
+    let value = op(T::read(m, m.to_abs(a)), T::read(m, m.to_abs(b)));
+    let result = m.to_abs(result);
+    value.write(m, result);
+}
+
+pub fn unary<T: Number>(m: &mut Memory, value: i32, op: fn(T) -> T)
+{
+    let address = m.to_abs(value);
+    op(T::read(m, address)).write(m, address);
+}
+
+pub fn compare<T: Number>(m: &mut Memory, a: i32, b: i32, result: i32, op: fn(T, T) -> bool)
+{
+    let value = op(T::read(m, m.to_abs(a)), T::read(m, m.to_abs(b)));
+    m.write_byte(m.to_abs(result), value as u8);
+}
+
+pub fn negate<T: Number>(m: &mut Memory, a: i32, result: i32)
+{
+    let value = -T::read(m, m.to_abs(a));
+    let result = m.to_abs(result);
+    value.write(m, result);
+}
+
+pub fn is_false(m: &Memory, condition: i32, size: u8) -> bool
+{
+    m.read(m.to_abs(condition), size as i32).iter().all(|b| *b == 0)
+}
+
+pub fn allocate_stack(m: &mut Memory, value: &[u8])
+{
+    let address = m.allocate_stack(value.len() as i32);
+    m.write_slice(address, value);
+}
+
+pub fn push_variable(m: &mut Memory, variable: i32, size: u8)
+{
+    let variable_address = m.to_abs(variable);
+    let address = m.allocate_stack(size as i32);
+    m.copy(variable_address, address, size as i32);
+}
+
+pub fn allocate_heap(m: &mut Memory, storage: i32, size: i32)
+{
+    let storage_address = m.to_abs(storage);
+    let pointer = m.allocate_heap(size);
+    m.write_int(storage_address, pointer);
+}
+
+pub fn prologue(m: &mut Memory)
+{
+    m.push_int(m.base_pointer);
+    m.base_pointer = m.stack_pointer;
+}
+
+pub fn epilogue(m: &mut Memory)
+{
+    m.stack_pointer = m.base_pointer;
+    m.base_pointer = m.pop_int();
+}
+
+pub fn to_ptr_ref_type(m: &mut Memory, variable: i32, result: i32)
+{
+    let value_address = m.read_int(m.to_abs(variable));
+    m.write_int(m.to_abs(result), value_address);
+}
+
+pub fn ptr_get(m: &mut Memory, pointer: i32, result: i32, size: u8)
+{
+    let depointed_address = m.read_int(m.to_abs(pointer));
+    m.copy(depointed_address, m.to_abs(result), size as i32);
+}
+
+pub fn ptr_set(m: &mut Memory, pointer: i32, value: i32, size: u8)
+{
+    let depointed_address = m.read_int(m.to_abs(pointer));
+    m.copy(m.to_abs(value), depointed_address, size as i32);
+}
+
+pub fn ptr_shift(m: &mut Memory, pointer: i32, shift: i32)
+{
+    let pointer_address = m.to_abs(pointer);
+    m.write_int(pointer_address, m.read_int(pointer_address) + shift);
+}
+
+pub fn ptr_shift_by(m: &mut Memory, pointer: i32, shift: i32, additional: i32)
+{
+    let pointer_address = m.to_abs(pointer);
+    let shift_value = m.read_int(m.to_abs(shift)) + additional;
+    m.write_int(pointer_address, m.read_int(pointer_address) + shift_value);
+}
+
+pub fn field_access(m: &mut Memory, base: i32, field_offset: i32, size: u8, is_getter: u8, result: i32)
+{
+    let result_address = m.to_abs(result);
+    let field_pointer = m.read_int(m.to_abs(base)) + field_offset;
+
+    if is_getter > 0
+    {
+        m.copy(field_pointer, result_address, size as i32);
+    }
+    else
+    {
+        m.write_int(result_address, field_pointer);
+    }
+}
+
+pub fn cast(m: &mut Memory, variable: i32, variable_size: u8, result: i32, result_size: u8)
+{
+    let (variable_address, result_address) = (m.to_abs(variable), m.to_abs(result));
+    let variable_value = m.read(variable_address, variable_size as i32).to_vec();
+
+    for i in 0..result_size as i32
+    {
+        m.write_byte(result_address + i, variable_value.get(i as usize).copied().unwrap_or(0));
+    }
+}
+
+// VM commands
+
+pub struct Argument
+{
+    pub rbp: i32,
+    pub size_in_bytes: u8,
+    pub type_index: u8,
+}
+
+pub const fn arg(rbp: i32, size_in_bytes: u8, type_index: u8) -> Argument
+{
+    Argument {
+        rbp,
+        size_in_bytes,
+        type_index
+    }
+}
+
+fn read_argument_int(m: &Memory, argument: &Argument) -> i64
+{
+    let value = m.read(m.to_abs(argument.rbp), argument.size_in_bytes as i32);
+
+    match argument.type_index {
+        0 | 1 => value[0] as i64,
+        2 => i16::from_ne_bytes(value.try_into().unwrap()) as i64,
+        3 | 5 => i32::from_ne_bytes(value.try_into().unwrap()) as i64,
+        4 => i64::from_ne_bytes(value.try_into().unwrap()),
+        _ => panic!("Failed to read argument as integer due to invalid type_index = {}", argument.type_index)
+    }
+}
+
+fn read_argument_string(m: &Memory, argument: &Argument) -> Option<String>
+{
+    if argument.type_index != 6
+    {
+        panic!("Failed to read argument as string due to invalid type_index = {}", argument.type_index)
+    }
+
+    let ptr_address = m.read_int(m.to_abs(argument.rbp));
+    if m.is_valid_range(ptr_address, 4) == false
+    {
+        return None
+    }
+    let str_len = m.read_int(ptr_address);
+    if m.is_valid_range(ptr_address + 4, str_len) == false
+    {
+        return None
+    }
+
+    String::from_utf8(Vec::from(m.read(ptr_address + 4, str_len))).ok()
+}
+
+fn write_argument_int(m: &mut Memory, argument: &Argument, value: i64)
+{
+    let address = m.to_abs(argument.rbp);
+    m.write_slice(address, &value.to_ne_bytes()[..argument.size_in_bytes as usize]);
+}
+
+pub fn print(m: &mut Memory, _rt: &mut Runtime, arguments: &[Argument])
+{
+    let mut line = String::new();
+
+    for arg in arguments
+    {
+        let value = m.read(m.to_abs(arg.rbp), arg.size_in_bytes as i32);
+
+        let text = match arg.type_index {
+            0 => format!("{}", value[0] > 0),
+            1 => format!("{}", value[0]),
+            2 => format!("{}", i16::from_ne_bytes(value.try_into().unwrap())),
+            3 => format!("{}", i32::from_ne_bytes(value.try_into().unwrap())),
+            4 => format!("{}", i64::from_ne_bytes(value.try_into().unwrap())),
+            5 => format!("<0x{:X}>", i32::from_ne_bytes(value.try_into().unwrap())),
+            6 => read_argument_string(m, arg).unwrap_or_else(|| String::from("<invalid string>")),
+            _ => panic!("Failed to print argument with type_index = {}", arg.type_index)
+        };
+        line.push_str(&text);
+    }
+
+    writeln!(stdout(), "{line}").unwrap();
+}
+
+pub fn sleep(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let duration = read_argument_int(m, &arguments[0]).max(0) as u64;
+    rt.clock.sleep(Duration::from_millis(duration));
+}
+
+pub fn time_now(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    write_argument_int(m, &arguments[0], rt.clock.unix_time_ms());
+}
+
+pub fn time_monotonic_ms(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    write_argument_int(m, &arguments[0], (rt.clock.monotonic_ns() / 1_000_000) as i64);
+}
+
+pub fn time_monotonic_ns(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    write_argument_int(m, &arguments[0], rt.clock.monotonic_ns() as i64);
+}
+
+pub fn time_elapsed(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let since_ms = read_argument_int(m, &arguments[1]);
+    let monotonic_ms = (rt.clock.monotonic_ns() / 1_000_000) as i64;
+    write_argument_int(m, &arguments[0], monotonic_ms - since_ms);
+}
+
+pub fn random_int(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let min = read_argument_int(m, &arguments[1]);
+    let max = read_argument_int(m, &arguments[2]);
+    write_argument_int(m, &arguments[0], rt.random.next_range(min, max));
+}
+
+pub fn random_bytes(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let buffer_address = read_argument_int(m, &arguments[1]) as i32;
+    let count = read_argument_int(m, &arguments[2]) as i32;
+
+    let mut result = -1;
+
+    if m.is_valid_range(buffer_address, count)
+    {
+        let mut buffer = vec![0; count as usize];
+        rt.random.fill(&mut buffer);
+        m.write_slice(buffer_address, &buffer);
+        result = count as i64;
+    }
+
+    write_argument_int(m, &arguments[0], result);
+}
+
+pub fn random_seed(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let seed = read_argument_int(m, &arguments[0]);
+    rt.random.reseed(seed as u64);
+}
+
+pub fn get_args(m: &mut Memory, rt: &mut Runtime, arguments: &[Argument])
+{
+    let args_count = m.read_int(rt.args_address);
+    write_argument_int(m, &arguments[0], rt.args_address as i64);
+
+    if arguments.len() > 1
+    {
+        write_argument_int(m, &arguments[1], args_count as i64);
+    }
+}
+
+// Only variables allowed at translation time are visible
+pub fn get_env(m: &mut Memory, _rt: &mut Runtime, arguments: &[Argument])
+{
+    let name = read_argument_string(m, &arguments[1]);
+
+    let mut pointer = 0;
+    if let Some(name) = name.filter(|name| ENV_ALLOW_LIST.contains(&name.as_str()))
+    {
+        if let Ok(value) = env::var(&name)
+        {
+            pointer = m.try_allocate_heap_string(&value).unwrap_or(0);
+        }
+    }
+
+    write_argument_int(m, &arguments[0], pointer as i64);
+}
\ No newline at end of file
@@ -0,0 +1,336 @@
+﻿use std::io::sink;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::vm::binary_file::SharedBytes;
+use crate::vm::compiled_module::{deserialize_module_from_bytes, CompiledModule, FieldInfo_Blit, FunctionInfo_Blit, ManagedCode, MetaTable, TypeInfo_Blit};
+use crate::vm::functions::get_functions;
+use crate::vm::memory::Memory;
+use crate::vm::opcodes::OpCode;
+use crate::vm::stack_trace::{run_guarded, run_instruction};
+use crate::vm::vm::VM;
+
+// Compares the usual interpreter loop with the pre-decoded one (with and without optimizer and JIT) on the same module.
+// Usage: RustVM bench [module.asc] [runs]. Without module built-in counting loop and loop calling a small function are used.
+
+const LOOP_ITERATIONS: i32 = 1_000_000;
+const CALL_ITERATIONS: i32 = 300_000;
+
+pub fn bench(args: &[String])
+{
+    match args.first()
+    {
+        Some(path) => {
+            let buffer = SharedBytes::map_file(Path::new(path)).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+            let runs = args.get(1).map(|r| r.parse().unwrap()).unwrap_or(5);
+            compare(&|| deserialize_module_from_bytes(&buffer), runs);
+        },
+        None => {
+            println!("Loop:");
+            compare(&|| loop_module(LOOP_ITERATIONS), 5);
+            println!();
+            println!("Calls:");
+            compare(&|| call_module(CALL_ITERATIONS), 5);
+        }
+    }
+}
+
+fn compare(load: &dyn Fn() -> CompiledModule, runs: u32)
+{
+    let mut vm = VM::new(load());
+    let decoded_count = vm.program.as_ref().expect("Module can't be pre-decoded").ops.len();
+    vm.optimize_program();
+    let optimized_count = vm.program.as_ref().unwrap().ops.len();
+    println!("{} instructions, {} after optimization", decoded_count, optimized_count);
+
+    let (classic_time, classic_memory) = measure(runs, load, false, run_classic);
+    let (decoded_time, decoded_memory) = measure(runs, load, false, |vm| { vm.run_decoded(); });
+    let (optimized_time, optimized_memory) = measure(runs, load, true, |vm| { vm.run_decoded(); });
+
+    println!("{:<12} best {:>9.3} ms", "Classic:", classic_time.as_secs_f64() * 1000.0);
+    println!("{:<12} best {:>9.3} ms, {:.2}x", "Pre-decoded:", decoded_time.as_secs_f64() * 1000.0, classic_time.as_secs_f64() / decoded_time.as_secs_f64());
+    println!("{:<12} best {:>9.3} ms, {:.2}x", "Optimized:", optimized_time.as_secs_f64() * 1000.0, classic_time.as_secs_f64() / optimized_time.as_secs_f64());
+
+    if classic_memory != decoded_memory || classic_memory != optimized_memory
+    {
+        println!("Warning: memory after execution differs between interpreters")
+    }
+
+    #[cfg(feature = "jit")]
+    {
+        let (jit_time, jit_memory) = measure(runs, load, true, run_jit);
+        println!("{:<12} best {:>9.3} ms, {:.2}x", "JIT:", jit_time.as_secs_f64() * 1000.0, classic_time.as_secs_f64() / jit_time.as_secs_f64());
+
+        if classic_memory != jit_memory
+        {
+            println!("Warning: memory after execution differs with JIT")
+        }
+    }
+}
+
+// Returns the best time of all runs and memory after the last one.
+// Free stack is cleared, optimizer may skip writes above stack pointer.
+fn measure(runs: u32, load: &dyn Fn() -> CompiledModule, optimize: bool, run: fn(&mut VM)) -> (Duration, Vec<u8>)
+{
+    let mut best = Duration::MAX;
+    let mut memory = Vec::new();
+
+    for _ in 0..runs.max(1)
+    {
+        let mut vm = VM::new(load());
+        vm.output = Box::from(sink());
+        if optimize
+        {
+            vm.optimize_program();
+        }
+
+        let start = Instant::now();
+        run(&mut vm);
+        best = best.min(start.elapsed());
+
+        memory = vm.memory.bytes.to_vec();
+        let stack_pointer = vm.memory.stack_pointer.clamp(0, Memory::HEAP_START) as usize;
+        memory[stack_pointer..Memory::HEAP_START as usize].fill(0);
+    }
+
+    (best, memory)
+}
+
+fn run_classic(vm: &mut VM)
+{
+    let functions = get_functions();
+
+    run_guarded(vm, |vm| {
+        while vm.byte_code.can_next()
+        {
+            let offset = vm.byte_code.current;
+            let byte_opcode = vm.byte_code.next();
+            run_instruction(vm, functions, byte_opcode, offset);
+        }
+    });
+}
+
+// Every function is compiled on the first call, compilation time is included
+#[cfg(feature = "jit")]
+fn run_jit(vm: &mut VM)
+{
+    vm.enable_jit(1);
+    vm.run_decoded();
+}
+
+// for (i = 0; i < iterations; i++) sum += i & 255
+pub fn loop_module(iterations: i32) -> CompiledModule
+{
+    let mut code: Vec<u8> = Vec::new();
+    let int = |code: &mut Vec<u8>, value: i32| code.extend_from_slice(&value.to_ne_bytes());
+
+    // Empty data section followed by next section opcode and mode
+    code.extend_from_slice(&[OpCode::Section as u8, 0]);
+    int(&mut code, 0);
+    code.extend_from_slice(&[0, 0]);
+
+    // rbp+0 i, rbp+4 iterations, rbp+8 sum, rbp+12 255, rbp+16 i & 255, rbp+20 condition
+    for value in [0, iterations, 0, 255, 0]
+    {
+        code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 4]);
+        int(&mut code, value);
+    }
+    code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 1, 0]);
+
+    let loop_start = code.len() as i32;
+    code.push(OpCode::Compare as u8);
+    int(&mut code, 0);
+    int(&mut code, 4);
+    code.push(4);
+    int(&mut code, 20);
+    code.push(4); // <
+
+    code.push(OpCode::JumpIfFalse as u8);
+    let end_operand = code.len();
+    int(&mut code, 0);
+    int(&mut code, 20);
+    code.push(1);
+
+    for (opcode, a, b, result) in [(OpCode::BitAnd, 0, 12, 16), (OpCode::Add, 8, 16, 8)]
+    {
+        code.push(opcode as u8);
+        int(&mut code, a);
+        int(&mut code, b);
+        int(&mut code, result);
+        code.push(4);
+    }
+
+    code.push(OpCode::Increment as u8);
+    int(&mut code, 0);
+    code.push(4);
+
+    code.push(OpCode::Jump as u8);
+    int(&mut code, loop_start);
+
+    let loop_end = code.len() as i32;
+    code[end_operand..end_operand + 4].copy_from_slice(&loop_end.to_ne_bytes());
+    code.extend_from_slice(&[OpCode::Exit as u8, OpCode::Exit as u8]);
+
+    CompiledModule {
+        table: MetaTable {
+            types: Vec::new(),
+            functions: Vec::new(),
+        },
+        managed_code: ManagedCode {
+            bytes: SharedBytes::from(code),
+        },
+        debug_info: None,
+        hash: 0
+    }
+}
+
+// for (i = 0; i < iterations; i++) sum = Mix(sum, i), where Mix(a, b) = a + (b & 255)
+pub fn call_module(iterations: i32) -> CompiledModule
+{
+    let mut code: Vec<u8> = Vec::new();
+    let int = |code: &mut Vec<u8>, value: i32| code.extend_from_slice(&value.to_ne_bytes());
+
+    code.extend_from_slice(&[OpCode::Section as u8, 0]);
+    int(&mut code, 0);
+    code.extend_from_slice(&[0, 0]);
+
+    // rbp+0 i, rbp+4 iterations, rbp+8 sum, rbp+12 condition, rbp+13 returned value while calling
+    for value in [0, iterations, 0]
+    {
+        code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 4]);
+        int(&mut code, value);
+    }
+    code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 1, 0]);
+
+    let loop_start = code.len() as i32;
+    code.push(OpCode::Compare as u8);
+    int(&mut code, 0);
+    int(&mut code, 4);
+    code.push(4);
+    int(&mut code, 12);
+    code.push(4); // <
+
+    code.push(OpCode::JumpIfFalse as u8);
+    let end_operand = code.len();
+    int(&mut code, 0);
+    int(&mut code, 12);
+    code.push(1);
+
+    // Returned value, then arguments
+    code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 4]);
+    int(&mut code, 0);
+    for variable in [8, 0]
+    {
+        code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 1]);
+        int(&mut code, variable);
+        code.push(4);
+    }
+    code.push(OpCode::Call as u8);
+    int(&mut code, 0);
+    code.push(OpCode::Deallocate_Stack as u8);
+    int(&mut code, 8);
+
+    code.extend_from_slice(&[OpCode::Mov as u8, 1]);
+    int(&mut code, 8);
+    code.push(1);
+    int(&mut code, 13);
+    code.push(4);
+    code.push(OpCode::Deallocate_Stack as u8);
+    int(&mut code, 4);
+
+    code.push(OpCode::Increment as u8);
+    int(&mut code, 0);
+    code.push(4);
+
+    code.push(OpCode::Jump as u8);
+    int(&mut code, loop_start);
+
+    let loop_end = code.len() as i32;
+    code[end_operand..end_operand + 4].copy_from_slice(&loop_end.to_ne_bytes());
+    code.push(OpCode::Exit as u8);
+
+    // Frame: rbp-20 returned value, rbp-16 a, rbp-12 b, rbp-8 return address, rbp-4 saved rbp. Locals: rbp+0 255, rbp+4 b & 255
+    let mix_start = code.len() as u32;
+    code.push(OpCode::FunctionPrologue as u8);
+    for value in [255, 0]
+    {
+        code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 4]);
+        int(&mut code, value);
+    }
+    for (opcode, a, b, result) in [(OpCode::BitAnd, -12, 0, 4), (OpCode::Add, -16, 4, -20)]
+    {
+        code.push(opcode as u8);
+        int(&mut code, a);
+        int(&mut code, b);
+        int(&mut code, result);
+        code.push(4);
+    }
+    code.extend_from_slice(&[OpCode::FunctionEpilogue as u8, OpCode::Return as u8, OpCode::Exit as u8]);
+
+    let argument = |name: &str| FieldInfo_Blit {
+        name: String::from(name),
+        type_index: 0,
+    };
+
+    CompiledModule {
+        table: MetaTable {
+            types: vec![
+                TypeInfo_Blit { name: String::from("int"), is_value_type: true, fields: Vec::new(), functions: Vec::new() },
+                TypeInfo_Blit { name: String::from("Program"), is_value_type: false, fields: Vec::new(), functions: vec![0] },
+            ],
+            functions: vec![FunctionInfo_Blit {
+                name: String::from("Mix"),
+                is_static: true,
+                is_abstract: false,
+                owner_type: 1,
+                arguments: vec![argument("a"), argument("b")],
+                returns: vec![0],
+                pointed_module: 0,
+                pointed_opcode: mix_start,
+            }],
+        },
+        managed_code: ManagedCode {
+            bytes: SharedBytes::from(code),
+        },
+        debug_info: None,
+        hash: 0
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn decoded_run_matches_classic()
+    {
+        let load = || loop_module(1000);
+        let (_, classic_memory) = measure(1, &load, false, run_classic);
+        let (_, decoded_memory) = measure(1, &load, false, |vm| { vm.run_decoded(); });
+
+        let sum: i32 = (0..1000).map(|i| i & 255).sum();
+        assert_eq!(classic_memory[8..12], sum.to_ne_bytes());
+        assert!(classic_memory == decoded_memory, "Memory differs after classic and pre-decoded runs");
+    }
+    #[test]
+    fn decoded_calls_match_classic()
+    {
+        let load = || call_module(1000);
+        let (_, classic_memory) = measure(1, &load, false, run_classic);
+        let (_, decoded_memory) = measure(1, &load, false, |vm| { vm.run_decoded(); });
+
+        let sum: i32 = (0..1000).map(|i| i & 255).sum();
+        assert_eq!(classic_memory[8..12], sum.to_ne_bytes());
+        assert!(classic_memory == decoded_memory, "Memory differs after classic and pre-decoded runs");
+    }
+    #[test]
+    fn optimized_run_matches_unoptimized()
+    {
+        for load in [|| loop_module(1000), || call_module(1000)]
+        {
+            let (_, memory) = measure(1, &load, false, |vm| { vm.run_decoded(); });
+            let (_, optimized_memory) = measure(1, &load, true, |vm| { vm.run_decoded(); });
+            assert!(memory == optimized_memory, "Memory differs after optimized run");
+        }
+    }
+}
\ No newline at end of file
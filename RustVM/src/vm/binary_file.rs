@@ -1,15 +1,24 @@
-﻿pub struct BinaryFile
+﻿use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::rc::Rc;
+use memmap2::Mmap;
+
+// Reads values one after another. Works on borrowed slice while parsing files,
+// VM reads byte code through SharedBytes.
+pub struct BinaryFile<B: Deref<Target = [u8]>>
 {
-    pub bytes: Vec<u8>,
+    pub bytes: B,
     pub current: usize,
 }
 
-impl BinaryFile
+impl<B: Deref<Target = [u8]>> BinaryFile<B>
 {
-    pub fn new(bytes: &Vec<u8>) -> Self
+    pub fn new(bytes: B) -> Self
     {
         Self {
-            bytes: bytes.clone(),
+            bytes,
             current: 0
         }
     }
@@ -47,8 +56,114 @@ impl BinaryFile
     {
         self.next() > 0
     }
+
+    // Checked reads for optional sections: None instead of panic when data is cut or malformed
+    pub fn try_next_range(&mut self, count: usize) -> Option<&[u8]>
+    {
+        let end = self.current.checked_add(count)?;
+        let slice = self.bytes.get(self.current..end)?;
+        self.current = end;
+        Some(slice)
+    }
+
+    pub fn try_next_uint(&mut self) -> Option<u32>
+    {
+        Some(u32::from_ne_bytes(self.try_next_range(4)?.try_into().unwrap()))
+    }
+
+    pub fn try_next_int(&mut self) -> Option<i32>
+    {
+        Some(i32::from_ne_bytes(self.try_next_range(4)?.try_into().unwrap()))
+    }
+
+    // Negative count is None
+    pub fn try_next_count(&mut self) -> Option<usize>
+    {
+        usize::try_from(self.try_next_int()?).ok()
+    }
+
+    pub fn try_next_string(&mut self) -> Option<String>
+    {
+        let length = self.try_next_count()?;
+        String::from_utf8(Vec::from(self.try_next_range(length)?)).ok()
+    }
     
     pub fn can_next(&self) -> bool {
         self.current < self.bytes.len() - 1
     }
-}
\ No newline at end of file
+}
+
+enum Buffer
+{
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+// Module file loaded once. Module, its byte code and VM share parts of it without copying.
+#[derive(Clone)]
+pub struct SharedBytes
+{
+    buffer: Rc<Buffer>,
+    // Mapped and owned bytes never move while buffer is alive, so the slice is resolved once
+    ptr: *const u8,
+    len: usize,
+}
+
+impl SharedBytes
+{
+    // File must not be changed by other processes while it's mapped
+    pub fn map_file(path: &Path) -> io::Result<Self>
+    {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self::from_buffer(Buffer::Mapped(map)))
+    }
+
+    fn from_buffer(buffer: Buffer) -> Self
+    {
+        let (ptr, len) = match &buffer
+        {
+            Buffer::Mapped(map) => (map.as_ptr(), map.len()),
+            Buffer::Owned(bytes) => (bytes.as_ptr(), bytes.len()),
+        };
+
+        Self {
+            buffer: Rc::new(buffer),
+            ptr,
+            len
+        }
+    }
+
+    // Part of the same buffer
+    pub fn slice(&self, start: usize, count: usize) -> Self
+    {
+        if start + count > self.len
+        {
+            panic!("Range {start}..{} is out of {} bytes", start + count, self.len)
+        }
+
+        Self {
+            buffer: self.buffer.clone(),
+            ptr: unsafe { self.ptr.add(start) },
+            len: count
+        }
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes
+{
+    fn from(bytes: Vec<u8>) -> Self
+    {
+        Self::from_buffer(Buffer::Owned(bytes))
+    }
+}
+
+impl Deref for SharedBytes
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8]
+    {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
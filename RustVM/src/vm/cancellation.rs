@@ -0,0 +1,151 @@
+﻿use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::vm::fuel::StopReason;
+use crate::vm::vm::VM;
+
+// Stops running guest from outside: by token cancelled from another thread or by wall-clock deadline.
+// They are checked every CHECK_INTERVAL instructions, compiled functions don't check them,
+// so JIT is not used while any of them is set.
+
+pub const CHECK_INTERVAL: u32 = 1024;
+
+#[derive(Clone, Default)]
+pub struct CancellationToken
+{
+    cancelled: Arc<AtomicBool>,
+}
+
+// Host API, the command line runner only uses timeout
+#[allow(dead_code)]
+impl CancellationToken
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn cancel(&self)
+    {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool
+    {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl VM
+{
+    #[allow(dead_code)]
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>)
+    {
+        self.cancellation = token;
+    }
+
+    // Deadline counts from now, None removes it
+    pub fn set_timeout(&mut self, timeout: Option<Duration>)
+    {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    pub fn can_be_cancelled(&self) -> bool
+    {
+        self.cancellation.is_some() || self.deadline.is_some()
+    }
+
+    #[cold]
+    pub fn check_cancellation(&self) -> Option<StopReason>
+    {
+        if self.cancellation.as_ref().is_some_and(|token| token.is_cancelled())
+        {
+            return Some(StopReason::Cancelled)
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Some(StopReason::TimedOut)
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::thread;
+    use super::*;
+    use crate::vm::bench::loop_module;
+    use crate::vm::clock::VirtualClock;
+    use crate::vm::fuel::RunStatus;
+    use crate::vm::functions::vm_command_functions::{run_vm_command, VMCmdArgument};
+    use crate::vm::opcodes::VMCommand_Cmd;
+
+    const DURATION: VMCmdArgument = VMCmdArgument { rbp: 0, size_in_bytes: 4, type_index: 3 };
+
+    fn sum(vm: &VM) -> i32
+    {
+        vm.memory.read_int(8)
+    }
+
+    #[test]
+    fn cancelled_run_can_be_resumed()
+    {
+        for predecode in [true, false]
+        {
+            let mut vm = VM::new(loop_module(10_000));
+            if !predecode
+            {
+                vm.program = None;
+            }
+            let token = CancellationToken::new();
+            vm.set_cancellation_token(Some(token.clone()));
+
+            let canceller = token.clone();
+            thread::spawn(move || canceller.cancel()).join().unwrap();
+            assert!(matches!(vm.run(), RunStatus::Cancelled(_)));
+            assert_ne!(sum(&vm), (0..10_000).map(|i| i & 255).sum::<i32>());
+
+            vm.set_cancellation_token(None);
+            assert!(matches!(vm.run(), RunStatus::Finished));
+            assert_eq!(sum(&vm), (0..10_000).map(|i| i & 255).sum::<i32>());
+        }
+    }
+
+    #[test]
+    fn deadline_times_out()
+    {
+        let mut vm = VM::new(loop_module(10_000));
+        vm.set_timeout(Some(Duration::ZERO));
+        match vm.run()
+        {
+            RunStatus::TimedOut(stack_trace) => assert_eq!(stack_trace.names, ["<startup>"]),
+            _ => panic!("Run hasn't timed out")
+        }
+
+        vm.set_timeout(None);
+        assert!(!vm.can_be_cancelled());
+        assert!(matches!(vm.run(), RunStatus::Finished));
+    }
+
+    #[test]
+    fn cancellation_wakes_sleep()
+    {
+        let mut vm = VM::new(loop_module(0));
+        vm.clock = Box::from(VirtualClock::new(0));
+        vm.memory.write_int(0, 10_000);
+
+        // Sleep goes by slices while it can be cancelled
+        vm.set_timeout(Some(Duration::from_secs(3600)));
+        run_vm_command(&mut vm, VMCommand_Cmd::Sleep as u8, &[DURATION]);
+        assert_eq!(vm.clock.monotonic_ns(), 10_000_000_000);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        vm.set_cancellation_token(Some(token));
+        run_vm_command(&mut vm, VMCommand_Cmd::Sleep as u8, &[DURATION]);
+        assert_eq!(vm.clock.monotonic_ns(), 10_000_000_000);
+        assert_eq!(vm.cancellation_countdown, 1);
+        assert!(matches!(vm.stop_reason(), Some(StopReason::Cancelled)));
+    }
+}
\ No newline at end of file
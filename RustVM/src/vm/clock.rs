@@ -0,0 +1,92 @@
+﻿use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Every time-related VM command goes through Clock, so the host can replace real time with VirtualClock
+pub trait Clock
+{
+    // Wall-clock time in milliseconds since unix epoch
+    fn unix_time_ms(&self) -> i64;
+
+    // Monotonic time in nanoseconds since clock creation
+    fn monotonic_ns(&self) -> u64;
+
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock
+{
+    start: Instant,
+}
+
+impl SystemClock
+{
+    pub fn new() -> Self
+    {
+        Self {
+            start: Instant::now()
+        }
+    }
+}
+
+impl Clock for SystemClock
+{
+    fn unix_time_ms(&self) -> i64
+    {
+        match SystemTime::now().duration_since(UNIX_EPOCH)
+        {
+            Ok(duration) => duration.as_millis() as i64,
+            Err(error) => -(error.duration().as_millis() as i64)
+        }
+    }
+
+    fn monotonic_ns(&self) -> u64
+    {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn sleep(&mut self, duration: Duration)
+    {
+        thread::sleep(duration);
+    }
+}
+
+// Time stands still until someone sleeps: Sleep advances both wall-clock and monotonic time instantly
+pub struct VirtualClock
+{
+    pub start_unix_time_ms: i64,
+    pub elapsed_ns: u64,
+}
+
+impl VirtualClock
+{
+    pub fn new(start_unix_time_ms: i64) -> Self
+    {
+        Self {
+            start_unix_time_ms,
+            elapsed_ns: 0,
+        }
+    }
+
+    pub fn advance(&mut self, duration: Duration)
+    {
+        self.elapsed_ns += duration.as_nanos() as u64;
+    }
+}
+
+impl Clock for VirtualClock
+{
+    fn unix_time_ms(&self) -> i64
+    {
+        self.start_unix_time_ms + (self.elapsed_ns / 1_000_000) as i64
+    }
+
+    fn monotonic_ns(&self) -> u64
+    {
+        self.elapsed_ns
+    }
+
+    fn sleep(&mut self, duration: Duration)
+    {
+        self.advance(duration);
+    }
+}
\ No newline at end of file
@@ -1,21 +1,45 @@
-﻿use crate::vm::binary_file::BinaryFile;
+﻿use std::path::Path;
+use crate::vm::binary_file::{BinaryFile, SharedBytes};
+use crate::vm::debug_info::{deserialize_debug_info, DebugInfo};
 
-pub fn deserialize_module_from_bytes(buffer: &Vec<u8>) -> CompiledModule {
+// Maps module file, byte code is read from the mapping itself
+pub fn load_module(path: &Path) -> CompiledModule
+{
+    let buffer = SharedBytes::map_file(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+    deserialize_module_from_bytes(&buffer)
+}
 
-    let mut file: BinaryFile = BinaryFile::new(buffer);
-    let module = deserialize_module(&mut file);
+pub fn deserialize_module_from_bytes(buffer: &SharedBytes) -> CompiledModule {
+
+    let mut file = BinaryFile::new(&buffer[..]);
+    let mut module = deserialize_module(&mut file, buffer);
+    module.hash = hash_bytes(buffer);
     module
 }
 
-fn deserialize_module(file: &mut BinaryFile) -> CompiledModule
+// FNV-1a, identifies module in crash dumps
+fn hash_bytes(bytes: &[u8]) -> u64
+{
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes
+    {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn deserialize_module(file: &mut BinaryFile<&[u8]>, buffer: &SharedBytes) -> CompiledModule
 {
     CompiledModule {
         table: deserialize_metatable(file),
-        managed_code: deserialize_managed_code(file)
+        managed_code: deserialize_managed_code(file, buffer),
+        debug_info: deserialize_debug_info(file),
+        hash: 0
     }
 }
 
-fn deserialize_metatable(file: &mut BinaryFile) -> MetaTable
+fn deserialize_metatable(file: &mut BinaryFile<&[u8]>) -> MetaTable
 {
     MetaTable {
         types: deserialize_types(file),
@@ -23,22 +47,25 @@ fn deserialize_metatable(file: &mut BinaryFile) -> MetaTable
     }
 }
 
-fn deserialize_managed_code(file: &mut BinaryFile) -> ManagedCode
+// Byte code isn't copied, it stays in module buffer
+fn deserialize_managed_code(file: &mut BinaryFile<&[u8]>, buffer: &SharedBytes) -> ManagedCode
 {
-    let count = file.next_int();
+    let count = file.next_int() as usize;
+    let start = file.current;
+    file.next_range(count);
 
     ManagedCode {
-        bytes: Vec::from(file.next_range(count as usize))
+        bytes: buffer.slice(start, count)
     }
 }
 
-fn deserialize_functions(file: &mut BinaryFile) -> Vec<FunctionInfo_Blit>
+fn deserialize_functions(file: &mut BinaryFile<&[u8]>) -> Vec<FunctionInfo_Blit>
 {
     let count = file.next_int();
     (0..count).map(|_| deserialize_function(file)).collect()
 }
 
-fn deserialize_function(file: &mut BinaryFile) -> FunctionInfo_Blit
+fn deserialize_function(file: &mut BinaryFile<&[u8]>) -> FunctionInfo_Blit
 {
     FunctionInfo_Blit {
         name: file.next_string(),
@@ -52,7 +79,7 @@ fn deserialize_function(file: &mut BinaryFile) -> FunctionInfo_Blit
     }
 }
 
-fn deserialize_types(file: &mut BinaryFile) -> Vec<TypeInfo_Blit>
+fn deserialize_types(file: &mut BinaryFile<&[u8]>) -> Vec<TypeInfo_Blit>
 {
     let count = file.next_int();
     let mut types = Vec::with_capacity(count as usize);
@@ -65,7 +92,7 @@ fn deserialize_types(file: &mut BinaryFile) -> Vec<TypeInfo_Blit>
     types
 }
 
-fn deserialize_type(file: &mut BinaryFile) -> TypeInfo_Blit
+fn deserialize_type(file: &mut BinaryFile<&[u8]>) -> TypeInfo_Blit
 {
     let type_info: TypeInfo_Blit = TypeInfo_Blit {
         name: file.next_string(),
@@ -77,7 +104,7 @@ fn deserialize_type(file: &mut BinaryFile) -> TypeInfo_Blit
     type_info
 }
 
-fn deserialize_fields(file: &mut BinaryFile) -> Vec<FieldInfo_Blit>
+fn deserialize_fields(file: &mut BinaryFile<&[u8]>) -> Vec<FieldInfo_Blit>
 {
     let count = file.next_int();
     let mut fields = Vec::with_capacity(count as usize);
@@ -90,7 +117,7 @@ fn deserialize_fields(file: &mut BinaryFile) -> Vec<FieldInfo_Blit>
     fields
 }
 
-fn deserialize_field(file: &mut BinaryFile) -> FieldInfo_Blit
+fn deserialize_field(file: &mut BinaryFile<&[u8]>) -> FieldInfo_Blit
 {
     FieldInfo_Blit {
         name: file.next_string(),
@@ -98,7 +125,7 @@ fn deserialize_field(file: &mut BinaryFile) -> FieldInfo_Blit
     }
 }
 
-fn deserialize_indexes(file: &mut BinaryFile) -> Vec<u32>
+fn deserialize_indexes(file: &mut BinaryFile<&[u8]>) -> Vec<u32>
 {
     let count = file.next_int();
     (0..count).map(|_| file.next_uint()).collect()
@@ -108,25 +135,99 @@ fn deserialize_indexes(file: &mut BinaryFile) -> Vec<u32>
 
 pub struct CompiledModule {
     pub table: MetaTable,
-    pub managed_code: ManagedCode
+    pub managed_code: ManagedCode,
+    pub debug_info: Option<DebugInfo>,
+    pub hash: u64
+}
+
+impl CompiledModule
+{
+    // Finds function by "Type.Function" or just "Function" name
+    pub fn find_function(&self, name: &str) -> Option<usize>
+    {
+        let (type_name, function_name) = match name.rsplit_once('.')
+        {
+            Some((type_name, function_name)) => (Some(type_name), function_name),
+            None => (None, name)
+        };
+
+        self.table.functions.iter().position(|f| {
+            f.name == function_name && type_name.is_none_or(|type_name| {
+                self.table.types.get(f.owner_type as usize).is_some_and(|t| t.name == type_name)
+            })
+        })
+    }
+
+    // Managed function which body contains given byte code offset
+    pub fn function_at(&self, offset: usize) -> Option<usize>
+    {
+        self.table.functions.iter().enumerate()
+            .filter(|(_, f)| f.pointed_module == 0 && f.pointed_opcode as usize <= offset)
+            .max_by_key(|(_, f)| f.pointed_opcode)
+            .map(|(i, _)| i)
+    }
+
+    pub fn function_full_name(&self, function_index: usize) -> String
+    {
+        let function_info = &self.table.functions[function_index];
+
+        match self.table.types.get(function_info.owner_type as usize)
+        {
+            Some(type_info) => format!("{}.{}", type_info.name, function_info.name),
+            None => function_info.name.clone()
+        }
+    }
+
+    // "file:line:column" of instruction, if module has debug info
+    pub fn source_location(&self, offset: usize) -> Option<String>
+    {
+        self.debug_info.as_ref()?.location_at(offset)
+    }
+
+    pub fn type_name(&self, type_index: u32) -> &str
+    {
+        &self.table.types[type_index as usize].name
+    }
+
+    // Size of variable of given type. Reference types are stored as pointers.
+    pub fn type_size(&self, type_index: u32) -> i32
+    {
+        let type_info = &self.table.types[type_index as usize];
+
+        match type_info.name.as_str()
+        {
+            "bool" | "byte" => 1,
+            "short" => 2,
+            "int" => 4,
+            "long" => 8,
+            _ if type_info.is_value_type && !type_info.fields.is_empty() => {
+                type_info.fields.iter().map(|f| self.type_size(f.type_index)).sum()
+            },
+            _ => 4
+        }
+    }
 }
 pub struct MetaTable {
     pub types: Vec<TypeInfo_Blit>,
     pub functions: Vec<FunctionInfo_Blit>
 }
 pub struct ManagedCode {
-    pub bytes: Vec<u8>
+    pub bytes: SharedBytes
 }
+// Read as the compiler writes them, not every field is used
+#[allow(non_camel_case_types, dead_code)]
 pub struct TypeInfo_Blit {
     pub name: String,
     pub is_value_type: bool,
     pub fields: Vec<FieldInfo_Blit>,
     pub functions: Vec<u32>,
 }
+#[allow(non_camel_case_types)]
 pub struct FieldInfo_Blit {
     pub name: String,
     pub type_index: u32,
 }
+#[allow(non_camel_case_types, dead_code)]
 pub struct FunctionInfo_Blit {
     pub name: String,
     pub is_static: bool,
@@ -136,4 +237,98 @@ pub struct FunctionInfo_Blit {
     pub returns: Vec<u32>,
     pub pointed_module: u8,
     pub pointed_opcode: u32
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::env::temp_dir;
+    use std::fs;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::fuel::RunStatus;
+    use crate::vm::vm::VM;
+
+    // Module file as compiler writes it
+    fn serialize(module: &CompiledModule) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+        let int = |bytes: &mut Vec<u8>, value: u32| bytes.extend_from_slice(&value.to_ne_bytes());
+        let string = |bytes: &mut Vec<u8>, value: &str| {
+            int(bytes, value.len() as u32);
+            bytes.extend_from_slice(value.as_bytes());
+        };
+        let fields = |bytes: &mut Vec<u8>, fields: &[FieldInfo_Blit]| {
+            int(bytes, fields.len() as u32);
+            for field in fields
+            {
+                string(bytes, &field.name);
+                int(bytes, field.type_index);
+            }
+        };
+        let indexes = |bytes: &mut Vec<u8>, indexes: &[u32]| {
+            int(bytes, indexes.len() as u32);
+            indexes.iter().for_each(|index| int(bytes, *index));
+        };
+
+        int(&mut bytes, module.table.types.len() as u32);
+        for type_info in &module.table.types
+        {
+            string(&mut bytes, &type_info.name);
+            bytes.push(type_info.is_value_type as u8);
+            fields(&mut bytes, &type_info.fields);
+            indexes(&mut bytes, &type_info.functions);
+        }
+
+        int(&mut bytes, module.table.functions.len() as u32);
+        for function_info in &module.table.functions
+        {
+            string(&mut bytes, &function_info.name);
+            bytes.extend_from_slice(&[function_info.is_static as u8, function_info.is_abstract as u8]);
+            int(&mut bytes, function_info.owner_type);
+            fields(&mut bytes, &function_info.arguments);
+            indexes(&mut bytes, &function_info.returns);
+            bytes.push(function_info.pointed_module);
+            int(&mut bytes, function_info.pointed_opcode);
+        }
+
+        int(&mut bytes, module.managed_code.bytes.len() as u32);
+        bytes.extend_from_slice(&module.managed_code.bytes);
+        bytes
+    }
+
+    #[test]
+    fn byte_code_stays_in_module_buffer()
+    {
+        let file = serialize(&call_module(10));
+        let code_start = file.len() - call_module(10).managed_code.bytes.len();
+        let buffer = SharedBytes::from(file);
+        let module = deserialize_module_from_bytes(&buffer);
+
+        assert_eq!(module.managed_code.bytes.as_ptr(), buffer[code_start..].as_ptr());
+        assert_eq!(module.find_function("Program.Mix"), Some(0));
+        assert!(module.debug_info.is_none());
+
+        let vm = VM::new(module);
+        assert_eq!(vm.byte_code.bytes.as_ptr(), buffer[code_start..].as_ptr());
+    }
+
+    #[test]
+    fn mapped_module_runs()
+    {
+        let path = temp_dir().join(format!("rustvm-module-{}.asc", std::process::id()));
+        fs::write(&path, serialize(&call_module(10))).unwrap();
+
+        let module = load_module(&path);
+        assert_eq!(module.hash, hash_bytes(&fs::read(&path).unwrap()));
+        let mut vm = VM::new(module);
+        assert!(matches!(vm.run(), RunStatus::Finished));
+
+        let sum: i32 = (0..10).map(|i| i & 255).sum();
+        assert_eq!(vm.memory.read_int(8), sum);
+
+        // Mapped file can't be removed on Windows
+        drop(vm);
+        fs::remove_file(&path).unwrap();
+    }
 }
\ No newline at end of file
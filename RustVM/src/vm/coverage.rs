@@ -0,0 +1,237 @@
+﻿use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use crate::vm::disassembler::{decode_all, Instruction};
+use crate::vm::opcodes::OpCode;
+use crate::vm::vm::VM;
+
+// Records executed instructions and JumpIfFalse outcomes
+
+pub struct Coverage
+{
+    // Execution count by instruction offset
+    counts: Vec<u64>,
+    // JumpIfFalse offset => [times jumped, times fell through]
+    branches: HashMap<usize, [u64; 2]>,
+}
+
+// JumpIfFalse [target: int] [condition: int] [size: byte]
+const CONDITION_OPERAND: usize = 5;
+
+// Hits of line and branches on it
+type LineHits = (u64, Vec<[u64; 2]>);
+
+impl Coverage
+{
+    pub fn new(vm: &VM) -> Self
+    {
+        Self {
+            counts: vec![0; vm.byte_code.bytes.len()],
+            branches: HashMap::new(),
+        }
+    }
+
+    // Called after instruction at given offset is executed
+    pub fn record(&mut self, vm: &VM, offset: usize)
+    {
+        self.counts[offset] += 1;
+
+        // Jump target may be the next instruction, so the decision is taken from condition (it's only read by the jump)
+        if vm.byte_code.bytes[offset] == OpCode::JumpIfFalse as u8
+        {
+            let operands = &vm.byte_code.bytes[offset + CONDITION_OPERAND..];
+            let condition_address = vm.memory.to_abs(i32::from_ne_bytes(operands[..4].try_into().unwrap()));
+            let fell_through = vm.memory.read(condition_address, operands[4] as i32).iter().any(|b| *b > 0);
+            self.branches.entry(offset).or_default()[fell_through as usize] += 1;
+        }
+    }
+
+    fn is_branch(instruction: &Instruction) -> bool
+    {
+        matches!(instruction.opcode, OpCode::JumpIfFalse)
+    }
+
+    // "Type.Function: 10/12 instructions (83.3%), 3/4 branches" for each function
+    pub fn report(&self, vm: &VM) -> String
+    {
+        let mut functions: BTreeMap<String, [usize; 4]> = BTreeMap::new();
+
+        for instruction in decode_all(&vm.byte_code.bytes)
+        {
+            let name = match vm.module.function_at(instruction.offset)
+            {
+                Some(function_index) => vm.module.function_full_name(function_index),
+                None => String::from("<startup>")
+            };
+            let stats = functions.entry(name).or_default();
+
+            stats[0] += 1;
+            if self.counts[instruction.offset] > 0
+            {
+                stats[1] += 1;
+            }
+            if Self::is_branch(&instruction)
+            {
+                let outcomes = self.branches.get(&instruction.offset).copied().unwrap_or_default();
+                stats[2] += 2;
+                stats[3] += outcomes.iter().filter(|count| **count > 0).count();
+            }
+        }
+
+        let mut text = String::new();
+        let (mut total, mut covered) = (0, 0);
+
+        for (name, [instructions, executed, branches, taken]) in &functions
+        {
+            total += instructions;
+            covered += executed;
+            writeln!(text, "{}: {}/{} instructions ({:.1}%), {}/{} branches", name, executed, instructions, percent(*executed, *instructions), taken, branches).unwrap();
+        }
+        writeln!(text, "Total: {}/{} instructions ({:.1}%)", covered, total, percent(covered, total)).unwrap();
+
+        text
+    }
+
+    // Line coverage in lcov tracefile format. Returns None if module has no line table.
+    pub fn lcov(&self, vm: &VM) -> Option<String>
+    {
+        let debug_info = vm.module.debug_info.as_ref()?;
+
+        // file => line => (hits, branches)
+        let mut files: BTreeMap<u32, BTreeMap<u32, LineHits>> = BTreeMap::new();
+        // file => function => (first line, hits)
+        let mut functions: BTreeMap<u32, BTreeMap<String, (u32, u64)>> = BTreeMap::new();
+
+        for instruction in decode_all(&vm.byte_code.bytes)
+        {
+            let entry = match debug_info.line_at(instruction.offset)
+            {
+                Some(entry) => entry,
+                None => continue
+            };
+
+            let line = files.entry(entry.file).or_default().entry(entry.line).or_default();
+            line.0 = line.0.max(self.counts[instruction.offset]);
+            if Self::is_branch(&instruction)
+            {
+                line.1.push(self.branches.get(&instruction.offset).copied().unwrap_or_default());
+            }
+
+            if let Some(function_index) = vm.module.function_at(instruction.offset)
+                && vm.module.table.functions[function_index].pointed_opcode as usize == instruction.offset
+            {
+                functions.entry(entry.file).or_default()
+                    .insert(vm.module.function_full_name(function_index), (entry.line, self.counts[instruction.offset]));
+            }
+        }
+
+        let mut text = String::new();
+
+        for (file, lines) in &files
+        {
+            writeln!(text, "TN:").unwrap();
+            writeln!(text, "SF:{}", debug_info.file_name(*file)).unwrap();
+
+            let file_functions = functions.get(file).cloned().unwrap_or_default();
+            for (name, (line, _)) in &file_functions
+            {
+                writeln!(text, "FN:{},{}", line, name).unwrap();
+            }
+            for (name, (_, hits)) in &file_functions
+            {
+                writeln!(text, "FNDA:{},{}", hits, name).unwrap();
+            }
+            writeln!(text, "FNF:{}", file_functions.len()).unwrap();
+            writeln!(text, "FNH:{}", file_functions.values().filter(|(_, hits)| *hits > 0).count()).unwrap();
+
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (line, (hits, branches)) in lines
+            {
+                for (block, outcomes) in branches.iter().enumerate()
+                {
+                    for (branch, count) in outcomes.iter().enumerate()
+                    {
+                        // Never executed branch is "-", executed but not taken is 0
+                        let taken = if *hits == 0 { String::from("-") } else { count.to_string() };
+                        writeln!(text, "BRDA:{},{},{},{}", line, block, branch, taken).unwrap();
+
+                        branches_found += 1;
+                        if *count > 0
+                        {
+                            branches_hit += 1;
+                        }
+                    }
+                }
+            }
+            writeln!(text, "BRF:{}", branches_found).unwrap();
+            writeln!(text, "BRH:{}", branches_hit).unwrap();
+
+            for (line, (hits, _)) in lines
+            {
+                writeln!(text, "DA:{},{}", line, hits).unwrap();
+            }
+            writeln!(text, "LF:{}", lines.len()).unwrap();
+            writeln!(text, "LH:{}", lines.values().filter(|(hits, _)| *hits > 0).count()).unwrap();
+            writeln!(text, "end_of_record").unwrap();
+        }
+
+        Some(text)
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64
+{
+    match total
+    {
+        0 => 100.0,
+        _ => part as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::debug_info::{DebugInfo, LineEntry};
+    use crate::vm::run_instrumented;
+
+    fn cover(iterations: i32) -> (VM, Coverage)
+    {
+        let mut vm = VM::new(call_module(iterations));
+        let mut coverage = Some(Coverage::new(&vm));
+        run_instrumented(&mut vm, &mut None, &mut None, &mut coverage);
+        (vm, coverage.unwrap())
+    }
+
+    #[test]
+    fn report_counts_instructions_and_branches()
+    {
+        // Loop body and Mix are never reached, condition is false once
+        let (vm, coverage) = cover(0);
+        assert_eq!(coverage.report(&vm), "<startup>: 8/17 instructions (47.1%), 1/2 branches\n\
+            Program.Mix: 0/8 instructions (0.0%), 0/0 branches\n\
+            Total: 8/25 instructions (32.0%)\n");
+
+        let (vm, coverage) = cover(2);
+        assert!(coverage.report(&vm).starts_with("<startup>: 17/17 instructions (100.0%), 2/2 branches\n"));
+        assert_eq!(coverage.branches[&0x30], [1, 2]);
+    }
+
+    #[test]
+    fn lcov_needs_line_table()
+    {
+        let (mut vm, coverage) = cover(0);
+        assert!(coverage.lcov(&vm).is_none());
+
+        let line = |offset, line| LineEntry { offset, file: 0, line, column: 1 };
+        vm.module.debug_info = Some(DebugInfo {
+            files: vec![String::from("main.ab")],
+            lines: vec![line(0, 1), line(0x3A, 2), line(0x75, 3), line(0x76, 10)],
+            locals: Vec::new(),
+        });
+        let lcov = coverage.lcov(&vm).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:main.ab\nFN:10,Program.Mix\nFNDA:0,Program.Mix\n"));
+        assert!(lcov.contains("BRDA:1,0,0,1\nBRDA:1,0,1,0\n"));
+        assert!(lcov.contains("DA:1,1\nDA:2,0\nDA:3,1\nDA:10,0\nLF:4\nLH:2\nend_of_record"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,288 @@
+﻿use std::fs;
+use std::path::Path;
+use crate::vm::binary_file::BinaryFile;
+use crate::vm::compiled_module::load_module;
+use crate::vm::disassembler::decode;
+use crate::vm::memory::Memory;
+use crate::vm::stack_trace::capture_stack_trace;
+use crate::vm::vm::VM;
+
+// Dump layout (little-endian): "ADMP" [version: int] [module hash: u64] [pc: uint] [rsp: int] [rbp: int] [heap: int] [data section size: int]
+//              [error message: string] [history count: int] [offset: uint]... (oldest first) [memory size: int] [memory bytes]
+
+const MAGIC: &[u8; 4] = b"ADMP";
+const VERSION: i32 = 1;
+
+// How many last executed instructions are kept for crash dumps
+pub const HISTORY_SIZE: usize = 64;
+
+// Ring buffer of offsets of last executed instructions
+pub struct InstructionHistory
+{
+    offsets: [u32; HISTORY_SIZE],
+    next: usize,
+    count: usize,
+}
+
+impl InstructionHistory
+{
+    pub fn new() -> Self
+    {
+        Self {
+            offsets: [0; HISTORY_SIZE],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, offset: usize)
+    {
+        self.offsets[self.next] = offset as u32;
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        self.count = (self.count + 1).min(HISTORY_SIZE);
+    }
+
+    // Oldest first
+    pub fn to_vec(&self) -> Vec<u32>
+    {
+        let start = (self.next + HISTORY_SIZE - self.count) % HISTORY_SIZE;
+        (0..self.count).map(|i| self.offsets[(start + i) % HISTORY_SIZE]).collect()
+    }
+}
+
+pub struct CrashDump
+{
+    pub module_hash: u64,
+    pub pc: u32,
+    pub stack_pointer: i32,
+    pub base_pointer: i32,
+    pub heap_pointer: i32,
+    pub data_section_size: i32,
+    pub message: String,
+    pub history: Vec<u32>,
+    pub memory: Vec<u8>,
+}
+
+impl CrashDump
+{
+    pub fn capture(vm: &VM, offset: usize, message: &str) -> Self
+    {
+        Self {
+            module_hash: vm.module.hash,
+            pc: offset as u32,
+            stack_pointer: vm.memory.stack_pointer,
+            base_pointer: vm.memory.base_pointer,
+            heap_pointer: vm.memory.heap_pointer,
+            data_section_size: vm.memory.data_section_size,
+            message: String::from(message),
+            history: vm.history.to_vec(),
+            memory: vm.memory.bytes.to_vec(),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.module_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend_from_slice(&self.stack_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.base_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.heap_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.data_section_size.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.message.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(self.message.as_bytes());
+
+        bytes.extend_from_slice(&(self.history.len() as i32).to_le_bytes());
+        for offset in &self.history
+        {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.memory.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+
+        bytes
+    }
+
+    pub fn deserialize(buffer: &[u8]) -> Self
+    {
+        let mut file = BinaryFile::new(buffer);
+
+        if file.bytes.get(0..4) != Some(MAGIC.as_slice())
+        {
+            panic!("File is not a crash dump")
+        }
+        file.next_range(4);
+
+        let version = next_int(&mut file);
+        if version != VERSION
+        {
+            panic!("Unsupported crash dump version {version}")
+        }
+
+        Self {
+            module_hash: u64::from_le_bytes(file.next_range(8).try_into().unwrap()),
+            pc: next_int(&mut file) as u32,
+            stack_pointer: next_int(&mut file),
+            base_pointer: next_int(&mut file),
+            heap_pointer: next_int(&mut file),
+            data_section_size: next_int(&mut file),
+            message: {
+                let length = next_int(&mut file);
+                String::from_utf8_lossy(file.next_range(length as usize)).into_owned()
+            },
+            history: {
+                let count = next_int(&mut file);
+                (0..count).map(|_| next_int(&mut file) as u32).collect()
+            },
+            memory: {
+                let count = next_int(&mut file);
+                file.next_range(count as usize).to_vec()
+            },
+        }
+    }
+}
+
+// Unlike byte code, dump is read the same way on every machine
+fn next_int(file: &mut BinaryFile<&[u8]>) -> i32
+{
+    i32::from_le_bytes(file.next_range(4).try_into().unwrap())
+}
+
+// Prints guest stack trace of failed instruction and writes crash dump (if VM has dump path)
+pub fn report_fatal_error(vm: &VM, offset: usize, message: &str)
+{
+    match vm.module.source_location(offset)
+    {
+        Some(location) => eprintln!("Runtime error at 0x{:04X} ({}): {}", offset, location, message),
+        None => eprintln!("Runtime error at 0x{:04X}: {}", offset, message)
+    }
+    eprint!("Guest stack trace:\n{}", capture_stack_trace(vm, offset));
+
+    if let Some(path) = &vm.dump_path
+    {
+        match fs::write(path, CrashDump::capture(vm, offset, message).serialize())
+        {
+            Ok(_) => eprintln!("Crash dump is written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write crash dump to {}: {}", path.display(), e)
+        }
+    }
+}
+
+// Usage: RustVM inspect-dump <dump> <module.asc>
+pub fn inspect_dump(dump_path: &Path, module_path: &Path)
+{
+    let dump = CrashDump::deserialize(&fs::read(dump_path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", dump_path.display())));
+    let module = load_module(module_path);
+
+    if module.hash != dump.module_hash
+    {
+        println!("Warning: dump was made for module {:016x}, but given module is {:016x}", dump.module_hash, module.hash);
+    }
+
+    // Restore VM state as it was at the moment of crash, so the same inspection tools can be used
+    let mut vm = VM::new(module);
+    let memory_size = dump.memory.len().min(vm.memory.bytes.len());
+    vm.memory.bytes[..memory_size].copy_from_slice(&dump.memory[..memory_size]);
+    vm.memory.stack_pointer = dump.stack_pointer;
+    vm.memory.base_pointer = dump.base_pointer;
+    vm.memory.heap_pointer = dump.heap_pointer;
+    vm.memory.data_section_size = dump.data_section_size;
+    vm.byte_code.current = dump.pc as usize;
+
+    println!("Error: {}", dump.message);
+    println!("Module: {:016x}", dump.module_hash);
+    println!("pc = 0x{:04X}{}, rsp = {}, rbp = {}, heap = {}, data section = {} bytes", dump.pc,
+        vm.module.source_location(dump.pc as usize).map(|l| format!(" ({l})")).unwrap_or_default(),
+        dump.stack_pointer, dump.base_pointer, dump.heap_pointer, dump.data_section_size);
+
+    println!();
+    println!("Last {} instructions:", dump.history.len());
+    for offset in &dump.history
+    {
+        let offset = *offset as usize;
+        let instruction = match decode(&vm.byte_code.bytes, offset)
+        {
+            Some(instruction) => instruction.describe(&vm.module),
+            None => String::from("<invalid>")
+        };
+        let function_name = match vm.module.function_at(offset)
+        {
+            Some(function_index) => vm.module.function_full_name(function_index),
+            None => String::from("<startup>")
+        };
+
+        println!("  0x{:04X} {:<40} in {}", offset, instruction, function_name);
+    }
+
+    println!();
+    print!("Guest stack trace:\n{}", capture_stack_trace(&vm, dump.pc as usize));
+
+    println!();
+    println!("Stack:");
+    print_bytes(&vm.memory, 0, vm.memory.stack_pointer.clamp(0, Memory::STACK_SIZE));
+    println!("Heap:");
+    print_bytes(&vm.memory, Memory::HEAP_START, vm.memory.heap_pointer.clamp(Memory::HEAP_START, Memory::STACK_SIZE));
+}
+
+fn print_bytes(memory: &Memory, from: i32, to: i32)
+{
+    let mut address = from;
+    while address < to
+    {
+        let count = (to - address).min(16);
+        let hex: Vec<String> = memory.read(address, count).iter().map(|b| format!("{:02x}", b)).collect();
+
+        println!("  {:5}: {}", address, hex.join(" "));
+        address += count;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    #[test]
+    fn history_keeps_last_offsets()
+    {
+        let mut history = InstructionHistory::new();
+        for offset in 0..HISTORY_SIZE + 3
+        {
+            history.push(offset);
+        }
+        let offsets = history.to_vec();
+        assert_eq!(offsets.len(), HISTORY_SIZE);
+        assert_eq!((offsets[0], offsets[HISTORY_SIZE - 1]), (3, HISTORY_SIZE as u32 + 2));
+    }
+
+    #[test]
+    fn dump_is_read_back()
+    {
+        let mut vm = VM::new(call_module(1));
+        vm.set_fuel(Some(14));
+        run_instrumented(&mut vm, &mut None, &mut None, &mut None);
+
+        let dump = CrashDump::capture(&vm, vm.byte_code.current, "Test failure");
+        let bytes = dump.serialize();
+        let read = CrashDump::deserialize(&bytes);
+
+        assert_eq!(read.serialize(), bytes);
+        assert_eq!((read.pc, read.base_pointer, read.message.as_str()), (0x85, 33, "Test failure"));
+        assert_eq!(read.history.len(), 14);
+        assert_eq!(read.memory, vm.memory.bytes.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "File is not a crash dump")]
+    fn other_files_are_rejected()
+    {
+        CrashDump::deserialize(b"ASNP....");
+    }
+}
\ No newline at end of file
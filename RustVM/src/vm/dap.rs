@@ -0,0 +1,761 @@
+﻿use std::collections::HashMap;
+use std::io::{stdin, stdout, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde_json::{json, Value as JsonValue};
+use crate::vm::debugger::{DebugFrontend, Debugger, PauseReason, StepMode};
+use crate::vm::memory::Memory;
+use crate::vm::stack_trace::{capture_stack_trace, StackTrace};
+use crate::vm::vm::VM;
+use crate::vm::watchpoints::{WatchAction, Watchpoints};
+
+// Debug Adapter Protocol server, so editors (like VS Code) can debug Astra programs.
+// Guest has a single thread, frames are reconstructed by walking saved rbp chain.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DapTransport
+{
+    Stdio,
+    Tcp(u16),
+}
+
+const THREAD_ID: i64 = 1;
+const HEAP_REFERENCE: i64 = 1;
+const LOCALS_REFERENCE_BASE: i64 = 1000;
+const REGISTERS_REFERENCE_BASE: i64 = 2000;
+
+struct DapWriter
+{
+    output: Box<dyn Write + Send>,
+    seq: i64,
+}
+
+impl DapWriter
+{
+    fn send(&mut self, mut message: JsonValue)
+    {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let body = message.to_string();
+        // Client may be gone already, there is nobody to report it to
+        let _ = write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.output.flush();
+    }
+}
+
+// Guest output forwarded to debug console as DAP output events
+pub struct DapOutput
+{
+    writer: Arc<Mutex<DapWriter>>,
+}
+
+impl Write for DapOutput
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        self.writer.lock().unwrap().send(json!({
+            "type": "event",
+            "event": "output",
+            "body": { "category": "stdout", "output": String::from_utf8_lossy(buf) }
+        }));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+enum Flow
+{
+    Wait,
+    Resume,
+    Configured,
+}
+
+pub struct DapFrontend
+{
+    writer: Arc<Mutex<DapWriter>>,
+    requests: Receiver<JsonValue>,
+
+    // DAP replaces breakpoints per kind, debugger keeps all of them in one set
+    function_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    // Source breakpoints by source path
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    // Ids of watchpoints set as data breakpoints
+    data_breakpoints: Vec<u32>,
+}
+
+impl DapFrontend
+{
+    // Waits for the client to connect
+    pub fn connect(transport: DapTransport) -> Self
+    {
+        let (input, output): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match transport
+        {
+            DapTransport::Stdio => (Box::from(stdin()), Box::from(stdout())),
+            DapTransport::Tcp(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| panic!("Failed to listen DAP port {port}: {e}"));
+                eprintln!("Waiting for DAP client on 127.0.0.1:{port}");
+
+                let (stream, _) = listener.accept().unwrap();
+                (Box::from(stream.try_clone().unwrap()), Box::from(stream))
+            }
+        };
+
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(input);
+            while let Some(message) = read_message(&mut reader)
+            {
+                if sender.send(message).is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            writer: Arc::new(Mutex::new(DapWriter { output, seq: 0 })),
+            requests,
+            function_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            source_breakpoints: HashMap::new(),
+            data_breakpoints: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> DapOutput
+    {
+        DapOutput {
+            writer: self.writer.clone()
+        }
+    }
+
+    fn send(&self, message: JsonValue)
+    {
+        self.writer.lock().unwrap().send(message);
+    }
+
+    fn send_event(&self, event: &str, body: JsonValue)
+    {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&self, request: &JsonValue, success: bool, body: JsonValue)
+    {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+            "body": body
+        });
+        if !success
+        {
+            response["message"] = body["error"]["format"].clone();
+        }
+        self.send(response);
+    }
+
+    fn respond_error(&self, request: &JsonValue, message: &str)
+    {
+        self.respond(request, false, json!({ "error": { "id": 1, "format": message } }));
+    }
+
+    // Client has gone: let the guest run to the end
+    fn detach(&mut self, debugger: &mut Debugger, vm: &mut VM)
+    {
+        debugger.breakpoints.clear();
+        debugger.mode = StepMode::Continue;
+        self.function_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        self.source_breakpoints.clear();
+        // Removing the last watchpoint turns write recording off again
+        for id in self.data_breakpoints.drain(..)
+        {
+            vm.remove_watchpoint(id);
+        }
+    }
+
+    fn update_breakpoints(&self, debugger: &mut Debugger)
+    {
+        debugger.breakpoints = self.function_breakpoints.iter()
+            .chain(&self.instruction_breakpoints)
+            .chain(self.source_breakpoints.values().flatten())
+            .copied().collect();
+    }
+
+    fn handle(&mut self, request: &JsonValue, debugger: &mut Debugger, vm: &mut VM) -> Flow
+    {
+        let arguments = &request["arguments"];
+
+        match request["command"].as_str().unwrap_or("")
+        {
+            "initialize" => {
+                self.respond(request, true, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsDataBreakpoints": true,
+                    "supportsSteppingGranularity": true,
+                    "supportsTerminateRequest": true,
+                    "supportsStepBack": vm.time_travel.is_some(),
+                }));
+                self.send_event("initialized", json!({}));
+            },
+            "launch" | "attach" => {
+                debugger.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                self.respond(request, true, json!({}));
+            },
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().or(arguments["source"]["name"].as_str()).unwrap_or("");
+                let mut offsets = Vec::new();
+                let mut breakpoints = Vec::new();
+
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten()
+                {
+                    let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+
+                    // Breakpoint is moved to the nearest line with code
+                    match vm.module.debug_info.as_ref().and_then(|debug_info| debug_info.offset_for_line(path, line).map(|offset| (offset, debug_info)))
+                    {
+                        Some((offset, debug_info)) => {
+                            offsets.push(offset);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "line": debug_info.line_at(offset).map(|entry| entry.line).unwrap_or(line),
+                                "instructionReference": format_offset(offset)
+                            }));
+                        },
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "message": match vm.module.debug_info
+                            {
+                                Some(_) => "No code at this line",
+                                None => "Module has no line information"
+                            }
+                        }))
+                    }
+                }
+
+                self.source_breakpoints.insert(String::from(path), offsets);
+                self.update_breakpoints(debugger);
+                self.respond(request, true, json!({ "breakpoints": breakpoints }));
+            },
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints.clear();
+                let mut breakpoints = Vec::new();
+
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten()
+                {
+                    let name = breakpoint["name"].as_str().unwrap_or("");
+                    let offset = vm.module.find_function(name).map(|i| vm.module.table.functions[i].pointed_opcode as usize);
+
+                    if let Some(offset) = offset
+                    {
+                        self.function_breakpoints.push(offset);
+                    }
+                    breakpoints.push(json!({
+                        "verified": offset.is_some(),
+                        "instructionReference": offset.map(format_offset)
+                    }));
+                }
+
+                self.update_breakpoints(debugger);
+                self.respond(request, true, json!({ "breakpoints": breakpoints }));
+            },
+            "setInstructionBreakpoints" => {
+                self.instruction_breakpoints.clear();
+                let mut breakpoints = Vec::new();
+
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten()
+                {
+                    let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+                    let offset = Debugger::resolve_location(vm, reference)
+                        .map(|offset| offset as i64 + breakpoint["offset"].as_i64().unwrap_or(0))
+                        .filter(|offset| *offset >= 0 && (*offset as usize) < vm.byte_code.bytes.len())
+                        .map(|offset| offset as usize);
+
+                    if let Some(offset) = offset
+                    {
+                        self.instruction_breakpoints.push(offset);
+                    }
+                    breakpoints.push(json!({
+                        "verified": offset.is_some(),
+                        "instructionReference": offset.map(format_offset)
+                    }));
+                }
+
+                self.update_breakpoints(debugger);
+                self.respond(request, true, json!({ "breakpoints": breakpoints }));
+            },
+            "dataBreakpointInfo" => {
+                // Data id is "address:size" of the variable, only writes can be watched
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                let name = arguments["name"].as_str().unwrap_or("");
+
+                let slots = match reference
+                {
+                    HEAP_REFERENCE => heap_slots(vm),
+                    r if r >= REGISTERS_REFERENCE_BASE => Vec::new(),
+                    r if r >= LOCALS_REFERENCE_BASE => local_slots(vm, &capture_stack_trace(vm, vm.byte_code.current), (r - LOCALS_REFERENCE_BASE) as usize),
+                    _ => Vec::new()
+                };
+
+                match slots.iter().find(|slot| slot.name == name)
+                {
+                    Some(slot) => self.respond(request, true, json!({
+                        "dataId": format!("{}:{}", slot.address, slot.size),
+                        "description": format!("{} ({}..{})", name, slot.address, slot.address + slot.size),
+                        "accessTypes": ["write"]
+                    })),
+                    None => self.respond(request, true, json!({ "dataId": null, "description": format!("'{name}' can't be watched") }))
+                }
+            },
+            "setDataBreakpoints" => {
+                for id in self.data_breakpoints.drain(..)
+                {
+                    vm.remove_watchpoint(id);
+                }
+                let mut breakpoints = Vec::new();
+
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten()
+                {
+                    let range = breakpoint["dataId"].as_str().and_then(|id| Watchpoints::parse_range(&vm.memory, id));
+                    if let Some((address, size)) = range
+                    {
+                        self.data_breakpoints.push(vm.add_watchpoint(address, size, WatchAction::Pause));
+                    }
+                    breakpoints.push(json!({ "verified": range.is_some() }));
+                }
+
+                self.respond(request, true, json!({ "breakpoints": breakpoints }));
+            },
+            "setExceptionBreakpoints" => {
+                self.respond(request, true, json!({ "breakpoints": [] }));
+            },
+            "configurationDone" => {
+                self.respond(request, true, json!({}));
+                return Flow::Configured;
+            },
+            "threads" => {
+                self.respond(request, true, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }));
+            },
+            "stackTrace" => {
+                let trace = capture_stack_trace(vm, vm.byte_code.current);
+                let frames: Vec<JsonValue> = trace.frames.iter().zip(&trace.names).enumerate().map(|(i, (frame, name))| {
+                    let mut stack_frame = json!({
+                        "id": i,
+                        "name": name,
+                        "line": 0,
+                        "column": 0,
+                        "instructionPointerReference": format_offset(frame.offset)
+                    });
+
+                    let debug_info = vm.module.debug_info.as_ref();
+                    if let Some((entry, debug_info)) = debug_info.and_then(|d| d.line_at(frame.offset).map(|entry| (entry, d)))
+                    {
+                        let path = debug_info.file_name(entry.file);
+                        stack_frame["source"] = json!({ "name": path.rsplit(['/', '\\']).next(), "path": path });
+                        stack_frame["line"] = json!(entry.line);
+                        stack_frame["column"] = json!(entry.column);
+                    }
+                    stack_frame
+                }).collect();
+
+                self.respond(request, true, json!({ "stackFrames": frames, "totalFrames": frames.len() }));
+            },
+            "scopes" => {
+                let frame_id = arguments["frameId"].as_i64().unwrap_or(0);
+                self.respond(request, true, json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": LOCALS_REFERENCE_BASE + frame_id, "expensive": false },
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE_BASE + frame_id, "expensive": false },
+                    { "name": "Heap", "variablesReference": HEAP_REFERENCE, "expensive": true },
+                ]}));
+            },
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                let trace = capture_stack_trace(vm, vm.byte_code.current);
+
+                let variables = match reference
+                {
+                    HEAP_REFERENCE => heap_variables(vm),
+                    r if r >= REGISTERS_REFERENCE_BASE => register_variables(vm, &trace, (r - REGISTERS_REFERENCE_BASE) as usize),
+                    r if r >= LOCALS_REFERENCE_BASE => local_variables(vm, &trace, (r - LOCALS_REFERENCE_BASE) as usize),
+                    _ => Vec::new()
+                };
+
+                self.respond(request, true, json!({ "variables": variables }));
+            },
+            "continue" => {
+                debugger.mode = StepMode::Continue;
+                self.respond(request, true, json!({ "allThreadsContinued": true }));
+                return Flow::Resume;
+            },
+            "next" => {
+                step(debugger, vm, arguments, StepMode::StepOver(debugger.depth));
+                self.respond(request, true, json!({}));
+                return Flow::Resume;
+            },
+            "stepIn" => {
+                step(debugger, vm, arguments, StepMode::Step);
+                self.respond(request, true, json!({}));
+                return Flow::Resume;
+            },
+            "stepOut" => {
+                debugger.mode = StepMode::StepOut(debugger.depth);
+                self.respond(request, true, json!({}));
+                return Flow::Resume;
+            },
+            // Going back doesn't resume execution, VM stays paused in the past
+            "stepBack" | "reverseContinue" if vm.time_travel.is_none() => {
+                self.respond_error(request, "Time travel is not enabled, start VM with --time-travel");
+            },
+            "stepBack" => {
+                debugger.step_back(vm);
+                self.respond(request, true, json!({}));
+                self.send_event("stopped", json!({ "reason": "step", "threadId": THREAD_ID, "allThreadsStopped": true }));
+            },
+            "reverseContinue" => {
+                let reason = match debugger.reverse_continue(vm)
+                {
+                    true => "breakpoint",
+                    false => "entry"
+                };
+                self.respond(request, true, json!({}));
+                self.send_event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }));
+            },
+            "pause" => {
+                debugger.pause_requested = true;
+                self.respond(request, true, json!({}));
+            },
+            "disconnect" => {
+                self.respond(request, true, json!({}));
+                let terminate = arguments["terminateDebuggee"].as_bool().unwrap_or(false);
+                self.detach(debugger, vm);
+                debugger.stop_requested = terminate;
+                return Flow::Resume;
+            },
+            "terminate" => {
+                // Run ends as cancelled, "terminated" event is sent on exit
+                self.respond(request, true, json!({}));
+                debugger.stop_requested = true;
+                return Flow::Resume;
+            },
+            command => {
+                self.respond_error(request, &format!("Request '{command}' is not supported"));
+            }
+        }
+
+        Flow::Wait
+    }
+}
+
+impl DebugFrontend for DapFrontend
+{
+    // Handles client requests until configuration is done
+    fn on_start(&mut self, debugger: &mut Debugger, vm: &mut VM)
+    {
+        while let Ok(request) = self.requests.recv()
+        {
+            let flow = self.handle(&request, debugger, vm);
+            if matches!(flow, Flow::Configured) || debugger.stop_requested
+            {
+                return;
+            }
+        }
+        self.detach(debugger, vm);
+    }
+
+    fn on_pause(&mut self, debugger: &mut Debugger, vm: &mut VM, reason: PauseReason)
+    {
+        let reason = match reason
+        {
+            PauseReason::Entry => "entry",
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::Step => "step",
+            PauseReason::Pause => "pause",
+            PauseReason::Watchpoint => "data breakpoint",
+        };
+        self.send_event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }));
+
+        loop
+        {
+            match self.requests.recv()
+            {
+                Ok(request) => {
+                    if let Flow::Resume = self.handle(&request, debugger, vm)
+                    {
+                        return;
+                    }
+                },
+                Err(_) => {
+                    self.detach(debugger, vm);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, debugger: &mut Debugger, vm: &mut VM)
+    {
+        loop
+        {
+            match self.requests.try_recv()
+            {
+                Ok(request) => {
+                    self.handle(&request, debugger, vm);
+                },
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.detach(debugger, vm);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn on_exit(&mut self, exit_code: i32)
+    {
+        self.send_event("exited", json!({ "exitCode": exit_code }));
+        self.send_event("terminated", json!({}));
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<JsonValue>
+{
+    let mut content_length = None;
+
+    loop
+    {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0
+        {
+            return None
+        }
+
+        let line = line.trim_end();
+        if line.is_empty()
+        {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:")
+        {
+            content_length = length.trim().parse().ok();
+        }
+    }
+
+    let mut body = vec![0; content_length?];
+    reader.read_exact(&mut body).ok()?;
+
+    serde_json::from_slice(&body).ok()
+}
+
+// Client asks for instruction granularity in disassembly view, otherwise stepping goes by source lines
+fn step(debugger: &mut Debugger, vm: &VM, arguments: &JsonValue, mode: StepMode)
+{
+    match arguments["granularity"].as_str()
+    {
+        Some("instruction") => debugger.mode = mode,
+        _ => debugger.step_by_line(vm, mode)
+    }
+}
+
+fn format_offset(offset: usize) -> String
+{
+    format!("0x{:04X}", offset)
+}
+
+fn variable(name: String, value: String) -> JsonValue
+{
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+// Memory range shown as a variable
+struct Slot
+{
+    name: String,
+    address: i32,
+    size: i32,
+    type_index: Option<u32>,
+}
+
+// Arguments are placed right before return address, everything from rbp up to the next frame is local variables
+fn local_slots(vm: &VM, trace: &StackTrace, frame_index: usize) -> Vec<Slot>
+{
+    let frame = match trace.frames.get(frame_index)
+    {
+        Some(frame) => frame,
+        None => return Vec::new()
+    };
+
+    let mut slots = Vec::new();
+
+    if let Some(function_index) = frame.function_index
+    {
+        let function_info = &vm.module.table.functions[function_index];
+        let mut address = frame.base_pointer - 8;
+
+        for argument in function_info.arguments.iter().rev()
+        {
+            let size = vm.module.type_size(argument.type_index);
+            address -= size;
+            if vm.memory.is_valid_range(address, size)
+            {
+                slots.insert(0, Slot { name: argument.name.clone(), address, size, type_index: Some(argument.type_index) });
+            }
+        }
+
+        for local in vm.module.debug_info.iter().flat_map(|debug_info| debug_info.locals_of(function_index))
+        {
+            let address = frame.base_pointer + local.rbp_offset;
+            let size = vm.module.type_size(local.type_index);
+            if vm.memory.is_valid_range(address, size) && slots.iter().all(|slot| slot.address != address)
+            {
+                slots.push(Slot { name: local.name.clone(), address, size, type_index: Some(local.type_index) });
+            }
+        }
+    }
+
+    let frame_end = match frame_index
+    {
+        0 => vm.memory.stack_pointer,
+        _ => trace.frames[frame_index - 1].base_pointer - 8
+    };
+
+    // Memory without names is shown by rbp offsets
+    let mut address = frame.base_pointer;
+    while address < frame_end
+    {
+        if let Some(named) = slots.iter().find(|slot| slot.address <= address && address < slot.address + slot.size)
+        {
+            address = named.address + named.size;
+            continue;
+        }
+
+        let next_named = slots.iter().map(|slot| slot.address).filter(|a| *a > address).min().unwrap_or(frame_end);
+        let size = (next_named.min(frame_end) - address).min(4);
+        slots.push(Slot { name: format!("rbp+{}", address - frame.base_pointer), address, size, type_index: None });
+        address += size;
+    }
+
+    slots
+}
+
+fn local_variables(vm: &VM, trace: &StackTrace, frame_index: usize) -> Vec<JsonValue>
+{
+    local_slots(vm, trace, frame_index).into_iter().map(|slot| {
+        let value = match (slot.type_index, slot.size)
+        {
+            (Some(type_index), _) => vm.read_value(slot.address, type_index).to_string(),
+            (None, 4) => vm.memory.read_int(slot.address).to_string(),
+            (None, _) => format!("{:?}", vm.memory.read(slot.address, slot.size))
+        };
+        variable(slot.name, value)
+    }).collect()
+}
+
+fn register_variables(vm: &VM, trace: &StackTrace, frame_index: usize) -> Vec<JsonValue>
+{
+    let frame = match trace.frames.get(frame_index)
+    {
+        Some(frame) => frame,
+        None => return Vec::new()
+    };
+
+    vec![
+        variable(String::from("current"), format_offset(frame.offset)),
+        variable(String::from("rbp"), frame.base_pointer.to_string()),
+        variable(String::from("rsp"), vm.memory.stack_pointer.to_string()),
+        variable(String::from("heap"), vm.memory.heap_pointer.to_string()),
+    ]
+}
+
+fn heap_slots(vm: &VM) -> Vec<Slot>
+{
+    let mut slots = Vec::new();
+    let mut address = Memory::HEAP_START;
+
+    while address < vm.memory.heap_pointer.min(Memory::STACK_SIZE)
+    {
+        let size = (vm.memory.heap_pointer.min(Memory::STACK_SIZE) - address).min(16);
+        slots.push(Slot { name: address.to_string(), address, size, type_index: None });
+        address += size;
+    }
+
+    slots
+}
+
+fn heap_variables(vm: &VM) -> Vec<JsonValue>
+{
+    heap_slots(vm).into_iter().map(|slot| {
+        let hex: Vec<String> = vm.memory.read(slot.address, slot.size).iter().map(|b| format!("{:02x}", b)).collect();
+        variable(slot.name, hex.join(" "))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::sink;
+    use std::sync::mpsc::Sender;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::debugger::ConsoleFrontend;
+    use crate::vm::fuel::RunStatus;
+    use crate::vm::run_instrumented;
+
+    fn frontend() -> (DapFrontend, Sender<JsonValue>)
+    {
+        let (sender, requests) = channel();
+        let frontend = DapFrontend {
+            writer: Arc::new(Mutex::new(DapWriter { output: Box::from(sink()), seq: 0 })),
+            requests,
+            function_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            source_breakpoints: HashMap::new(),
+            data_breakpoints: Vec::new(),
+        };
+        (frontend, sender)
+    }
+
+    fn request(command: &str, arguments: JsonValue) -> JsonValue
+    {
+        json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    #[test]
+    fn detach_removes_data_breakpoints()
+    {
+        let mut vm = VM::new(call_module(0));
+        let (mut frontend, sender) = frontend();
+        let mut debugger = Debugger::new(Box::from(ConsoleFrontend));
+
+        frontend.handle(&request("setDataBreakpoints", json!({ "breakpoints": [{ "dataId": "0:4" }] })), &mut debugger, &mut vm);
+        assert!(vm.memory.record_writes);
+
+        drop(sender);
+        frontend.poll(&mut debugger, &mut vm);
+        assert!(vm.watchpoints.is_empty());
+        assert!(!vm.memory.record_writes);
+    }
+
+    #[test]
+    fn terminate_stops_run_as_cancelled()
+    {
+        let mut vm = VM::new(call_module(0));
+        let (frontend, sender) = frontend();
+        sender.send(request("terminate", json!({}))).unwrap();
+
+        let mut debugger = Some(Debugger::new(Box::from(frontend)));
+        let status = run_instrumented(&mut vm, &mut debugger, &mut None, &mut None);
+        assert!(matches!(status, RunStatus::Cancelled(_)));
+        assert_eq!(vm.byte_code.current, 0);
+    }
+}
\ No newline at end of file
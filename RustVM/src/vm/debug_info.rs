@@ -0,0 +1,221 @@
+﻿use crate::vm::binary_file::BinaryFile;
+
+// Optional section written by compiler after managed code. Loaders which don't know about it just stop reading before it.
+// Layout: "ADBG" [version: int]
+//         [files count: int] [path: string]...
+//         [lines count: int] ([offset: uint] [file: uint] [line: uint] [column: uint])... sorted by offset
+//         [locals count: int] ([function: uint] [name: string] [rbp offset: int] [type: uint])...
+
+const MAGIC: &[u8; 4] = b"ADBG";
+const VERSION: i32 = 1;
+
+pub struct DebugInfo
+{
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+    pub locals: Vec<LocalVariable>,
+}
+
+// Instructions from offset up to the next entry belong to this source position
+pub struct LineEntry
+{
+    pub offset: u32,
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+pub struct LocalVariable
+{
+    pub function: u32,
+    pub name: String,
+    pub rbp_offset: i32,
+    pub type_index: u32,
+}
+
+pub fn deserialize_debug_info(file: &mut BinaryFile<&[u8]>) -> Option<DebugInfo>
+{
+    if file.bytes.get(file.current..file.current + 4) != Some(MAGIC.as_slice())
+    {
+        return None
+    }
+    file.next_range(4);
+
+    // Section is optional, module from a newer compiler or a damaged one still runs without it
+    let version = file.try_next_int()?;
+    if version != VERSION
+    {
+        return None
+    }
+
+    let files_count = file.try_next_count()?;
+    let files = (0..files_count).map(|_| file.try_next_string()).collect::<Option<Vec<String>>>()?;
+
+    let lines_count = file.try_next_count()?;
+    let mut lines = (0..lines_count).map(|_| Some(LineEntry {
+        offset: file.try_next_uint()?,
+        file: file.try_next_uint()?,
+        line: file.try_next_uint()?,
+        column: file.try_next_uint()?,
+    })).collect::<Option<Vec<LineEntry>>>()?;
+    // line_at searches by offset, so the table is sorted even if compiler hasn't done it
+    lines.sort_by_key(|entry| entry.offset);
+
+    let locals_count = file.try_next_count()?;
+    let locals = (0..locals_count).map(|_| Some(LocalVariable {
+        function: file.try_next_uint()?,
+        name: file.try_next_string()?,
+        rbp_offset: file.try_next_int()?,
+        type_index: file.try_next_uint()?,
+    })).collect::<Option<Vec<LocalVariable>>>()?;
+
+    Some(DebugInfo {
+        files,
+        lines,
+        locals,
+    })
+}
+
+impl DebugInfo
+{
+    pub fn line_at(&self, offset: usize) -> Option<&LineEntry>
+    {
+        let index = self.lines.partition_point(|entry| entry.offset as usize <= offset);
+        match index
+        {
+            0 => None,
+            _ => Some(&self.lines[index - 1])
+        }
+    }
+
+    // "file:line:column" of instruction at given offset
+    pub fn location_at(&self, offset: usize) -> Option<String>
+    {
+        let entry = self.line_at(offset)?;
+        Some(format!("{}:{}:{}", self.file_name(entry.file), entry.line, entry.column))
+    }
+
+    pub fn file_name(&self, file: u32) -> &str
+    {
+        self.files.get(file as usize).map(|f| f.as_str()).unwrap_or("<unknown>")
+    }
+
+    // File is matched by full path or its ending, so "main.ab" finds "src/main.ab"
+    pub fn find_file(&self, path: &str) -> Option<u32>
+    {
+        let path = path.replace('\\', "/");
+        self.files.iter().position(|f| {
+            let f = f.replace('\\', "/");
+            f == path || path.ends_with(&format!("/{f}")) || f.ends_with(&format!("/{path}"))
+        }).map(|i| i as u32)
+    }
+
+    // First instruction of given line. If line has no code, the nearest next line with code is taken.
+    pub fn offset_for_line(&self, path: &str, line: u32) -> Option<usize>
+    {
+        let file = self.find_file(path)?;
+
+        let best_line = self.lines.iter()
+            .filter(|entry| entry.file == file && entry.line >= line)
+            .map(|entry| entry.line)
+            .min()?;
+
+        self.lines.iter()
+            .filter(|entry| entry.file == file && entry.line == best_line)
+            .map(|entry| entry.offset as usize)
+            .min()
+    }
+
+    pub fn locals_of(&self, function_index: usize) -> impl Iterator<Item = &LocalVariable>
+    {
+        self.locals.iter().filter(move |local| local.function as usize == function_index)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn int(bytes: &mut Vec<u8>, value: i32)
+    {
+        bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn string(bytes: &mut Vec<u8>, value: &str)
+    {
+        int(bytes, value.len() as i32);
+        bytes.extend_from_slice(value.as_bytes());
+    }
+
+    // Lines are (offset, line) in file 0, written in given order
+    fn section(lines: &[(i32, i32)]) -> Vec<u8>
+    {
+        let mut bytes = Vec::from(MAGIC.as_slice());
+        int(&mut bytes, VERSION);
+        int(&mut bytes, 1);
+        string(&mut bytes, "src/main.ab");
+
+        int(&mut bytes, lines.len() as i32);
+        for (offset, line) in lines
+        {
+            for value in [*offset, 0, *line, 5]
+            {
+                int(&mut bytes, value);
+            }
+        }
+
+        int(&mut bytes, 1);
+        int(&mut bytes, 0);
+        string(&mut bytes, "sum");
+        int(&mut bytes, 8);
+        int(&mut bytes, 3);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Option<DebugInfo>
+    {
+        deserialize_debug_info(&mut BinaryFile::new(bytes))
+    }
+
+    #[test]
+    fn lines_and_locals_are_read()
+    {
+        let debug_info = parse(&section(&[(0, 1), (10, 3), (20, 4)])).unwrap();
+
+        assert_eq!(debug_info.location_at(15).as_deref(), Some("src/main.ab:3:5"));
+        assert_eq!(debug_info.offset_for_line("main.ab", 2), Some(10));
+        assert!(debug_info.offset_for_line("main.ab", 5).is_none());
+
+        let locals: Vec<&str> = debug_info.locals_of(0).map(|local| local.name.as_str()).collect();
+        assert_eq!(locals, ["sum"]);
+    }
+
+    #[test]
+    fn unsorted_lines_are_sorted()
+    {
+        let debug_info = parse(&section(&[(20, 4), (0, 1), (10, 3)])).unwrap();
+        assert_eq!(debug_info.line_at(25).map(|entry| entry.line), Some(4));
+        assert_eq!(debug_info.line_at(9).map(|entry| entry.line), Some(1));
+    }
+
+    #[test]
+    fn damaged_section_is_ignored()
+    {
+        let bytes = section(&[(0, 1), (10, 3)]);
+        for length in 0..bytes.len()
+        {
+            assert!(parse(&bytes[..length]).is_none(), "section cut at {length} is read");
+        }
+
+        // Negative files count
+        let mut bytes = section(&[]);
+        bytes[8..12].copy_from_slice(&(-1i32).to_ne_bytes());
+        assert!(parse(&bytes).is_none());
+
+        // String longer than the section
+        let mut bytes = section(&[]);
+        bytes[12..16].copy_from_slice(&i32::MAX.to_ne_bytes());
+        assert!(parse(&bytes).is_none());
+    }
+}
\ No newline at end of file
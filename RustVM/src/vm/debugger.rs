@@ -0,0 +1,628 @@
+﻿use std::collections::BTreeSet;
+use std::io::{stdin, stdout, Write};
+use crate::vm::disassembler::decode;
+use crate::vm::opcodes::OpCode;
+use crate::vm::stack_trace::capture_stack_trace;
+use crate::vm::vm::VM;
+use crate::vm::watchpoints::{WatchAction, Watchpoints};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StepMode
+{
+    Continue,
+    Step,
+    // Pause when call depth gets back to (or below) the given one
+    StepOver(i32),
+    // Pause when call depth gets below the given one
+    StepOut(i32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PauseReason
+{
+    Entry,
+    Breakpoint,
+    Step,
+    Pause,
+    Watchpoint,
+}
+
+// User interface of the debugger: console prompt or editor connected through DAP
+pub trait DebugFrontend
+{
+    // Called once before execution starts
+    fn on_start(&mut self, _debugger: &mut Debugger, _vm: &mut VM) {}
+
+    // Called when execution is paused. Returns when user resumes it (after setting debugger.mode).
+    fn on_pause(&mut self, debugger: &mut Debugger, vm: &mut VM, reason: PauseReason);
+
+    // Called periodically while guest is running, so frontend can handle asynchronous requests
+    fn poll(&mut self, _debugger: &mut Debugger, _vm: &mut VM) {}
+
+    fn on_exit(&mut self, _exit_code: i32) {}
+}
+
+pub struct Debugger
+{
+    pub breakpoints: BTreeSet<usize>,
+    pub mode: StepMode,
+    pub depth: i32,
+    pub pause_requested: bool,
+    pub stop_on_entry: bool,
+    // Source line (file, line) stepping has started from. Step ends only on another line.
+    pub step_line: Option<(u32, u32)>,
+    // Set by frontend to end the run, it stops as cancelled so the usual reports are still written
+    pub stop_requested: bool,
+
+    frontend: Option<Box<dyn DebugFrontend>>,
+    started: bool,
+    instructions_since_poll: u32,
+}
+
+// How many instructions are executed between frontend polls
+const POLL_INTERVAL: u32 = 4096;
+
+impl Debugger
+{
+    pub fn new(frontend: Box<dyn DebugFrontend>) -> Self
+    {
+        Self {
+            breakpoints: BTreeSet::new(),
+            mode: StepMode::Continue,
+            depth: 0,
+            pause_requested: false,
+            stop_on_entry: true,
+            step_line: None,
+            stop_requested: false,
+            frontend: Some(frontend),
+            started: false,
+            instructions_since_poll: 0,
+        }
+    }
+
+    // Breakpoint is either byte code offset (decimal or 0x hex), source line (file.ab:12) or function name
+    pub fn resolve_location(vm: &VM, location: &str) -> Option<usize>
+    {
+        if let Some(hex) = location.strip_prefix("0x")
+        {
+            return usize::from_str_radix(hex, 16).ok()
+        }
+        if let Ok(offset) = location.parse()
+        {
+            return Some(offset)
+        }
+
+        if let (Some(debug_info), Some((path, line))) = (&vm.module.debug_info, location.rsplit_once(':'))
+            && let Ok(line) = line.parse()
+        {
+            return debug_info.offset_for_line(path, line)
+        }
+
+        vm.module.find_function(location).map(|i| vm.module.table.functions[i].pointed_opcode as usize)
+    }
+
+    pub fn add_breakpoint(&mut self, vm: &VM, location: &str) -> Option<usize>
+    {
+        let offset = Self::resolve_location(vm, location)?;
+        self.breakpoints.insert(offset);
+        Some(offset)
+    }
+
+    // Called before each instruction: pauses execution if needed and tracks call depth
+    pub fn on_instruction(&mut self, vm: &mut VM)
+    {
+        self.instructions_since_poll += 1;
+        if self.instructions_since_poll >= POLL_INTERVAL
+        {
+            self.instructions_since_poll = 0;
+            self.with_frontend(|frontend, debugger| frontend.poll(debugger, vm));
+        }
+
+        if let Some(reason) = self.pause_reason(vm)
+        {
+            self.pause(vm, reason);
+        }
+
+        vm.record_step(self.depth);
+        self.track_depth(vm);
+    }
+
+    // Goes back to the state before the previous instruction, false if log has nothing before
+    pub fn step_back(&mut self, vm: &mut VM) -> bool
+    {
+        match vm.find_step_back(|_, _| true)
+        {
+            Some(step) => {
+                self.travel(vm, step);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Goes back to the latest breakpoint hit or to the start of log. Returns false if there was no breakpoint.
+    pub fn reverse_continue(&mut self, vm: &mut VM) -> bool
+    {
+        let breakpoint = vm.find_step_back(|offset, _| self.breakpoints.contains(&offset));
+        if let Some(step) = breakpoint.or(vm.first_step())
+        {
+            self.travel(vm, step);
+        }
+        breakpoint.is_some()
+    }
+
+    // Goes back to the latest instruction which has written into given range, false if there is none
+    pub fn back_to_write(&mut self, vm: &mut VM, address: i32, size: i32) -> bool
+    {
+        let step = vm.find_step_back(|_, writes| {
+            writes.iter().any(|write| write.address < address + size && address < write.address + write.new.len() as i32)
+        });
+
+        match step
+        {
+            Some(step) => {
+                self.travel(vm, step);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn travel(&mut self, vm: &mut VM, step: u64)
+    {
+        self.depth = vm.travel_to(step, self.depth);
+        self.mode = StepMode::Continue;
+        self.step_line = None;
+    }
+
+    // Pauses right away, for events found outside of instruction boundaries (like watchpoint hits)
+    pub fn pause(&mut self, vm: &mut VM, reason: PauseReason)
+    {
+        self.mode = StepMode::Continue;
+        self.pause_requested = false;
+        self.step_line = None;
+        self.with_frontend(|frontend, debugger| frontend.on_pause(debugger, vm, reason));
+    }
+
+    // Source line of instruction at current offset
+    pub fn current_line(vm: &VM) -> Option<(u32, u32)>
+    {
+        let entry = vm.module.debug_info.as_ref()?.line_at(vm.byte_code.current)?;
+        Some((entry.file, entry.line))
+    }
+
+    // Steps by source lines if module has debug info, otherwise by instructions
+    pub fn step_by_line(&mut self, vm: &VM, mode: StepMode)
+    {
+        self.mode = mode;
+        self.step_line = Self::current_line(vm);
+    }
+
+    pub fn on_start(&mut self, vm: &mut VM)
+    {
+        self.with_frontend(|frontend, debugger| frontend.on_start(debugger, vm));
+    }
+
+    pub fn on_exit(&mut self, exit_code: i32)
+    {
+        self.with_frontend(|frontend, _| frontend.on_exit(exit_code));
+    }
+
+    fn with_frontend(&mut self, action: impl FnOnce(&mut Box<dyn DebugFrontend>, &mut Debugger))
+    {
+        let mut frontend = self.frontend.take().expect("Debugger frontend is already in use");
+        action(&mut frontend, self);
+        self.frontend = Some(frontend);
+    }
+
+    fn pause_reason(&mut self, vm: &VM) -> Option<PauseReason>
+    {
+        if self.stop_requested
+        {
+            return None
+        }
+        if !self.started
+        {
+            self.started = true;
+            if self.stop_on_entry
+            {
+                return Some(PauseReason::Entry)
+            }
+        }
+
+        if self.pause_requested
+        {
+            return Some(PauseReason::Pause)
+        }
+        if self.breakpoints.contains(&vm.byte_code.current)
+        {
+            return Some(PauseReason::Breakpoint)
+        }
+
+        let step_done = match self.mode
+        {
+            StepMode::Continue => false,
+            StepMode::Step => true,
+            StepMode::StepOver(depth) => self.depth <= depth,
+            StepMode::StepOut(depth) => self.depth < depth,
+        };
+
+        if step_done && self.step_line.is_some()
+        {
+            // Instructions without line information and the rest of the same line are stepped over
+            let line = Self::current_line(vm);
+            if line.is_none() || line == self.step_line
+            {
+                return None
+            }
+        }
+
+        match step_done
+        {
+            true => Some(PauseReason::Step),
+            false => None
+        }
+    }
+
+    // Tracks call depth for step-over and step-out. Called right before instruction execution.
+    fn track_depth(&mut self, vm: &VM)
+    {
+        let offset = vm.byte_code.current;
+        let byte_opcode = vm.byte_code.bytes[offset];
+
+        if byte_opcode == OpCode::Call as u8
+        {
+            let function_index = i32::from_ne_bytes(vm.byte_code.bytes[offset + 1..offset + 5].try_into().unwrap());
+            if vm.module.table.functions[function_index as usize].pointed_module == 0
+            {
+                self.depth += 1;
+            }
+        }
+        else if byte_opcode == OpCode::Return as u8
+        {
+            self.depth -= 1;
+        }
+    }
+}
+
+// Debugger frontend reading commands from stdin
+pub struct ConsoleFrontend;
+
+impl DebugFrontend for ConsoleFrontend
+{
+    // Reads commands from stdin until user resumes execution
+    fn on_pause(&mut self, debugger: &mut Debugger, vm: &mut VM, _reason: PauseReason)
+    {
+        print_location(vm);
+
+        loop
+        {
+            print!("(adb) ");
+            stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin().read_line(&mut line).unwrap() == 0
+            {
+                // stdin is closed, nobody is going to debug
+                debugger.breakpoints.clear();
+                debugger.mode = StepMode::Continue;
+                return;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty()
+            {
+                continue;
+            }
+
+            match parts[0]
+            {
+                "c" | "continue" => {
+                    debugger.mode = StepMode::Continue;
+                    return;
+                },
+                "s" | "step" => {
+                    debugger.step_by_line(vm, StepMode::Step);
+                    return;
+                },
+                "n" | "next" => {
+                    debugger.step_by_line(vm, StepMode::StepOver(debugger.depth));
+                    return;
+                },
+                "si" | "stepi" => {
+                    debugger.mode = StepMode::Step;
+                    return;
+                },
+                "ni" | "nexti" => {
+                    debugger.mode = StepMode::StepOver(debugger.depth);
+                    return;
+                },
+                "o" | "out" => {
+                    debugger.mode = StepMode::StepOut(debugger.depth);
+                    return;
+                },
+                "rsi" | "reverse-stepi" | "rc" | "reverse-continue" | "bw" | "back-write" if vm.time_travel.is_none() => {
+                    println!("Time travel is not enabled, start VM with --time-travel");
+                },
+                "rsi" | "reverse-stepi" => {
+                    match debugger.step_back(vm)
+                    {
+                        true => print_location(vm),
+                        false => println!("No earlier instructions in log")
+                    }
+                },
+                "rc" | "reverse-continue" => {
+                    if !debugger.reverse_continue(vm)
+                    {
+                        println!("No earlier breakpoint hits in log, went back to its start");
+                    }
+                    print_location(vm);
+                },
+                "bw" | "back-write" if parts.len() > 1 => {
+                    match Watchpoints::parse_range(&vm.memory, parts[1])
+                    {
+                        Some((address, size)) => {
+                            match debugger.back_to_write(vm, address, size)
+                            {
+                                true => print_location(vm),
+                                false => println!("No earlier writes to {}..{} in log", address, address + size)
+                            }
+                        },
+                        None => println!("Invalid range '{}'", parts[1])
+                    }
+                },
+                "b" | "break" if parts.len() > 1 => {
+                    match debugger.add_breakpoint(vm, parts[1])
+                    {
+                        Some(offset) => println!("Breakpoint set at 0x{:04X}", offset),
+                        None => println!("Unknown location '{}'", parts[1])
+                    }
+                },
+                "d" | "delete" if parts.len() > 1 => {
+                    match Debugger::resolve_location(vm, parts[1]).map(|offset| debugger.breakpoints.remove(&offset))
+                    {
+                        Some(true) => println!("Breakpoint deleted"),
+                        _ => println!("No breakpoint at '{}'", parts[1])
+                    }
+                },
+                "bl" | "breakpoints" => {
+                    for offset in &debugger.breakpoints
+                    {
+                        println!("  0x{:04X} {}", offset, function_name_at(vm, *offset));
+                    }
+                },
+                "r" | "regs" => {
+                    println!("current = 0x{:04X}, rbp = {}, rsp = {}, heap = {}", vm.byte_code.current, vm.memory.base_pointer, vm.memory.stack_pointer, vm.memory.heap_pointer);
+                },
+                "l" | "local" if parts.len() > 1 => {
+                    let rbp_offset: i32 = parts[1].parse().unwrap_or(0);
+                    let size: i32 = parts.get(2).and_then(|s| s.parse().ok()).unwrap_or(4);
+                    print_memory(vm, vm.memory.to_abs(rbp_offset), size);
+                },
+                "v" | "vars" => {
+                    let function_index = vm.module.function_at(vm.byte_code.current);
+                    match (&vm.module.debug_info, function_index)
+                    {
+                        (Some(debug_info), Some(function_index)) => {
+                            for local in debug_info.locals_of(function_index)
+                            {
+                                let address = vm.memory.to_abs(local.rbp_offset);
+                                if vm.memory.is_valid_range(address, vm.module.type_size(local.type_index))
+                                {
+                                    println!("  {} (rbp{:+}) = {}", local.name, local.rbp_offset, vm.read_value(address, local.type_index));
+                                }
+                            }
+                        },
+                        _ => println!("No local variables information")
+                    }
+                },
+                "m" | "mem" if parts.len() > 2 => {
+                    let address: i32 = parts[1].parse().unwrap_or(0);
+                    let count: i32 = parts[2].parse().unwrap_or(0);
+                    print_memory(vm, address, count);
+                },
+                "w" | "watch" if parts.len() > 1 => {
+                    let action = match parts.get(2)
+                    {
+                        Some(&"log") => WatchAction::Log,
+                        _ => WatchAction::Pause
+                    };
+                    match Watchpoints::parse_range(&vm.memory, parts[1])
+                    {
+                        Some((address, size)) => {
+                            let id = vm.add_watchpoint(address, size, action);
+                            println!("Watchpoint #{} set at {}..{}", id, address, address + size);
+                        },
+                        None => println!("Invalid range '{}'", parts[1])
+                    }
+                },
+                "wl" | "watchpoints" => {
+                    for watchpoint in &vm.watchpoints.list
+                    {
+                        let action = if watchpoint.action == WatchAction::Log { "log" } else { "pause" };
+                        println!("  #{} {}..{} {}", watchpoint.id, watchpoint.address, watchpoint.address + watchpoint.size, action);
+                    }
+                },
+                "wd" if parts.len() > 1 => {
+                    match parts[1].parse().map(|id| vm.remove_watchpoint(id))
+                    {
+                        Ok(true) => println!("Watchpoint deleted"),
+                        _ => println!("No watchpoint #{}", parts[1])
+                    }
+                },
+                "trace" if parts.len() > 1 => {
+                    match parts[1]
+                    {
+                        "on" => {
+                            if !vm.tracer.has_output()
+                            {
+                                vm.tracer.set_output(Box::from(stdout()));
+                            }
+                            vm.set_tracing(true);
+                        },
+                        "off" => vm.set_tracing(false),
+                        path => {
+                            vm.tracer.open(path, vm.tracer.format);
+                            vm.set_tracing(true);
+                        }
+                    }
+                },
+                "bt" | "backtrace" => {
+                    print!("{}", capture_stack_trace(vm, vm.byte_code.current));
+                },
+                "q" | "quit" => {
+                    debugger.stop_requested = true;
+                    return;
+                },
+                _ => {
+                    println!("Commands:");
+                    println!("  c, continue            resume execution");
+                    println!("  s, step                execute one source line (one instruction without debug info)");
+                    println!("  n, next                same as step, but over calls");
+                    println!("  si, stepi              execute one instruction");
+                    println!("  ni, nexti              execute one instruction, step over calls");
+                    println!("  o, out                 run until current function returns");
+                    println!("  rsi, reverse-stepi     go back one instruction (needs --time-travel)");
+                    println!("  rc, reverse-continue   go back to the last breakpoint hit");
+                    println!("  bw, back-write <range> go back to the last write to addr[:size] or rbp+N[:size]");
+                    println!("  b, break <loc>         set breakpoint at offset (12, 0xC), line (main.ab:12) or function (Type.Function)");
+                    println!("  d, delete <loc>        delete breakpoint");
+                    println!("  bl, breakpoints        list breakpoints");
+                    println!("  r, regs                show rbp, rsp and heap pointers");
+                    println!("  l, local <rbp> [size]  show rbp-relative memory");
+                    println!("  v, vars                show named local variables");
+                    println!("  m, mem <addr> <count>  show raw memory");
+                    println!("  w, watch <range> [log] pause (or log) on writes to addr[:size] or rbp+N[:size]");
+                    println!("  wl, watchpoints        list watchpoints");
+                    println!("  wd <id>                delete watchpoint");
+                    println!("  trace <on|off|file>    switch execution tracing (to stdout or file)");
+                    println!("  bt, backtrace          show guest call stack");
+                    println!("  q, quit                stop the VM");
+                }
+            }
+        }
+    }
+}
+
+fn function_name_at(vm: &VM, offset: usize) -> String
+{
+    match vm.module.function_at(offset)
+    {
+        Some(function_index) => vm.module.function_full_name(function_index),
+        None => String::from("<startup>")
+    }
+}
+
+fn print_location(vm: &VM)
+{
+    let offset = vm.byte_code.current;
+    let instruction = match decode(&vm.byte_code.bytes, offset)
+    {
+        Some(instruction) => instruction.describe(&vm.module),
+        None => format!("<invalid {}>", vm.byte_code.bytes[offset])
+    };
+
+    match vm.module.source_location(offset)
+    {
+        Some(location) => println!("Paused at {} (0x{:04X} {}) in {}", location, offset, instruction, function_name_at(vm, offset)),
+        None => println!("Paused at 0x{:04X} {} in {}", offset, instruction, function_name_at(vm, offset))
+    }
+}
+
+fn print_memory(vm: &VM, address: i32, count: i32)
+{
+    if !vm.memory.is_valid_range(address, count)
+    {
+        println!("Range {}..{} is out of memory", address, address + count);
+        return;
+    }
+
+    let bytes = vm.memory.read(address, count);
+
+    for (i, line) in bytes.chunks(16).enumerate()
+    {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("  {:5}: {}", address + 16 * i as i32, hex.join(" "));
+    }
+
+    if count == 4
+    {
+        println!("  as int: {}", vm.memory.read_int(address));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    // Records pauses (reason, offset, depth) and resumes with the next mode
+    struct ScriptFrontend
+    {
+        modes: VecDeque<StepMode>,
+        pauses: Rc<RefCell<Vec<(PauseReason, usize, i32)>>>,
+    }
+
+    impl DebugFrontend for ScriptFrontend
+    {
+        fn on_pause(&mut self, debugger: &mut Debugger, vm: &mut VM, reason: PauseReason)
+        {
+            self.pauses.borrow_mut().push((reason, vm.byte_code.current, debugger.depth));
+            debugger.mode = match self.modes.pop_front()
+            {
+                Some(StepMode::StepOut(_)) => StepMode::StepOut(debugger.depth),
+                Some(mode) => mode,
+                None => StepMode::Continue
+            };
+        }
+    }
+
+    fn debug(vm: &mut VM, breakpoint: Option<&str>, modes: Vec<StepMode>) -> Vec<(PauseReason, usize, i32)>
+    {
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let mut debugger = Debugger::new(Box::from(ScriptFrontend { modes: VecDeque::from(modes), pauses: pauses.clone() }));
+        debugger.stop_on_entry = breakpoint.is_none();
+        if let Some(location) = breakpoint
+        {
+            debugger.add_breakpoint(vm, location).unwrap();
+        }
+
+        run_instrumented(vm, &mut Some(debugger), &mut None, &mut None);
+        pauses.take()
+    }
+
+    #[test]
+    fn breakpoint_pauses_on_every_call()
+    {
+        let mut vm = VM::new(call_module(2));
+        let mix_start = vm.module.table.functions[0].pointed_opcode as usize;
+
+        let pauses = debug(&mut vm, Some("Program.Mix"), Vec::new());
+        assert_eq!(pauses, vec![(PauseReason::Breakpoint, mix_start, 1), (PauseReason::Breakpoint, mix_start, 1)]);
+        assert_eq!(Debugger::resolve_location(&vm, "0x10"), Some(16));
+        assert_eq!(Debugger::resolve_location(&vm, "Program.Missing"), None);
+    }
+
+    #[test]
+    fn steps_follow_call_depth()
+    {
+        let mut vm = VM::new(call_module(1));
+        let pauses = debug(&mut vm, None, vec![StepMode::Step]);
+        assert_eq!(pauses.len(), 2);
+        assert_eq!(pauses[0], (PauseReason::Entry, 0, 0));
+        assert_eq!(pauses[1].0, PauseReason::Step);
+        assert!(pauses[1].1 > 0);
+
+        // Out of Mix stops right after the call
+        let mut vm = VM::new(call_module(1));
+        let pauses = debug(&mut vm, Some("Program.Mix"), vec![StepMode::StepOut(0)]);
+        let (reason, offset, depth) = pauses[1];
+        assert_eq!((reason, depth), (PauseReason::Step, 0));
+        assert_eq!(vm.byte_code.bytes[offset - 5], OpCode::Call as u8);
+    }
+}
\ No newline at end of file
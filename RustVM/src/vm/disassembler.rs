@@ -0,0 +1,265 @@
+﻿use std::fmt::{Display, Formatter};
+use crate::vm::compiled_module::CompiledModule;
+use crate::vm::opcodes::{OpCode, VMCommand_Cmd};
+
+// Decodes byte code into instructions with operands, without executing them.
+// Operand layout must match the one read by functions in vm/functions.
+
+pub enum Operand
+{
+    // Address relative to rbp
+    Rbp(i32),
+    // Address stored in rbp-relative variable
+    Deref(i32),
+    // Absolute memory address
+    Abs(i32),
+    Int(i32),
+    Size(u8),
+    Bytes(Vec<u8>),
+    // Byte code offset
+    Target(usize),
+    Function(usize),
+    Name(String),
+}
+
+pub struct Instruction
+{
+    pub offset: usize,
+    pub opcode: OpCode,
+    pub operands: Vec<Operand>,
+    // Size in bytes including opcode
+    pub size: usize,
+}
+
+// Bounds checked reading of operands, gives None at the end of byte code
+pub struct Reader<'a>
+{
+    bytes: &'a [u8],
+    pub current: usize,
+}
+
+impl<'a> Reader<'a>
+{
+    pub fn new(bytes: &'a [u8], offset: usize) -> Self
+    {
+        Self {
+            bytes,
+            current: offset,
+        }
+    }
+    pub fn byte(&mut self) -> Option<u8>
+    {
+        let byte = *self.bytes.get(self.current)?;
+        self.current += 1;
+        Some(byte)
+    }
+    pub fn range(&mut self, count: usize) -> Option<&[u8]>
+    {
+        let slice = self.bytes.get(self.current..self.current + count)?;
+        self.current += count;
+        Some(slice)
+    }
+    pub fn int(&mut self) -> Option<i32>
+    {
+        Some(i32::from_ne_bytes(self.range(4)?.try_into().unwrap()))
+    }
+    fn rbp(&mut self) -> Option<Operand>
+    {
+        Some(Operand::Rbp(self.int()?))
+    }
+    fn size(&mut self) -> Option<Operand>
+    {
+        Some(Operand::Size(self.byte()?))
+    }
+}
+
+// Returns None if there is no valid instruction at given offset
+pub fn decode(bytes: &[u8], offset: usize) -> Option<Instruction>
+{
+    let mut r = Reader::new(bytes, offset);
+    let opcode = OpCode::try_from(r.byte()?).ok()?;
+
+    let operands = match opcode
+    {
+        OpCode::Allocate_Stack => match r.byte()?
+        {
+            0 => {
+                let size = r.byte()?;
+                vec![Operand::Size(size), Operand::Bytes(r.range(size as usize)?.to_vec())]
+            },
+            1 => vec![r.rbp()?, r.size()?],
+            _ => return None
+        },
+        OpCode::Allocate_Heap => match r.byte()?
+        {
+            0 => vec![r.rbp()?, Operand::Int(r.int()?)],
+            _ => vec![r.rbp()?]
+        },
+        OpCode::Deallocate_Stack => vec![Operand::Int(r.int()?)],
+        OpCode::Call => vec![Operand::Function(r.int()? as usize)],
+        OpCode::Jump => vec![Operand::Target(r.int()? as usize)],
+        OpCode::JumpIfFalse => vec![Operand::Target(r.int()? as usize), r.rbp()?, r.size()?],
+        OpCode::Mov => {
+            let dst = match r.byte()?
+            {
+                1 => r.rbp()?,
+                2 => Operand::Deref(r.int()?),
+                _ => return None
+            };
+            match r.byte()?
+            {
+                1 | 3 => vec![dst, r.rbp()?, r.size()?],
+                2 => {
+                    let size = r.byte()?;
+                    vec![dst, Operand::Bytes(r.range(size as usize)?.to_vec()), Operand::Size(size)]
+                },
+                4 => vec![dst, Operand::Abs(r.int()?), r.size()?],
+                _ => return None
+            }
+        },
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::DivRemainder |
+        OpCode::LeftBitShift | OpCode::RightBitShift | OpCode::BitAnd | OpCode::BitOr => vec![r.rbp()?, r.rbp()?, r.rbp()?, r.size()?],
+        OpCode::Compare => {
+            let (a, b, size, result) = (r.rbp()?, r.rbp()?, r.size()?, r.rbp()?);
+            let operator = match r.byte()?
+            {
+                0 => "==",
+                1 => "!=",
+                2 => ">",
+                3 => ">=",
+                4 => "<",
+                5 => "<=",
+                _ => return None
+            };
+            vec![a, Operand::Name(String::from(operator)), b, result, size]
+        },
+        OpCode::Negate | OpCode::PtrGet | OpCode::PtrSet => vec![r.rbp()?, r.rbp()?, r.size()?],
+        OpCode::Increment | OpCode::Decrement => vec![r.rbp()?, r.size()?],
+        OpCode::ToPtr_ValueType | OpCode::ToPtr_RefType => vec![r.rbp()?, r.rbp()?],
+        OpCode::PtrShift => match r.byte()?
+        {
+            0 => vec![r.rbp()?, Operand::Int(r.int()?)],
+            _ => vec![r.rbp()?, r.rbp()?, Operand::Int(r.int()?), r.size()?]
+        },
+        OpCode::FieldAccess => {
+            let (base, field_offset, size) = (r.rbp()?, Operand::Int(r.int()?), r.size()?);
+            let access = if r.byte()? > 0 { "get" } else { "set" };
+            vec![base, field_offset, size, Operand::Name(String::from(access)), r.rbp()?]
+        },
+        OpCode::Cast => vec![r.rbp()?, r.size()?, r.rbp()?, r.size()?],
+        OpCode::Section => {
+            let mut operands = Vec::new();
+            if r.byte()? == 0
+            {
+                let size = r.int()?;
+                operands.push(Operand::Bytes(r.range(size.max(0) as usize)?.to_vec()));
+            }
+            // Next section opcode and mode
+            r.range(2)?;
+            operands
+        },
+        OpCode::VMCommand => {
+            let cmd_byte = r.byte()?;
+            let cmd = match VMCommand_Cmd::try_from(cmd_byte)
+            {
+                Ok(cmd) => format!("{:?}", cmd),
+                Err(_) => format!("<invalid {}>", cmd_byte)
+            };
+
+            let mut operands = vec![Operand::Name(cmd)];
+            for _ in 0..r.int()?
+            {
+                let (rbp, size) = (r.rbp()?, r.size()?);
+                r.byte()?; // type index
+                operands.push(rbp);
+                operands.push(size);
+            }
+            operands
+        },
+        _ => Vec::new()
+    };
+
+    Some(Instruction {
+        offset,
+        opcode,
+        operands,
+        size: r.current - offset,
+    })
+}
+
+// Decodes instructions one after another, stops at the first invalid one
+pub fn decode_all(bytes: &[u8]) -> Vec<Instruction>
+{
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while let Some(instruction) = decode(bytes, offset)
+    {
+        offset += instruction.size;
+        instructions.push(instruction);
+    }
+
+    instructions
+}
+
+impl Display for Operand
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Operand::Rbp(offset) if *offset < 0 => write!(f, "rbp{}", offset),
+            Operand::Rbp(offset) => write!(f, "rbp+{}", offset),
+            Operand::Deref(offset) if *offset < 0 => write!(f, "[rbp{}]", offset),
+            Operand::Deref(offset) => write!(f, "[rbp+{}]", offset),
+            Operand::Abs(address) => write!(f, "@{}", address),
+            Operand::Int(value) => write!(f, "{}", value),
+            Operand::Size(size) => write!(f, "({} bytes)", size),
+            Operand::Bytes(bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "[{}]", hex.join(" "))
+            },
+            Operand::Target(offset) => write!(f, "0x{:04X}", offset),
+            Operand::Function(index) => write!(f, "function #{}", index),
+            Operand::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Display for Instruction
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "{:?}", self.opcode)?;
+
+        for (i, operand) in self.operands.iter().enumerate()
+        {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl Instruction
+{
+    // Same as Display, but called functions are shown by name
+    pub fn describe(&self, module: &CompiledModule) -> String
+    {
+        match (&self.opcode, self.operands.first())
+        {
+            (OpCode::Call, Some(Operand::Function(index))) if *index < module.table.functions.len() => {
+                format!("Call {}", module.function_full_name(*index))
+            },
+            _ => self.to_string()
+        }
+    }
+
+    pub fn operand_strings(&self, module: &CompiledModule) -> Vec<String>
+    {
+        self.operands.iter().map(|operand| match operand
+        {
+            Operand::Function(index) if *index < module.table.functions.len() => module.function_full_name(*index),
+            _ => operand.to_string()
+        }).collect()
+    }
+}
\ No newline at end of file
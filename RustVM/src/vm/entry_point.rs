@@ -0,0 +1,92 @@
+use crate::vm::compiled_module::CompiledModule;
+use crate::vm::functions::get_functions;
+use crate::vm::host_call::CallFrame;
+use crate::vm::opcodes::OpCode;
+use crate::vm::value::Value;
+use crate::vm::vm::VM;
+
+// Entry given by user, otherwise Main if module has it. None means byte code runs from offset 0.
+pub fn entry_name(module: &CompiledModule, entry: Option<String>) -> Option<String>
+{
+    entry.or_else(|| module.find_function("Main").map(|_| String::from("Main")))
+}
+
+// Initializes data section and pushes host call frame for the entry function.
+// Interpreter loop then runs it until it returns to host.
+pub fn enter_function(vm: &mut VM, function_name: &str, args: &[String]) -> CallFrame
+{
+    // Data section is always placed at the beginning of byte code
+    if vm.byte_code.bytes[0] == OpCode::Section as u8
+    {
+        let functions = get_functions();
+        let byte_opcode = vm.byte_code.next();
+        functions[byte_opcode as usize](vm);
+    }
+
+    let function_index = vm.module.find_function(function_name).unwrap_or_else(|| panic!("Entry function '{function_name}' not found"));
+    let function_info = &vm.module.table.functions[function_index];
+
+    // Command line arguments are strings for string parameters and numbers for everything else
+    let values: Vec<Value> = function_info.arguments.iter().zip(args).map(|(argument, value)| {
+        if vm.module.type_name(argument.type_index) == "string"
+        {
+            Value::Str(value.clone())
+        }
+        else
+        {
+            Value::Long(value.parse().unwrap_or_else(|_| panic!("Entry argument '{}' expects a number, but got '{value}'", argument.name)))
+        }
+    }).collect();
+
+    match vm.push_call_frame(function_index, &values)
+    {
+        Ok(frame) => frame,
+        Err(error) => panic!("Failed to enter function '{function_name}': {error}")
+    }
+}
+
+// Exit code is the first value returned by entry function
+pub fn read_exit_code(vm: &VM, frame: &CallFrame) -> i32
+{
+    if !vm.has_returned(frame)
+    {
+        return vm.memory.read_int(frame.returns_address)
+    }
+
+    let value = match vm.read_returns(frame)
+    {
+        Value::Tuple(values) => values[0].clone(),
+        value => value
+    };
+
+    value.as_i64().unwrap_or(0) as i32
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+
+    #[test]
+    fn main_is_default_entry()
+    {
+        let mut module = call_module(0);
+        assert_eq!(entry_name(&module, None), None);
+        assert_eq!(entry_name(&module, Some(String::from("Program.Mix"))).as_deref(), Some("Program.Mix"));
+
+        module.table.functions[0].name = String::from("Main");
+        assert_eq!(entry_name(&module, None).as_deref(), Some("Main"));
+    }
+
+    #[test]
+    fn exit_code_is_returned_value()
+    {
+        let mut vm = VM::new(call_module(0));
+        let frame = enter_function(&mut vm, "Program.Mix", &[String::from("5"), String::from("300")]);
+        vm.run();
+
+        assert!(vm.has_returned(&frame));
+        assert_eq!(read_exit_code(&vm, &frame), 49);
+    }
+}
\ No newline at end of file
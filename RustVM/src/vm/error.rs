@@ -0,0 +1,49 @@
+﻿use std::fmt::{Display, Formatter};
+
+// Errors of host calls, some of them are returned only to host API users
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum VMError
+{
+    FunctionNotFound(String),
+    NotManagedFunction(String),
+    ArgumentsCount { function: String, expected: usize, given: usize },
+    ArgumentType { function: String, argument: String, expected: String },
+    // Arguments don't fit into stack or heap
+    OutOfMemory(String),
+    // Guest code stopped (Exit or end of byte code) without returning to the host
+    NotReturned(String),
+    // Fuel is over before function has returned, call is resumed by finish_call
+    OutOfFuel(String),
+    // Another call is paused by fuel and must be finished or abandoned first
+    CallPending(String),
+    NoPendingCall,
+    // Stopped by cancellation token or deadline, stack trace shows where
+    Cancelled { function: String, stack_trace: String },
+    TimedOut { function: String, stack_trace: String },
+}
+
+pub type Result<T> = std::result::Result<T, VMError>;
+
+impl Display for VMError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            VMError::FunctionNotFound(name) => write!(f, "Function '{name}' not found"),
+            VMError::NotManagedFunction(name) => write!(f, "Function '{name}' is not defined in managed code"),
+            VMError::ArgumentsCount { function, expected, given } => write!(f, "Function '{function}' takes {expected} arguments, but {given} were given"),
+            VMError::ArgumentType { function, argument, expected } => write!(f, "Argument '{argument}' of function '{function}' expects {expected}"),
+            VMError::OutOfMemory(name) => write!(f, "Not enough memory to call function '{name}'"),
+            VMError::NotReturned(name) => write!(f, "Function '{name}' stopped execution without returning to host"),
+            VMError::OutOfFuel(name) => write!(f, "Function '{name}' ran out of fuel"),
+            VMError::CallPending(name) => write!(f, "Call of function '{name}' is paused and not finished"),
+            VMError::NoPendingCall => write!(f, "There is no paused call to finish"),
+            VMError::Cancelled { function, stack_trace } => write!(f, "Function '{function}' was cancelled\nGuest stack trace:\n{stack_trace}"),
+            VMError::TimedOut { function, stack_trace } => write!(f, "Function '{function}' timed out\nGuest stack trace:\n{stack_trace}"),
+        }
+    }
+}
+
+impl std::error::Error for VMError {}
\ No newline at end of file
@@ -0,0 +1,261 @@
+﻿use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+
+// Guest file access is limited to one host directory. Paths coming from guest are always relative to it.
+pub struct FileSandbox
+{
+    pub root: Option<PathBuf>,
+    pub can_read: bool,
+    pub can_write: bool,
+
+    handles: HashMap<i32, OpenFile>,
+    next_handle: i32,
+}
+
+// Open file with what is needed to open it again after restoring snapshot
+pub struct OpenFile
+{
+    pub file: File,
+    pub guest_path: String,
+    pub mode: i32,
+}
+
+pub struct SavedFile
+{
+    pub handle: i32,
+    pub guest_path: String,
+    pub mode: i32,
+    pub position: u64,
+}
+
+impl FileSandbox
+{
+    pub fn new(root: Option<PathBuf>, can_read: bool, can_write: bool) -> Self
+    {
+        let root = root.map(|path| path.canonicalize().unwrap_or_else(|_| panic!("File sandbox root '{}' doesn't exist", path.display())));
+
+        Self {
+            root,
+            can_read,
+            can_write,
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    pub fn disabled() -> Self
+    {
+        Self::new(None, false, false)
+    }
+
+    // Returns host path for guest path or None if it is not allowed to leave the root.
+    // Existing part of the path is returned canonicalized, so it has no symlinks left in it.
+    pub fn resolve(&self, guest_path: &str) -> Option<PathBuf>
+    {
+        let root = self.root.as_ref()?;
+        let mut parts = Vec::new();
+
+        for component in Path::new(guest_path).components()
+        {
+            match component
+            {
+                Component::Normal(part) => parts.push(part),
+                Component::CurDir => {},
+                // Absolute paths, drive prefixes and '..' are rejected
+                _ => return None
+            }
+        }
+
+        // Symlinks inside the root still may point outside of it. Links are not followed here,
+        // so dangling link is taken as existing and then fails to canonicalize.
+        let mut existing = root.clone();
+        let mut existing_count = 0;
+        while existing_count < parts.len() && existing.join(parts[existing_count]).symlink_metadata().is_ok()
+        {
+            existing.push(parts[existing_count]);
+            existing_count += 1;
+        }
+
+        let mut resolved = existing.canonicalize().ok()?;
+        if !resolved.starts_with(root)
+        {
+            return None
+        }
+
+        resolved.extend(&parts[existing_count..]);
+        Some(resolved)
+    }
+
+    // Opens guest file in mode 0 (read), 1 (write), 2 (append) or 3 (read and write).
+    // Returns None if mode is invalid, capability is denied, path is outside of the root or opening fails.
+    // Restored files are opened without creation or truncation.
+    pub fn open(&self, guest_path: &str, mode: i32, create: bool) -> Option<File>
+    {
+        let (need_read, need_write) = match mode
+        {
+            0 => (true, false),
+            1 | 2 => (false, true),
+            3 => (true, true),
+            _ => return None
+        };
+        if (need_read && !self.can_read) || (need_write && !self.can_write)
+        {
+            return None
+        }
+
+        let mut options = OpenOptions::new();
+        match mode
+        {
+            0 => options.read(true),
+            1 => options.write(true).create(create).truncate(create),
+            2 => options.append(true).create(create),
+            _ => options.read(true).write(true).create(create),
+        };
+
+        // Resolved path has no symlinks, file replaced by one after the check is not followed
+        #[cfg(unix)]
+        options.custom_flags(libc::O_NOFOLLOW);
+
+        options.open(self.resolve(guest_path)?).ok()
+    }
+
+    pub fn add_handle(&mut self, file: File, guest_path: &str, mode: i32) -> i32
+    {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        self.handles.insert(handle, OpenFile {
+            file,
+            guest_path: String::from(guest_path),
+            mode
+        });
+        handle
+    }
+    pub fn get_handle(&mut self, handle: i32) -> Option<&mut File>
+    {
+        self.handles.get_mut(&handle).map(|open_file| &mut open_file.file)
+    }
+    pub fn remove_handle(&mut self, handle: i32) -> Option<File>
+    {
+        self.handles.remove(&handle).map(|open_file| open_file.file)
+    }
+
+    // Open files sorted by handle
+    pub fn save(&self) -> Vec<SavedFile>
+    {
+        let mut files: Vec<SavedFile> = self.handles.iter().map(|(handle, open_file)| SavedFile {
+            handle: *handle,
+            guest_path: open_file.guest_path.clone(),
+            mode: open_file.mode,
+            position: (&open_file.file).stream_position().unwrap_or_else(|e| panic!("Failed to get position in file '{}': {e}", open_file.guest_path))
+        }).collect();
+
+        files.sort_by_key(|file| file.handle);
+        files
+    }
+
+    pub fn next_handle(&self) -> i32
+    {
+        self.next_handle
+    }
+
+    // Replaces open files with saved ones. Files are opened again without truncation and keep their handles.
+    pub fn restore(&mut self, files: &[SavedFile], next_handle: i32)
+    {
+        self.handles.clear();
+        self.next_handle = next_handle;
+
+        for SavedFile { handle, guest_path, mode, position } in files
+        {
+            let mut file = self.open(guest_path, *mode, false)
+                .unwrap_or_else(|| panic!("Failed to open file '{guest_path}' again, is file sandbox the same?"));
+            file.seek(SeekFrom::Start(*position)).unwrap_or_else(|e| panic!("Failed to seek in file '{guest_path}': {e}"));
+
+            self.handles.insert(*handle, OpenFile {
+                file,
+                guest_path: guest_path.clone(),
+                mode: *mode
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::env;
+    use std::fs;
+    use super::*;
+
+    // Folder with "root" directory for sandbox and place outside of it
+    fn test_folder(name: &str) -> PathBuf
+    {
+        let folder = env::temp_dir().join(format!("rustvm-{name}-{}", std::process::id()));
+        fs::create_dir_all(folder.join("root").join("inner")).unwrap();
+        folder
+    }
+
+    #[test]
+    fn paths_leaving_root_are_rejected()
+    {
+        let folder = test_folder("sandbox-paths");
+        let sandbox = FileSandbox::new(Some(folder.join("root")), true, true);
+        let root = sandbox.root.clone().unwrap();
+
+        assert_eq!(sandbox.resolve("inner/file.txt"), Some(root.join("inner").join("file.txt")));
+        assert_eq!(sandbox.resolve("./new.txt"), Some(root.join("new.txt")));
+        assert_eq!(sandbox.resolve("../outside.txt"), None);
+        assert_eq!(sandbox.resolve("inner/../../outside.txt"), None);
+        assert_eq!(sandbox.resolve(folder.join("outside.txt").to_str().unwrap()), None);
+        assert_eq!(FileSandbox::disabled().resolve("file.txt"), None);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_leaving_root_are_rejected()
+    {
+        use std::os::unix::fs::symlink;
+
+        let folder = test_folder("sandbox-links");
+        fs::write(folder.join("secret.txt"), "secret").unwrap();
+        symlink(&folder, folder.join("root").join("out")).unwrap();
+        symlink(folder.join("secret.txt"), folder.join("root").join("secret.txt")).unwrap();
+        symlink(folder.join("missing"), folder.join("root").join("dangling")).unwrap();
+        symlink("inner", folder.join("root").join("inside")).unwrap();
+        let sandbox = FileSandbox::new(Some(folder.join("root")), true, true);
+
+        assert_eq!(sandbox.resolve("out/secret.txt"), None);
+        assert!(sandbox.open("secret.txt", 0, false).is_none());
+        assert!(sandbox.open("dangling", 1, true).is_none());
+        assert!(sandbox.open("dangling/file.txt", 1, true).is_none());
+        assert!(!folder.join("missing").exists());
+        assert!(sandbox.open("inside/file.txt", 1, true).is_some());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn capabilities_limit_modes()
+    {
+        let folder = test_folder("sandbox-modes");
+        fs::write(folder.join("root").join("data.txt"), "data").unwrap();
+        let read_only = FileSandbox::new(Some(folder.join("root")), true, false);
+        let write_only = FileSandbox::new(Some(folder.join("root")), false, true);
+
+        assert!(read_only.open("data.txt", 0, false).is_some());
+        assert!(read_only.open("data.txt", 1, true).is_none());
+        assert!(read_only.open("data.txt", 3, false).is_none());
+        assert!(read_only.open("data.txt", 7, false).is_none());
+        assert!(write_only.open("data.txt", 0, false).is_none());
+        assert!(write_only.open("data.txt", 2, true).is_some());
+        assert!(FileSandbox::disabled().open("data.txt", 0, false).is_none());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
\ No newline at end of file
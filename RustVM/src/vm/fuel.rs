@@ -0,0 +1,129 @@
+﻿use crate::vm::cancellation::CHECK_INTERVAL;
+use crate::vm::functions::get_functions;
+use crate::vm::stack_trace::{capture_stack_trace, run_guarded, run_instruction, StackTrace};
+use crate::vm::vm::VM;
+
+// Fuel is the number of instructions VM may run. When it's over, execution pauses before the next instruction
+// and continues from it once host adds more fuel, so a program can be run in slices.
+// Compiled functions don't count instructions, so JIT is not used while fuel is limited.
+// Optimized program has fewer instructions, the same fuel goes further in it.
+
+pub enum RunStatus
+{
+    // Program exited or returned to host
+    Finished,
+    // Paused before instruction at byte_code.current
+    OutOfFuel,
+    // Stopped before instruction at byte_code.current by cancellation token or deadline
+    Cancelled(StackTrace),
+    TimedOut(StackTrace),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopReason
+{
+    OutOfFuel,
+    Cancelled,
+    TimedOut,
+}
+
+impl VM
+{
+    // None removes the limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>)
+    {
+        self.fuel = fuel;
+    }
+
+    // Does nothing if fuel is not limited
+    #[allow(dead_code)]
+    pub fn add_fuel(&mut self, fuel: u64)
+    {
+        if let Some(current) = &mut self.fuel
+        {
+            *current = current.saturating_add(fuel);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn remaining_fuel(&self) -> Option<u64>
+    {
+        self.fuel
+    }
+
+    // Takes fuel for one instruction, false if there is nothing left
+    #[inline(always)]
+    pub fn consume_fuel(&mut self) -> bool
+    {
+        match &mut self.fuel
+        {
+            None => true,
+            Some(0) => false,
+            Some(fuel) => {
+                *fuel -= 1;
+                true
+            }
+        }
+    }
+
+    // Checked before every instruction. Fuel is taken only if instruction is going to run.
+    #[inline(always)]
+    pub fn stop_reason(&mut self) -> Option<StopReason>
+    {
+        self.cancellation_countdown -= 1;
+        if self.cancellation_countdown == 0
+        {
+            self.cancellation_countdown = CHECK_INTERVAL;
+            if let Some(reason) = self.check_cancellation()
+            {
+                return Some(reason)
+            }
+        }
+
+        match self.consume_fuel()
+        {
+            true => None,
+            false => Some(StopReason::OutOfFuel)
+        }
+    }
+
+    // Status of run stopped before the current instruction (or finished if there is no reason)
+    pub fn run_status(&self, reason: Option<StopReason>) -> RunStatus
+    {
+        match reason
+        {
+            None => RunStatus::Finished,
+            Some(StopReason::OutOfFuel) => RunStatus::OutOfFuel,
+            Some(StopReason::Cancelled) => RunStatus::Cancelled(capture_stack_trace(self, self.byte_code.current)),
+            Some(StopReason::TimedOut) => RunStatus::TimedOut(capture_stack_trace(self, self.byte_code.current)),
+        }
+    }
+
+    // Runs from the current instruction until program finishes, fuel is over or it's cancelled.
+    // After that it can be called again to resume.
+    pub fn run(&mut self) -> RunStatus
+    {
+        if self.can_run_decoded()
+        {
+            return self.run_decoded()
+        }
+
+        run_guarded(self, |vm| {
+            let functions = get_functions();
+
+            while vm.byte_code.can_next()
+            {
+                if let Some(reason) = vm.stop_reason()
+                {
+                    return vm.run_status(Some(reason))
+                }
+
+                let offset = vm.byte_code.current;
+                let byte_opcode = vm.byte_code.next();
+                run_instruction(vm, functions, byte_opcode, offset);
+            }
+
+            RunStatus::Finished
+        })
+    }
+}
\ No newline at end of file
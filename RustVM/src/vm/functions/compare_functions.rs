@@ -60,6 +60,11 @@ pub fn compare(vm: &mut VM)
     let result_address = vm.next_address();
     let op = vm.byte_code.next();
 
+    compare_at(vm, a_address, b_address, size_in_bytes, result_address, op);
+}
+
+pub fn compare_at(vm: &mut VM, a_address: i32, b_address: i32, size_in_bytes: u8, result_address: i32, op: u8)
+{
     let a_value = vm.memory.read(a_address, size_in_bytes as i32);
     let b_value = vm.memory.read(b_address, size_in_bytes as i32);
 
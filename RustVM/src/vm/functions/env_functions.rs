@@ -0,0 +1,100 @@
+﻿use std::env;
+use crate::vm::functions::vm_command_functions::{read_argument_string, write_argument_int, VMCmdArgument};
+use crate::vm::vm::VM;
+
+// GetArgs(argv, [argc]) writes pointer to the array of guest arguments and, optionally, their count
+pub fn vm_get_args(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let args_address = vm.args_address;
+    let args_count = vm.memory.read_int(args_address);
+
+    write_argument_int(vm, &arguments[0], args_address as i64);
+
+    if arguments.len() > 1
+    {
+        write_argument_int(vm, &arguments[1], args_count as i64);
+    }
+}
+
+// GetEnv(result, name) writes pointer to new string with variable value.
+// Null pointer is written if variable is not set, not in the host's allow-list, name is not a valid string
+// or the value doesn't fit into heap.
+pub fn vm_get_env(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let name = read_argument_string(vm, &arguments[1]);
+
+    let mut pointer = 0;
+
+    if let Some(name) = name.filter(|name| vm.env_allow_list.contains(name))
+        && let Ok(value) = env::var(&name)
+    {
+        pointer = vm.memory.try_allocate_heap_string(&value).unwrap_or(0);
+    }
+
+    write_argument_int(vm, &arguments[0], pointer as i64);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::loop_module;
+    use crate::vm::memory::Memory;
+
+    const RESULT: VMCmdArgument = VMCmdArgument { rbp: 0, size_in_bytes: 4, type_index: 5 };
+    const NAME: VMCmdArgument = VMCmdArgument { rbp: 4, size_in_bytes: 4, type_index: 6 };
+    const COUNT: VMCmdArgument = VMCmdArgument { rbp: 8, size_in_bytes: 4, type_index: 3 };
+
+    fn read_string(vm: &VM, pointer: i32) -> String
+    {
+        let length = vm.memory.read_int(pointer);
+        String::from_utf8(vm.memory.read(pointer + 4, length).to_vec()).unwrap()
+    }
+
+    // Name is placed on stack, so heap is left for the value
+    fn get_env(vm: &mut VM, name: &str) -> i32
+    {
+        vm.memory.write_int(64, name.len() as i32);
+        vm.memory.write_slice(68, name.as_bytes());
+        vm.memory.write_int(4, 64);
+        vm_get_env(vm, &[RESULT, NAME]);
+        vm.memory.read_int(0)
+    }
+
+    #[test]
+    fn only_allowed_variables_are_read()
+    {
+        // Cargo sets these for test process
+        let mut vm = VM::new(loop_module(0));
+        vm.env_allow_list = vec![String::from("CARGO_PKG_NAME")];
+
+        let pointer = get_env(&mut vm, "CARGO_PKG_NAME");
+        assert_eq!(read_string(&vm, pointer), env::var("CARGO_PKG_NAME").unwrap());
+        assert_eq!(get_env(&mut vm, "CARGO_MANIFEST_DIR"), 0);
+
+        // Value which doesn't fit into heap
+        vm.memory.heap_pointer = Memory::STACK_SIZE - 12;
+        vm.env_allow_list.push(String::from("CARGO_MANIFEST_DIR"));
+        assert_eq!(get_env(&mut vm, "CARGO_MANIFEST_DIR"), 0);
+    }
+
+    #[test]
+    fn args_are_placed_on_heap()
+    {
+        let mut vm = VM::new(loop_module(0));
+        vm.place_args(&[String::from("first"), String::from("second")]);
+
+        vm_get_args(&mut vm, &[RESULT, COUNT]);
+        let array = vm.memory.read_int(0);
+        assert_eq!(vm.memory.read_int(8), 2);
+        assert_eq!(read_string(&vm, vm.memory.read_int(array + 4)), "first");
+        assert_eq!(read_string(&vm, vm.memory.read_int(array + 8)), "second");
+    }
+
+    #[test]
+    #[should_panic(expected = "Guest arguments need 1012 bytes of heap")]
+    fn too_long_args_are_reported()
+    {
+        VM::new(loop_module(0)).place_args(&[String::from("x").repeat(1000)]);
+    }
+}
\ No newline at end of file
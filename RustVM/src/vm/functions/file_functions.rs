@@ -0,0 +1,164 @@
+﻿use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::vm::functions::vm_command_functions::{get_argument_int, get_argument_string, set_argument_int, VMCmdArgument};
+use crate::vm::vm::VM;
+
+// All file commands take the result variable as the first argument.
+// Failures (denied capability, path outside of sandbox, io errors, malformed arguments) are reported to guest as -1 (or false), never as panic.
+
+pub fn vm_file_open(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let path = get_argument_string(vm, arguments, 1);
+    let mode = get_argument_int(vm, arguments, 2).and_then(|mode| i32::try_from(mode).ok()).unwrap_or(-1);
+
+    let file = path.as_ref().and_then(|path| vm.files.open(path, mode, true));
+    let handle = match (path, file)
+    {
+        (Some(path), Some(file)) => vm.files.add_handle(file, &path, mode),
+        _ => -1
+    };
+
+    set_argument_int(vm, arguments, 0, handle as i64);
+}
+
+pub fn vm_file_read(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let handle = get_argument_int(vm, arguments, 1);
+    let buffer_address = get_argument_int(vm, arguments, 2);
+    let count = get_argument_int(vm, arguments, 3);
+
+    let mut result = -1;
+
+    if let (Some(handle), Some(buffer_address), Some(count)) = (handle, buffer_address, count)
+        && vm.files.can_read
+        && vm.memory.is_valid_range(buffer_address as i32, count as i32)
+    {
+        let mut buffer = vec![0; count as usize];
+
+        if let Some(file) = vm.files.get_handle(handle as i32)
+            && let Ok(read_count) = file.read(&mut buffer)
+        {
+            vm.memory.write_slice(buffer_address as i32, &buffer[..read_count]);
+            result = read_count as i64;
+        }
+    }
+
+    set_argument_int(vm, arguments, 0, result);
+}
+
+pub fn vm_file_write(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let handle = get_argument_int(vm, arguments, 1);
+    let buffer_address = get_argument_int(vm, arguments, 2);
+    let count = get_argument_int(vm, arguments, 3);
+
+    let mut result = -1;
+
+    if let (Some(handle), Some(buffer_address), Some(count)) = (handle, buffer_address, count)
+        && vm.files.can_write
+        && vm.memory.is_valid_range(buffer_address as i32, count as i32)
+    {
+        let bytes = vm.memory.read(buffer_address as i32, count as i32).to_vec();
+
+        if let Some(file) = vm.files.get_handle(handle as i32)
+            && let Ok(written_count) = file.write(&bytes)
+        {
+            result = written_count as i64;
+        }
+    }
+
+    set_argument_int(vm, arguments, 0, result);
+}
+
+pub fn vm_file_close(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let handle = get_argument_int(vm, arguments, 1);
+
+    let result = match handle.and_then(|handle| vm.files.remove_handle(handle as i32))
+    {
+        Some(_) => 0,
+        None => -1
+    };
+
+    set_argument_int(vm, arguments, 0, result);
+}
+
+pub fn vm_file_seek(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let handle = get_argument_int(vm, arguments, 1);
+    let offset = get_argument_int(vm, arguments, 2);
+    let origin = get_argument_int(vm, arguments, 3);
+
+    let position = match (origin, offset)
+    {
+        (Some(0), Some(offset)) if offset >= 0 => Some(SeekFrom::Start(offset as u64)),
+        (Some(1), Some(offset)) => Some(SeekFrom::Current(offset)),
+        (Some(2), Some(offset)) => Some(SeekFrom::End(offset)),
+        _ => None
+    };
+
+    let mut result = -1;
+
+    if let (Some(position), Some(handle)) = (position, handle)
+        && let Some(file) = vm.files.get_handle(handle as i32)
+        && let Ok(new_position) = file.seek(position)
+    {
+        result = new_position as i64;
+    }
+
+    set_argument_int(vm, arguments, 0, result);
+}
+
+pub fn vm_file_exists(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let path = get_argument_string(vm, arguments, 1);
+
+    let exists = vm.files.can_read && path.and_then(|path| vm.files.resolve(&path)).is_some_and(|host_path| host_path.exists());
+
+    set_argument_int(vm, arguments, 0, exists as i64);
+}
+
+pub fn vm_file_delete(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let path = get_argument_string(vm, arguments, 1);
+
+    let deleted = vm.files.can_write && path.and_then(|path| vm.files.resolve(&path)).is_some_and(|host_path| fs::remove_file(host_path).is_ok());
+
+    set_argument_int(vm, arguments, 0, deleted as i64);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::loop_module;
+
+    const RESULT: VMCmdArgument = VMCmdArgument { rbp: 0, size_in_bytes: 4, type_index: 3 };
+    const HANDLE: VMCmdArgument = VMCmdArgument { rbp: 4, size_in_bytes: 4, type_index: 3 };
+
+    #[test]
+    fn malformed_arguments_give_minus_one()
+    {
+        let mut vm = VM::new(loop_module(0));
+
+        vm_file_read(&mut vm, &[RESULT]);
+        assert_eq!(vm.memory.read_int(0), -1);
+
+        vm.memory.write_int(0, 0);
+        vm_file_close(&mut vm, &[RESULT, VMCmdArgument { type_index: 9, ..HANDLE }]);
+        assert_eq!(vm.memory.read_int(0), -1);
+
+        vm.memory.write_int(0, 0);
+        vm_file_seek(&mut vm, &[RESULT, HANDLE, VMCmdArgument { rbp: 100_000, ..HANDLE }, HANDLE]);
+        assert_eq!(vm.memory.read_int(0), -1);
+
+        vm.memory.write_int(0, 0);
+        vm_file_exists(&mut vm, &[RESULT, HANDLE]);
+        assert_eq!(vm.memory.read_int(0), 0);
+
+        // Result which can't hold the value is left as is
+        vm_file_open(&mut vm, &[VMCmdArgument { size_in_bytes: 16, type_index: 4, ..RESULT }]);
+        vm_file_open(&mut vm, &[VMCmdArgument { rbp: -8, ..RESULT }]);
+        assert_eq!(vm.memory.read_int(0), 0);
+    }
+}
\ No newline at end of file
@@ -1,14 +1,15 @@
-﻿use crate::vm::VM;
+﻿use crate::vm::memory::Memory;
+use crate::vm::VM;
 use paste::paste;
 
 
 macro_rules! math_binary_op_sized {
     ($t:ty, $name:ident, $op:tt) => {
         paste! {
-            fn [<$name _ $t>](a_value: &[u8], b_value: &[u8]) -> Vec<u8> {
-                let a = $t::from_ne_bytes(a_value.try_into().unwrap());
-                let b = $t::from_ne_bytes(b_value.try_into().unwrap());
-                (a $op b).to_ne_bytes().to_vec()
+            fn [<$name _ $t>](memory: &mut Memory, a_address: i32, b_address: i32, result_address: i32) {
+                let a = $t::from_ne_bytes(memory.read_array(a_address));
+                let b = $t::from_ne_bytes(memory.read_array(b_address));
+                memory.write_array(result_address, (a $op b).to_ne_bytes());
             }
         }
     };
@@ -32,19 +33,21 @@ macro_rules! math_binary_op {
 				let result_address = vm.next_address();
 				let size_in_bytes = vm.byte_code.next();
 
-				let a_value = vm.memory.read(a_address, size_in_bytes as i32);
-				let b_value = vm.memory.read(b_address, size_in_bytes as i32);
+				[<$name _at>](vm, a_address, b_address, result_address, size_in_bytes);
+			}
+
+			pub fn [<$name _at>](vm: &mut VM, a_address: i32, b_address: i32, result_address: i32, size_in_bytes: u8)
+			{
+				let memory = &mut vm.memory;
 
-				let result = match size_in_bytes
+				match size_in_bytes
 				{
-					1 => [<$name _i8>](a_value, b_value),
-					2 => [<$name _i16>](a_value, b_value),
-					4 => [<$name _i32>](a_value, b_value),
-					8 => [<$name _i64>](a_value, b_value),
+					1 => [<$name _i8>](memory, a_address, b_address, result_address),
+					2 => [<$name _i16>](memory, a_address, b_address, result_address),
+					4 => [<$name _i32>](memory, a_address, b_address, result_address),
+					8 => [<$name _i64>](memory, a_address, b_address, result_address),
 					_ => panic!("Not supported number size ({size_in_bytes} bytes)")
-				};
-
-				vm.memory.write_vec(result_address, result);
+				}
 			}
             
             math_binary_op_each_size!($name, $op);
@@ -67,9 +70,9 @@ math_binary_op!(bit_or, |);
 macro_rules! math_unary_op_sized {
     ($t:ty, $name:ident, $op:expr) => {
         paste! {
-            fn [<$name _ $t>](a_value: &[u8]) -> Vec<u8> {
-                let a = $t::from_ne_bytes(a_value.try_into().unwrap());
-                ($op(a)).to_ne_bytes().to_vec()
+            fn [<$name _ $t>](memory: &mut Memory, value_address: i32) {
+                let a = $t::from_ne_bytes(memory.read_array(value_address));
+                memory.write_array(value_address, ($op(a)).to_ne_bytes());
             }
         }
     };
@@ -90,18 +93,21 @@ macro_rules! math_unary_op {
 				let value_address = vm.next_address();
 				let size_in_bytes = vm.byte_code.next();
 
-				let a_value = vm.memory.read(value_address, size_in_bytes as i32);
+				[<$name _at>](vm, value_address, size_in_bytes);
+			}
+
+			pub fn [<$name _at>](vm: &mut VM, value_address: i32, size_in_bytes: u8)
+			{
+				let memory = &mut vm.memory;
 
-				let result = match size_in_bytes
+				match size_in_bytes
 				{
-					1 => [<$name _i8>](a_value),
-					2 => [<$name _i16>](a_value),
-					4 => [<$name _i32>](a_value),
-					8 => [<$name _i64>](a_value),
+					1 => [<$name _i8>](memory, value_address),
+					2 => [<$name _i16>](memory, value_address),
+					4 => [<$name _i32>](memory, value_address),
+					8 => [<$name _i64>](memory, value_address),
 					_ => panic!("Not supported number size ({size_in_bytes} bytes)")
-				};
-
-				vm.memory.write_vec(value_address, result);
+				}
 			}
 
             math_unary_op_each_size!($name, $op);
@@ -110,43 +116,4 @@ macro_rules! math_unary_op {
 }
 
 math_unary_op!(increment, |a| a + 1);
-math_unary_op!(decrement, |a| a - 1);
-
-
-
-pub fn logical_not(vm: &mut VM)
-{
-    let a_address = vm.next_address();
-    let result_address = vm.next_address();
-    let size_in_bytes = vm.byte_code.next();
-    
-    let a_value = vm.memory.read(a_address, size_in_bytes as i32);
-    let result = !as_bool(a_value);
-    
-    let result_value = bool_to_vec(result, size_in_bytes as usize);
-    
-    vm.memory.write_vec(result_address, result_value);
-}
-
-fn as_bool(bytes: &[u8]) -> bool
-{
-    for b in bytes
-    {
-        if *b > 0
-        {
-            return true
-        }
-    }
-    false
-}
-fn bool_to_vec(value: bool, size: usize) -> Vec<u8>
-{
-    let mut vec = Vec::new();
-    vec.push(if value { 1u8 } else { 0u8 });
-    
-    for _ in (1..size)
-    {
-        vec.push(0)
-    }    
-    vec
-}
\ No newline at end of file
+math_unary_op!(decrement, |a| a - 1);
\ No newline at end of file
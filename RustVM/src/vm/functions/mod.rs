@@ -1,20 +1,26 @@
-﻿mod math_functions;
-mod compare_functions;
-mod negate_function;
-mod vm_command_functions;
+﻿pub mod math_functions;
+pub mod compare_functions;
+pub mod negate_function;
+pub mod vm_command_functions;
+mod file_functions;
+mod time_functions;
+mod random_functions;
+mod env_functions;
 
 use crate::debug_log;
 use crate::vm::functions::compare_functions::compare;
 use crate::vm::functions::math_functions::{*};
 use crate::vm::functions::negate_function::negate;
 use crate::vm::functions::vm_command_functions::vm_command;
-use crate::vm::opcodes::VMCommand_Cmd;
+use crate::vm::replay::run_nondeterministic;
 use crate::vm::{winframework, VM};
 
-pub fn get_functions() -> [fn(&mut VM); 37]
-{
-    let functions =
-    [
+// Return address pushed by host when it calls guest function directly. Returning to it stops execution.
+pub const HOST_RETURN_ADDRESS: i32 = -1;
+
+// Handlers indexed by opcode
+static FUNCTIONS: [fn(&mut VM); 37] =
+[
         do_nothing,
         allocate_stack,
         allocate_heap,
@@ -52,8 +58,11 @@ pub fn get_functions() -> [fn(&mut VM); 37]
         cast,
         section,
         vm_command
-    ];
-    functions
+];
+
+pub fn get_functions() -> &'static [fn(&mut VM); 37]
+{
+    &FUNCTIONS
 }
 
 fn do_nothing(_vm: &mut VM)
@@ -96,7 +105,7 @@ fn allocate_heap(vm: &mut VM)
         let bytes_to_allocate = vm.byte_code.next_int();
 
         let pointer = vm.memory.allocate_heap(bytes_to_allocate);
-        vm.memory.write_int(storage_address, pointer as i32)
+        vm.memory.write_int(storage_address, pointer)
     }
 }
 fn deallocate_stack(vm: &mut VM)
@@ -127,12 +136,17 @@ fn call(vm: &mut VM)
     }
     else
     {  
-        winframework::call(vm, function_info);
+        run_nondeterministic(vm, |vm| winframework::call(vm, &vm.module.table.functions[inmodule_function_index]));
     }
 }
 fn _return(vm: &mut VM)
 {
     let call_op_code_pointer = vm.memory.pop_int();
+    if call_op_code_pointer == HOST_RETURN_ADDRESS
+    {
+        vm.byte_code.current = vm.byte_code.bytes.len();
+        return;
+    }
     vm.byte_code.current = (call_op_code_pointer + 1 + 4) as usize; // + 1 (OpCode.Call) + int (pointer to label)
 }
 fn jump(vm: &mut VM)
@@ -156,7 +170,7 @@ fn jump_if_false(vm: &mut VM)
         }
     }
 
-    if is_true == false
+    if !is_true
     {
         vm.byte_code.current = jump_address as usize;
     }
@@ -277,20 +291,18 @@ fn ptr_shift(vm: &mut VM)
     let mode = vm.byte_code.next();
     let pointer_address = vm.next_address();
 
-    let shift_value;
-
-    if mode == 0
+    let shift_value = if mode == 0
     {
-        shift_value = vm.byte_code.next_int();
+        vm.byte_code.next_int()
     }
     else
     {
         let shift_address = vm.next_address();
         let additional_shift = vm.byte_code.next_int();
-        let size_in_bytes = vm.byte_code.next();
+        let _size_in_bytes = vm.byte_code.next();
 
-        shift_value = vm.memory.read_int(shift_address) + additional_shift;
-    }
+        vm.memory.read_int(shift_address) + additional_shift
+    };
 
     let mut pointer_value = vm.memory.read_int(pointer_address);
     pointer_value += shift_value;
@@ -305,6 +317,11 @@ fn field_access(vm: &mut VM)
     let is_getter = vm.byte_code.next();
     let result_address = vm.next_address();
 
+    field_access_at(vm, base_offset, field_offset, field_value_size, is_getter, result_address);
+}
+
+pub fn field_access_at(vm: &mut VM, base_offset: i32, field_offset: i32, field_value_size: u8, is_getter: u8, result_address: i32)
+{
     let address_in_stack = vm.memory.to_abs(base_offset);
     let address_in_heap = vm.memory.read_int(address_in_stack);
 
@@ -326,15 +343,15 @@ fn field_access(vm: &mut VM)
     }
 }
 
-fn allocate_rsp_saver(vm: &mut VM) {
+fn allocate_rsp_saver(_vm: &mut VM) {
     panic!("Legacy method")
 }
 
-fn restore_rsp_saver(vm: &mut VM) {
+fn restore_rsp_saver(_vm: &mut VM) {
     panic!("Legacy method")
 }
 
-fn deallocate_rsp_saver(vm: &mut VM) {
+fn deallocate_rsp_saver(_vm: &mut VM) {
     panic!("Legacy method")
 }
 
@@ -345,19 +362,15 @@ fn cast(vm: &mut VM)
     let result_address = vm.next_address();
     let result_size = vm.byte_code.next();
 
-    let variable_value = vm.memory.read(variable_address, variable_size as i32).to_vec();
+    cast_at(vm, variable_address, variable_size, result_address, result_size);
+}
 
-    for i in 0..result_size as i32
-    {
-        if i < variable_value.len() as i32
-        {
-            vm.memory.write_byte(result_address + i, variable_value[i as usize]);
-        }
-        else
-        {
-            vm.memory.write_byte(result_address + i, 0);
-        }
-    }
+pub fn cast_at(vm: &mut VM, variable_address: i32, variable_size: u8, result_address: i32, result_size: u8)
+{
+    // Value is truncated or extended with zeros
+    let copied = variable_size.min(result_size) as i32;
+    vm.memory.copy(variable_address, result_address, copied);
+    vm.memory.fill(result_address + copied, result_size as i32 - copied, 0);
 }
 
 fn section(vm: &mut VM) {
@@ -370,14 +383,9 @@ fn section(vm: &mut VM) {
         let data_section_size = vm.byte_code.next_int();
 
         let data = vm.byte_code.next_range(data_section_size as usize);
-        vm.memory.write_slice(0, data);
-        
-        vm.memory.data_section_size = data_section_size;
-        vm.memory.stack_pointer += data_section_size;
-        vm.memory.heap_pointer += data_section_size;
-        vm.memory.base_pointer += data_section_size;
+        vm.memory.place_data_section(data);
     }
 
-    let next_section_opcode = vm.byte_code.next();
-    let next_mode = vm.byte_code.next();
+    let _next_section_opcode = vm.byte_code.next();
+    let _next_mode = vm.byte_code.next();
 }
\ No newline at end of file
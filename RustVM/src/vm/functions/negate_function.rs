@@ -1,12 +1,13 @@
-﻿use crate::vm::vm::VM;
+﻿use crate::vm::memory::Memory;
+use crate::vm::vm::VM;
 use paste::paste;
 
 macro_rules! negate_sized {
      ($t:ty) => {
          paste! {
-            fn [<negate_ $t>](a_value: &[u8]) -> Vec<u8> {
-                let a = $t::from_ne_bytes(a_value.try_into().unwrap());
-                (-a).to_ne_bytes().to_vec()
+            fn [<negate_ $t>](memory: &mut Memory, a_address: i32, result_address: i32) {
+                let a = $t::from_ne_bytes(memory.read_array(a_address));
+                memory.write_array(result_address, (-a).to_ne_bytes());
             }
         }
      };
@@ -23,16 +24,19 @@ pub fn negate(vm: &mut VM)
     let result_address = vm.next_address();
     let size_in_bytes = vm.byte_code.next();
 
-    let a_value = vm.memory.read(a_address, size_in_bytes as i32);
+    negate_at(vm, a_address, result_address, size_in_bytes);
+}
+
+pub fn negate_at(vm: &mut VM, a_address: i32, result_address: i32, size_in_bytes: u8)
+{
+    let memory = &mut vm.memory;
 
-    let result = match size_in_bytes
+    match size_in_bytes
     {
-        1 => negate_i8(a_value),
-        2 => negate_i16(a_value),
-        4 => negate_i32(a_value),
-        8 => negate_i64(a_value),
+        1 => negate_i8(memory, a_address, result_address),
+        2 => negate_i16(memory, a_address, result_address),
+        4 => negate_i32(memory, a_address, result_address),
+        8 => negate_i64(memory, a_address, result_address),
         _ => panic!("Not supported number size ({size_in_bytes} bytes)")
-    };
-
-    vm.memory.write_vec(result_address, result);
+    }
 }
\ No newline at end of file
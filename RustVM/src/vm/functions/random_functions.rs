@@ -0,0 +1,38 @@
+﻿use crate::vm::functions::vm_command_functions::{read_argument_int, write_argument_int, VMCmdArgument};
+use crate::vm::vm::VM;
+
+// RandomInt(result, min, max) writes a value in [min, max)
+pub fn vm_random_int(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let min = read_argument_int(vm, &arguments[1]);
+    let max = read_argument_int(vm, &arguments[2]);
+
+    let value = vm.random.next_range(min, max);
+    write_argument_int(vm, &arguments[0], value);
+}
+
+// RandomBytes(result, buffer, count) fills guest buffer with random bytes, result is count or -1 if buffer is out of memory
+pub fn vm_random_bytes(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let buffer_address = read_argument_int(vm, &arguments[1]) as i32;
+    let count = read_argument_int(vm, &arguments[2]) as i32;
+
+    let mut result = -1;
+
+    if vm.memory.is_valid_range(buffer_address, count)
+    {
+        let mut buffer = vec![0; count as usize];
+        vm.random.fill(&mut buffer);
+        vm.memory.write_slice(buffer_address, &buffer);
+        result = count as i64;
+    }
+
+    write_argument_int(vm, &arguments[0], result);
+}
+
+// RandomSeed(seed)
+pub fn vm_random_seed(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let seed = read_argument_int(vm, &arguments[0]);
+    vm.random.reseed(seed as u64);
+}
\ No newline at end of file
@@ -0,0 +1,74 @@
+﻿use crate::vm::functions::vm_command_functions::{read_argument_int, write_argument_int, VMCmdArgument};
+use crate::vm::vm::VM;
+
+// Time commands write their result into the first argument. All of them read time from vm.clock.
+
+pub fn vm_time_now(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let unix_time_ms = vm.clock.unix_time_ms();
+    write_argument_int(vm, &arguments[0], unix_time_ms);
+}
+
+pub fn vm_time_monotonic_ms(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let monotonic_ms = (vm.clock.monotonic_ns() / 1_000_000) as i64;
+    write_argument_int(vm, &arguments[0], monotonic_ms);
+}
+
+pub fn vm_time_monotonic_ns(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let monotonic_ns = vm.clock.monotonic_ns() as i64;
+    write_argument_int(vm, &arguments[0], monotonic_ns);
+}
+
+// Milliseconds passed since the value previously returned by TimeMonotonicMs
+pub fn vm_time_elapsed(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    let since_ms = read_argument_int(vm, &arguments[1]);
+    let monotonic_ms = (vm.clock.monotonic_ns() / 1_000_000) as i64;
+
+    write_argument_int(vm, &arguments[0], monotonic_ms - since_ms);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::loop_module;
+    use crate::vm::clock::VirtualClock;
+    use crate::vm::functions::vm_command_functions::run_vm_command;
+    use crate::vm::opcodes::VMCommand_Cmd;
+
+    const RESULT: VMCmdArgument = VMCmdArgument { rbp: 0, size_in_bytes: 8, type_index: 4 };
+    const SINCE: VMCmdArgument = VMCmdArgument { rbp: 8, size_in_bytes: 8, type_index: 4 };
+    const DURATION: VMCmdArgument = VMCmdArgument { rbp: 16, size_in_bytes: 4, type_index: 3 };
+
+    fn read_long(vm: &VM, address: i32) -> i64
+    {
+        i64::from_ne_bytes(vm.memory.read_array(address))
+    }
+
+    #[test]
+    fn virtual_time_moves_only_by_sleep()
+    {
+        let mut vm = VM::new(loop_module(0));
+        vm.clock = Box::from(VirtualClock::new(1_000_000));
+
+        vm_time_monotonic_ms(&mut vm, &[SINCE]);
+        vm_time_now(&mut vm, &[RESULT]);
+        assert_eq!(read_long(&vm, 0), 1_000_000);
+
+        vm.memory.write_int(16, 1500);
+        run_vm_command(&mut vm, VMCommand_Cmd::Sleep as u8, &[DURATION]);
+        vm_time_elapsed(&mut vm, &[RESULT, SINCE]);
+        assert_eq!(read_long(&vm, 0), 1500);
+        vm_time_monotonic_ns(&mut vm, &[RESULT]);
+        assert_eq!(read_long(&vm, 0), 1_500_000_000);
+
+        // Negative duration doesn't move time
+        vm.memory.write_int(16, -5);
+        run_vm_command(&mut vm, VMCommand_Cmd::Sleep as u8, &[DURATION]);
+        vm_time_now(&mut vm, &[RESULT]);
+        assert_eq!(read_long(&vm, 0), 1_001_500);
+    }
+}
\ No newline at end of file
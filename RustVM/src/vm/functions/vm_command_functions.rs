@@ -1,14 +1,16 @@
-﻿use std::fmt::Arguments;
-use std::thread;
+﻿use std::io::Write;
 use std::time::Duration;
+use crate::vm::functions::file_functions::*;
+use crate::vm::functions::time_functions::*;
+use crate::vm::functions::random_functions::*;
+use crate::vm::functions::env_functions::*;
 use crate::vm::opcodes::VMCommand_Cmd;
+use crate::vm::replay::run_nondeterministic;
 use crate::vm::vm::VM;
-use crate::vm::winframework::WINFRAMEWORKDATA;
 
 pub fn vm_command(vm: &mut VM)
 {
     let cmd_byte = vm.byte_code.next();
-    let cmd = VMCommand_Cmd::try_from(cmd_byte).expect(format!("Invalid VM command = {cmd_byte}").as_str());
 
     let mut arguments = Vec::new();
     let arguments_count = vm.byte_code.next_int();
@@ -18,71 +20,202 @@ pub fn vm_command(vm: &mut VM)
         let argument = VMCmdArgument
         {
             rbp: vm.byte_code.next_int(),
-            size_in_bytes: vm.byte_code.next() as u8,
-            type_index: vm.byte_code.next() as u8
+            size_in_bytes: vm.byte_code.next(),
+            type_index: vm.byte_code.next()
         };
         arguments.push(argument);
     }
 
+    run_vm_command(vm, cmd_byte, &arguments);
+}
+
+pub fn run_vm_command(vm: &mut VM, cmd_byte: u8, arguments: &[VMCmdArgument])
+{
+    let cmd = VMCommand_Cmd::try_from(cmd_byte).unwrap_or_else(|_| panic!("Invalid VM command = {cmd_byte}"));
+
+    // Results of these depend on host, they are recorded and replayed
+    match cmd {
+        VMCommand_Cmd::Print | VMCommand_Cmd::RandomSeed | VMCommand_Cmd::GetArgs => run_command(vm, cmd, cmd_byte, arguments),
+        _ => run_nondeterministic(vm, |vm| run_command(vm, cmd, cmd_byte, arguments))
+    }
+}
+
+fn run_command(vm: &mut VM, cmd: VMCommand_Cmd, cmd_byte: u8, arguments: &[VMCmdArgument])
+{
     match cmd {
         VMCommand_Cmd::Print => vm_print(vm, arguments),
         VMCommand_Cmd::Sleep => vm_sleep(vm, arguments),
+        VMCommand_Cmd::FileOpen => vm_file_open(vm, arguments),
+        VMCommand_Cmd::FileRead => vm_file_read(vm, arguments),
+        VMCommand_Cmd::FileWrite => vm_file_write(vm, arguments),
+        VMCommand_Cmd::FileClose => vm_file_close(vm, arguments),
+        VMCommand_Cmd::FileSeek => vm_file_seek(vm, arguments),
+        VMCommand_Cmd::FileExists => vm_file_exists(vm, arguments),
+        VMCommand_Cmd::FileDelete => vm_file_delete(vm, arguments),
+        VMCommand_Cmd::TimeNow => vm_time_now(vm, arguments),
+        VMCommand_Cmd::TimeMonotonicMs => vm_time_monotonic_ms(vm, arguments),
+        VMCommand_Cmd::TimeMonotonicNs => vm_time_monotonic_ns(vm, arguments),
+        VMCommand_Cmd::TimeElapsed => vm_time_elapsed(vm, arguments),
+        VMCommand_Cmd::RandomInt => vm_random_int(vm, arguments),
+        VMCommand_Cmd::RandomBytes => vm_random_bytes(vm, arguments),
+        VMCommand_Cmd::RandomSeed => vm_random_seed(vm, arguments),
+        VMCommand_Cmd::GetArgs => vm_get_args(vm, arguments),
+        VMCommand_Cmd::GetEnv => vm_get_env(vm, arguments),
         _ => panic!("Invalid VM command = {cmd_byte}")
     }
 }
 
-fn vm_print(vm: &VM, arguments: Vec<VMCmdArgument>)
+fn vm_print(vm: &mut VM, arguments: &[VMCmdArgument])
 {
+    let mut line = String::new();
+
     for arg in arguments
     {
         let address = vm.memory.to_abs(arg.rbp);
         let value = vm.memory.read(address, arg.size_in_bytes as i32);
 
-        match arg.type_index {
-            0 => print!("{}", value[0] > 0),
-            1 => print!("{}", value[0]),
-            2 => print!("{}", i16::from_ne_bytes(value.try_into().unwrap())),
-            3 => print!("{}", i32::from_ne_bytes(value.try_into().unwrap())),
-            4 => print!("{}", i64::from_ne_bytes(value.try_into().unwrap())),
+        let text = match arg.type_index {
+            0 => format!("{}", value[0] > 0),
+            1 => format!("{}", value[0]),
+            2 => format!("{}", i16::from_ne_bytes(value.try_into().unwrap())),
+            3 => format!("{}", i32::from_ne_bytes(value.try_into().unwrap())),
+            4 => format!("{}", i64::from_ne_bytes(value.try_into().unwrap())),
             5 => {
                 let ptr_address = i32::from_ne_bytes(value.try_into().unwrap());
-                print!("<0x{:X}>", ptr_address);
+                format!("<0x{:X}>", ptr_address)
             },
-            6 => {
-                let ptr_address = i32::from_ne_bytes(value.try_into().unwrap());
+            6 => read_argument_string(vm, arg).unwrap_or_else(|| String::from("<invalid string>")),
+            _ => panic!("Failed to print argument with type_index = {}", arg.type_index)
+        };
+        line.push_str(&text);
+    }
 
-                let str_len = vm.memory.read_int(ptr_address);
-                let str_value = vm.memory.read(ptr_address + 4, str_len);
+    writeln!(vm.output, "{line}").unwrap();
+}
 
-                print!("{:}", String::from_utf8(Vec::from(str_value)).unwrap());
-            }
-            _ => panic!("Failed to print argument with type_index = {}", arg.type_index)
+// Sleep which can be cancelled wakes up this often to check it
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+fn vm_sleep(vm: &mut VM, arguments: &[VMCmdArgument])
+{
+    // Negative duration doesn't sleep
+    let mut remaining = Duration::from_millis(read_argument_int(vm, &arguments[0]).max(0) as u64);
+    if !vm.can_be_cancelled()
+    {
+        vm.clock.sleep(remaining);
+        return
+    }
+
+    while !remaining.is_zero()
+    {
+        if vm.check_cancellation().is_some()
+        {
+            // The next instruction checks it again and stops
+            vm.cancellation_countdown = 1;
+            return
         }
+
+        let slice = remaining.min(SLEEP_SLICE);
+        vm.clock.sleep(slice);
+        remaining -= slice;
     }
+}
 
-    println!();
+// Reads integer-like argument (byte, short, int, long or pointer) widened to i64
+pub(super) fn read_argument_int(vm: &VM, argument: &VMCmdArgument) -> i64
+{
+    let address = vm.memory.to_abs(argument.rbp);
+    let value = vm.memory.read(address, argument.size_in_bytes as i32);
+
+    match argument.type_index {
+        0 | 1 => value[0] as i64,
+        2 => i16::from_ne_bytes(value.try_into().unwrap()) as i64,
+        3 | 5 => i32::from_ne_bytes(value.try_into().unwrap()) as i64,
+        4 => i64::from_ne_bytes(value.try_into().unwrap()),
+        _ => panic!("Failed to read argument as integer due to invalid type_index = {}", argument.type_index)
+    }
 }
 
-fn vm_sleep(vm: &VM, arguments: Vec<VMCmdArgument>)
+// Returns None if string pointer or length go out of memory or bytes are not valid utf8
+pub(super) fn read_argument_string(vm: &VM, argument: &VMCmdArgument) -> Option<String>
 {
-    let duration_argument = &arguments[0];
-    let address = vm.memory.to_abs(duration_argument.rbp);
-    let value = vm.memory.read(address, duration_argument.size_in_bytes as i32);
-
-    let duration = match duration_argument.type_index {
-        1 => value[0] as u64,
-        2 => i16::from_ne_bytes(value.try_into().unwrap()) as u64,
-        3 => i32::from_ne_bytes(value.try_into().unwrap()) as u64,
-        4 => i64::from_ne_bytes(value.try_into().unwrap()) as u64,
-        _ => panic!("Failed to sleep due to invalid argument type_index = {}", duration_argument.type_index)
+    if argument.type_index != 6
+    {
+        panic!("Failed to read argument as string due to invalid type_index = {}", argument.type_index)
+    }
+
+    let address = vm.memory.to_abs(argument.rbp);
+    let ptr_address = vm.memory.read_int(address);
+    if !vm.memory.is_valid_range(ptr_address, 4)
+    {
+        return None
+    }
+
+    let str_len = vm.memory.read_int(ptr_address);
+    if !vm.memory.is_valid_range(ptr_address + 4, str_len)
+    {
+        return None
+    }
+    let str_value = vm.memory.read(ptr_address + 4, str_len);
+
+    String::from_utf8(Vec::from(str_value)).ok()
+}
+
+// Writes value into argument's memory, truncated to argument's size (used for command results)
+pub(super) fn write_argument_int(vm: &mut VM, argument: &VMCmdArgument, value: i64)
+{
+    let address = vm.memory.to_abs(argument.rbp);
+    let size = argument.size_in_bytes as usize;
+
+    vm.memory.write_slice(address, &value.to_ne_bytes()[..size]);
+}
+
+// Checked versions of the above for commands which report malformed arguments to guest.
+// They return None (or write nothing) if argument is missing, has unexpected type or size, or is out of memory.
+pub(super) fn get_argument_int(vm: &VM, arguments: &[VMCmdArgument], index: usize) -> Option<i64>
+{
+    let argument = arguments.get(index)?;
+    let size = argument.size_in_bytes as i32;
+    let is_valid_size = match argument.type_index
+    {
+        0 | 1 => size >= 1,
+        2 => size == 2,
+        3 | 5 => size == 4,
+        4 => size == 8,
+        _ => false
     };
 
-    thread::sleep(Duration::from_millis(duration));
+    match is_valid_size && vm.memory.is_valid_range(vm.memory.to_abs(argument.rbp), size)
+    {
+        true => Some(read_argument_int(vm, argument)),
+        false => None
+    }
+}
+
+pub(super) fn get_argument_string(vm: &VM, arguments: &[VMCmdArgument], index: usize) -> Option<String>
+{
+    let argument = arguments.get(index)?;
+    match argument.type_index == 6 && vm.memory.is_valid_range(vm.memory.to_abs(argument.rbp), 4)
+    {
+        true => read_argument_string(vm, argument),
+        false => None
+    }
+}
+
+pub(super) fn set_argument_int(vm: &mut VM, arguments: &[VMCmdArgument], index: usize, value: i64)
+{
+    if let Some(argument) = arguments.get(index)
+        && argument.size_in_bytes <= 8
+        && vm.memory.is_valid_range(vm.memory.to_abs(argument.rbp), argument.size_in_bytes as i32)
+    {
+        write_argument_int(vm, argument, value);
+    }
 }
 
-struct VMCmdArgument
+#[derive(Clone, Copy)]
+pub struct VMCmdArgument
 {
-    rbp: i32,
-    size_in_bytes: u8,
-    type_index: u8
+    pub rbp: i32,
+    pub size_in_bytes: u8,
+    pub type_index: u8
 }
\ No newline at end of file
@@ -0,0 +1,356 @@
+﻿use crate::vm::error::{Result, VMError};
+use crate::vm::fuel::RunStatus;
+use crate::vm::functions::HOST_RETURN_ADDRESS;
+use crate::vm::memory::Memory;
+use crate::vm::value::Value;
+use crate::vm::vm::VM;
+
+// Stack frame pushed by host: [returns][arguments][HOST_RETURN_ADDRESS]
+pub struct CallFrame
+{
+    pub function_index: usize,
+    pub stack_pointer: i32,
+    pub base_pointer: i32,
+    pub heap_pointer: i32,
+    pub returns_address: i32,
+    pub sentinel_address: i32,
+}
+
+// Call stopped by fuel with its frame still on stack
+pub struct PendingCall
+{
+    pub frame: CallFrame,
+    pub prev_current: usize,
+}
+
+// Argument checked against its type. Strings are placed on heap once it's known that everything fits.
+enum ArgumentValue<'a>
+{
+    Bytes(Vec<u8>),
+    Str(&'a str),
+}
+
+// Host API, the command line runner uses only part of it
+#[allow(dead_code)]
+impl VM
+{
+    // Calls guest function by "Type.Function" or "Function" name and returns its result.
+    // Several returns are packed into Value::Tuple, no returns give Value::Void.
+    pub fn call(&mut self, function_name: &str, args: &[Value]) -> Result<Value>
+    {
+        let function_index = self.module.find_function(function_name).ok_or_else(|| VMError::FunctionNotFound(String::from(function_name)))?;
+        self.call_index(function_index, args)
+    }
+
+    // When fuel is over the call stays on stack and is resumed by finish_call after more fuel is added
+    pub fn call_index(&mut self, function_index: usize, args: &[Value]) -> Result<Value>
+    {
+        if let Some(pending) = &self.pending_call
+        {
+            return Err(VMError::CallPending(self.module.table.functions[pending.frame.function_index].name.clone()))
+        }
+
+        let prev_current = self.byte_code.current;
+        let frame = self.push_call_frame(function_index, args)?;
+
+        let status = self.run();
+        self.complete_call(PendingCall { frame, prev_current }, status)
+    }
+
+    // Continues call which ran out of fuel and returns its result
+    pub fn finish_call(&mut self) -> Result<Value>
+    {
+        let pending = self.pending_call.take().ok_or(VMError::NoPendingCall)?;
+
+        let status = self.run();
+        self.complete_call(pending, status)
+    }
+
+    // Removes frame of call which ran out of fuel without finishing it
+    pub fn abandon_call(&mut self)
+    {
+        if let Some(pending) = self.pending_call.take()
+        {
+            self.pop_call_frame(&pending.frame);
+            self.byte_code.current = pending.prev_current;
+        }
+    }
+
+    fn complete_call(&mut self, call: PendingCall, status: RunStatus) -> Result<Value>
+    {
+        let function_name = self.module.table.functions[call.frame.function_index].name.clone();
+        if matches!(status, RunStatus::OutOfFuel)
+        {
+            self.pending_call = Some(call);
+            return Err(VMError::OutOfFuel(function_name))
+        }
+
+        // Other stops can't be resumed, frame is removed
+        let frame = call.frame;
+        let result = match (status, self.has_returned(&frame))
+        {
+            (RunStatus::Cancelled(stack_trace), _) => Err(VMError::Cancelled { function: function_name.clone(), stack_trace: stack_trace.to_string() }),
+            (RunStatus::TimedOut(stack_trace), _) => Err(VMError::TimedOut { function: function_name.clone(), stack_trace: stack_trace.to_string() }),
+            (_, true) => Ok(self.read_returns(&frame)),
+            (_, false) => Err(VMError::NotReturned(function_name))
+        };
+
+        self.pop_call_frame(&frame);
+        self.byte_code.current = call.prev_current;
+
+        result
+    }
+
+    // Pushes returns, arguments and sentinel return address, then jumps to function's first opcode.
+    // Nothing is allocated if arguments don't match or don't fit into memory.
+    pub fn push_call_frame(&mut self, function_index: usize, args: &[Value]) -> Result<CallFrame>
+    {
+        let function_info = &self.module.table.functions[function_index];
+
+        if function_info.pointed_module != 0
+        {
+            return Err(VMError::NotManagedFunction(function_info.name.clone()))
+        }
+        if function_info.arguments.len() != args.len()
+        {
+            return Err(VMError::ArgumentsCount {
+                function: function_info.name.clone(),
+                expected: function_info.arguments.len(),
+                given: args.len()
+            })
+        }
+
+        let mut values = Vec::with_capacity(args.len());
+        let mut frame_size = 4;
+        let mut strings_size = 0;
+
+        for (argument, value) in function_info.arguments.iter().zip(args)
+        {
+            let size = self.module.type_size(argument.type_index);
+            let is_string = self.module.type_name(argument.type_index) == "string";
+
+            let value = match (value, is_string)
+            {
+                (Value::Str(s), true) => ArgumentValue::Str(s),
+                (Value::Bytes(bytes), false) if bytes.len() == size as usize => ArgumentValue::Bytes(bytes.clone()),
+                (value, false) if value.as_i64().is_some() && size <= 8 => ArgumentValue::Bytes(value.as_i64().unwrap().to_ne_bytes()[..size as usize].to_vec()),
+                _ => {
+                    return Err(VMError::ArgumentType {
+                        function: function_info.name.clone(),
+                        argument: argument.name.clone(),
+                        expected: String::from(self.module.type_name(argument.type_index))
+                    })
+                }
+            };
+
+            if let ArgumentValue::Str(s) = &value
+            {
+                strings_size += 4 + s.len() as i64;
+            }
+            frame_size += size as i64;
+            values.push((size, value));
+        }
+
+        let returns_size: i32 = function_info.returns.iter().map(|r| self.module.type_size(*r)).sum();
+        frame_size += returns_size as i64;
+
+        // The same limit as allocate_stack has
+        if self.memory.stack_pointer as i64 + frame_size >= Memory::STACK_SIZE as i64 || strings_size > self.memory.heap_left() as i64
+        {
+            return Err(VMError::OutOfMemory(function_info.name.clone()))
+        }
+
+        let stack_pointer = self.memory.stack_pointer;
+        let base_pointer = self.memory.base_pointer;
+        let heap_pointer = self.memory.heap_pointer;
+
+        let returns_address = self.memory.allocate_stack(returns_size);
+        self.memory.write_vec(returns_address, vec![0; returns_size as usize]);
+
+        for (size, value) in values
+        {
+            let bytes = match value
+            {
+                ArgumentValue::Bytes(bytes) => bytes,
+                ArgumentValue::Str(s) => self.memory.allocate_heap_string(s).to_ne_bytes().to_vec()
+            };
+
+            let address = self.memory.allocate_stack(size);
+            self.memory.write_vec(address, bytes);
+        }
+
+        self.memory.push_int(HOST_RETURN_ADDRESS);
+        self.byte_code.current = function_info.pointed_opcode as usize;
+
+        Ok(CallFrame {
+            function_index,
+            stack_pointer,
+            base_pointer,
+            heap_pointer,
+            returns_address,
+            sentinel_address: self.memory.stack_pointer - 4,
+        })
+    }
+
+    // Function has returned to host if sentinel return address was popped by Return
+    pub fn has_returned(&self, frame: &CallFrame) -> bool
+    {
+        self.byte_code.current == self.byte_code.bytes.len() && self.memory.stack_pointer == frame.sentinel_address
+    }
+
+    // Heap is freed too: argument strings and whatever function has allocated (returns are already read by then)
+    pub fn pop_call_frame(&mut self, frame: &CallFrame)
+    {
+        self.memory.stack_pointer = frame.stack_pointer;
+        self.memory.base_pointer = frame.base_pointer;
+        self.memory.heap_pointer = frame.heap_pointer;
+    }
+
+    pub fn read_returns(&self, frame: &CallFrame) -> Value
+    {
+        let function_info = &self.module.table.functions[frame.function_index];
+
+        let mut values = Vec::with_capacity(function_info.returns.len());
+        let mut address = frame.returns_address;
+
+        for type_index in &function_info.returns
+        {
+            let size = self.module.type_size(*type_index);
+            values.push(self.read_value(address, *type_index));
+            address += size;
+        }
+
+        match values.len()
+        {
+            0 => Value::Void,
+            1 => values.pop().unwrap(),
+            _ => Value::Tuple(values)
+        }
+    }
+
+    pub fn read_value(&self, address: i32, type_index: u32) -> Value
+    {
+        let size = self.module.type_size(type_index);
+        let bytes = self.memory.read(address, size);
+
+        match self.module.type_name(type_index)
+        {
+            "bool" => Value::Bool(bytes[0] > 0),
+            "byte" => Value::Byte(bytes[0]),
+            "short" => Value::Short(i16::from_ne_bytes(bytes.try_into().unwrap())),
+            "int" => Value::Int(i32::from_ne_bytes(bytes.try_into().unwrap())),
+            "long" => Value::Long(i64::from_ne_bytes(bytes.try_into().unwrap())),
+            "string" => {
+                // Uninitialized variable holds whatever was on stack, it's shown as pointer
+                let pointer = self.memory.read_int(address);
+                if pointer == 0 || !self.memory.is_valid_range(pointer, 4)
+                {
+                    return Value::Ptr(pointer)
+                }
+
+                let length = self.memory.read_int(pointer);
+                if !self.memory.is_valid_range(pointer + 4, length)
+                {
+                    return Value::Ptr(pointer)
+                }
+                Value::Str(String::from_utf8_lossy(self.memory.read(pointer + 4, length)).into_owned())
+            },
+            _ if size == 4 => Value::Ptr(self.memory.read_int(address)),
+            _ => Value::Bytes(bytes.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::compiled_module::{CompiledModule, TypeInfo_Blit};
+    use crate::vm::interrupt;
+
+    // Mix(a, b: string) returns a + (pointer & 255)
+    fn string_module() -> CompiledModule
+    {
+        let mut module = call_module(0);
+        module.table.types.push(TypeInfo_Blit { name: String::from("string"), is_value_type: false, fields: Vec::new(), functions: Vec::new() });
+        module.table.functions[0].arguments[1].type_index = 2;
+        module
+    }
+
+    #[test]
+    fn call_resumes_after_fuel_is_added()
+    {
+        let mut vm = VM::new(call_module(0));
+        let stack_pointer = vm.memory.stack_pointer;
+        vm.set_fuel(Some(1));
+
+        let result = vm.call("Program.Mix", &[Value::Int(5), Value::Int(300)]);
+        assert!(matches!(result, Err(VMError::OutOfFuel(_))));
+        assert!(matches!(vm.call("Program.Mix", &[Value::Int(1), Value::Int(1)]), Err(VMError::CallPending(_))));
+
+        vm.add_fuel(1000);
+        assert!(matches!(vm.finish_call(), Ok(Value::Int(49))));
+        assert_eq!(vm.memory.stack_pointer, stack_pointer);
+        assert!(matches!(vm.finish_call(), Err(VMError::NoPendingCall)));
+    }
+
+    #[test]
+    fn failed_call_allocates_nothing()
+    {
+        let mut vm = VM::new(string_module());
+        let (stack_pointer, heap_pointer) = (vm.memory.stack_pointer, vm.memory.heap_pointer);
+
+        let result = vm.call("Program.Mix", &[Value::Str(String::from("a")), Value::Str(String::from("b"))]);
+        assert!(matches!(result, Err(VMError::ArgumentType { .. })));
+        assert_eq!((vm.memory.stack_pointer, vm.memory.heap_pointer), (stack_pointer, heap_pointer));
+
+        let result = vm.call("Program.Mix", &[Value::Int(5), Value::Str(String::from("x").repeat(Memory::STACK_SIZE as usize))]);
+        assert!(matches!(result, Err(VMError::OutOfMemory(_))));
+        assert_eq!((vm.memory.stack_pointer, vm.memory.heap_pointer), (stack_pointer, heap_pointer));
+
+        vm.memory.stack_pointer = Memory::STACK_SIZE - 8;
+        assert!(matches!(vm.call("Program.Mix", &[Value::Int(5), Value::Str(String::new())]), Err(VMError::OutOfMemory(_))));
+        assert_eq!(vm.memory.stack_pointer, Memory::STACK_SIZE - 8);
+    }
+
+    #[test]
+    fn string_arguments_are_freed_after_call()
+    {
+        let mut vm = VM::new(string_module());
+        let heap_pointer = vm.memory.heap_pointer;
+
+        for _ in 0..100
+        {
+            let result = vm.call("Program.Mix", &[Value::Int(5), Value::Str(String::from("argument"))]).unwrap();
+            assert!(matches!(result, Value::Int(value) if value == 5 + (heap_pointer & 255)));
+        }
+        assert_eq!(vm.memory.heap_pointer, heap_pointer);
+    }
+
+    #[test]
+    fn invalid_string_is_read_as_pointer()
+    {
+        let mut vm = VM::new(string_module());
+
+        vm.memory.write_int(0, 100_000);
+        assert!(matches!(vm.read_value(0, 2), Value::Ptr(100_000)));
+
+        vm.memory.write_int(0, 8);
+        vm.memory.write_int(8, -1);
+        assert!(matches!(vm.read_value(0, 2), Value::Ptr(8)));
+
+        vm.memory.write_int(8, 2);
+        vm.memory.write_slice(12, b"ok");
+        assert!(matches!(vm.read_value(0, 2), Value::Str(s) if s == "ok"));
+    }
+
+    #[test]
+    fn interrupt_passes_arguments()
+    {
+        let mut vm = VM::new(call_module(0));
+        let mix_start = vm.module.table.functions[0].pointed_opcode as i32;
+
+        assert!(matches!(interrupt(&mut vm, mix_start, &[Value::Int(5), Value::Int(300)]), Value::Int(49)));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,886 @@
+﻿use std::any::Any;
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Type, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use crate::vm::compiled_module::CompiledModule;
+use crate::vm::memory::Memory;
+use crate::vm::predecode::{execute, execute_op, run_binary, DecodedProgram, Dst, Op, Src};
+use crate::vm::vm::VM;
+
+// Compiles hot guest functions from pre-decoded program to native code with Cranelift.
+// Compiled code works with the same Memory as interpreter: stack and base pointers are kept in registers
+// and written back before anything else can see them.
+// Only simple instructions are compiled. The rest (calls, returns, VM commands, pointers arithmetic...)
+// and every instruction which would fail are run by interpreter through jit_run_op, so errors
+// get the same messages and offsets as without JIT. Crash dump history has only interpreted instructions.
+
+pub struct Jit
+{
+    module: JITModule,
+    run_op: FuncId,
+    run_binary: FuncId,
+    // Calls before function is compiled
+    threshold: u32,
+    // By function's first instruction index
+    functions: HashMap<usize, JitFunction>,
+    // Sorted first instruction indexes of all managed functions, compiled code ends at the next one
+    starts: Vec<usize>,
+    // Panic caught while native code was running, rethrown when it returns
+    fault: Option<Box<dyn Any + Send>>,
+    // Offset of the innermost instruction which panicked
+    pub fault_offset: Option<usize>,
+}
+
+enum JitFunction
+{
+    Counting(u32),
+    Compiled(NativeFunction),
+    Unsupported,
+}
+
+// Returns index of instruction to continue interpretation from, or FAULT
+type NativeFunction = unsafe extern "C" fn(*mut JitContext) -> u32;
+
+#[repr(C)]
+struct JitContext
+{
+    vm: *mut VM,
+    program: *const DecodedProgram,
+}
+
+const FAULT: u32 = u32::MAX;
+
+const BYTES: i32 = (offset_of!(VM, memory) + offset_of!(Memory, bytes)) as i32;
+const STACK_POINTER: i32 = (offset_of!(VM, memory) + offset_of!(Memory, stack_pointer)) as i32;
+const BASE_POINTER: i32 = (offset_of!(VM, memory) + offset_of!(Memory, base_pointer)) as i32;
+
+impl VM
+{
+    pub fn enable_jit(&mut self, threshold: u32)
+    {
+        if let Some(program) = &self.program
+        {
+            self.jit = Some(Box::new(Jit::new(program, &self.module, threshold)));
+        }
+    }
+}
+
+// Called by interpreter when managed function is entered, return address is already pushed.
+// Returns index to continue from if function was run natively.
+pub fn enter(vm: &mut VM, program: &DecodedProgram, entry: usize) -> Option<usize>
+{
+    // Recorded writes are needed by watchpoints and tracer, compiled code doesn't report them.
+    // It doesn't count fuel and can't be cancelled either.
+    if vm.memory.record_writes || vm.fuel.is_some() || vm.can_be_cancelled()
+    {
+        return None
+    }
+
+    let jit = vm.jit.as_mut()?;
+    let function = match jit.functions.get(&entry)
+    {
+        Some(JitFunction::Compiled(function)) => *function,
+        Some(JitFunction::Unsupported) => return None,
+        counting => {
+            let calls = match counting
+            {
+                Some(JitFunction::Counting(calls)) => calls + 1,
+                _ => 1
+            };
+            if calls < jit.threshold
+            {
+                jit.functions.insert(entry, JitFunction::Counting(calls));
+                return None
+            }
+
+            match jit.compile(program, entry)
+            {
+                Some(function) => {
+                    jit.functions.insert(entry, JitFunction::Compiled(function));
+                    function
+                },
+                None => {
+                    jit.functions.insert(entry, JitFunction::Unsupported);
+                    return None
+                }
+            }
+        }
+    };
+
+    let mut context = JitContext {
+        vm,
+        program,
+    };
+    let next = unsafe { function(&mut context) };
+
+    if next == FAULT
+    {
+        let payload = vm.jit.as_mut().unwrap().fault.take().unwrap();
+        resume_unwind(payload);
+    }
+
+    Some(next as usize)
+}
+
+// Host calls (VM::call, --entry) start right at function's first instruction without Call,
+// they are counted and compiled the same way
+pub fn enter_host_call(vm: &mut VM, program: &DecodedProgram, index: usize) -> Option<usize>
+{
+    if vm.jit.as_ref()?.starts.binary_search(&index).is_err()
+    {
+        return None
+    }
+
+    enter(vm, program, index)
+}
+
+// Runs instruction with interpreter. Call is run until the callee returns.
+extern "C" fn jit_run_op(context: *mut JitContext, index: u32) -> u32
+{
+    let (vm, program) = unsafe { (&mut *(*context).vm, &*(*context).program) };
+    let mut current = index as usize;
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let stack_pointer = vm.memory.stack_pointer;
+        vm.history.push(program.offsets[current]);
+        let next = execute_op(vm, program, current);
+
+        if vm.memory.stack_pointer != stack_pointer && is_managed_call(vm, &program.ops[current])
+        {
+            current = next;
+            execute(vm, program, &mut current, Some(stack_pointer));
+            return current
+        }
+        next
+    }));
+
+    finish(vm, program, current, result)
+}
+
+// Runs the second half of MovBinary, when the move is already done natively
+extern "C" fn jit_run_binary(context: *mut JitContext, index: u32) -> u32
+{
+    let (vm, program) = unsafe { (&mut *(*context).vm, &*(*context).program) };
+    let current = index as usize;
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        if let Op::MovBinary { op, a, b, result, size, .. } = &program.ops[current]
+        {
+            run_binary(vm, *op, *a, *b, *result, *size);
+        }
+        current + 1
+    }));
+
+    finish(vm, program, current, result)
+}
+
+fn finish(vm: &mut VM, program: &DecodedProgram, current: usize, result: std::thread::Result<usize>) -> u32
+{
+    match result
+    {
+        Ok(next) => next as u32,
+        Err(payload) => {
+            let jit = vm.jit.as_mut().unwrap();
+            jit.fault_offset.get_or_insert(program.offsets[current]);
+            jit.fault = Some(payload);
+            FAULT
+        }
+    }
+}
+
+fn is_managed_call(vm: &VM, op: &Op) -> bool
+{
+    match op
+    {
+        Op::Call { function } => vm.module.table.functions[*function as usize].pointed_module == 0,
+        _ => false
+    }
+}
+
+impl Jit
+{
+    pub fn new(program: &DecodedProgram, module: &CompiledModule, threshold: u32) -> Self
+    {
+        let mut builder = JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names())
+            .unwrap_or_else(|e| panic!("Failed to create JIT: {e}"));
+        builder.symbol("jit_run_op", jit_run_op as *const u8);
+        builder.symbol("jit_run_binary", jit_run_binary as *const u8);
+        let mut jit_module = JITModule::new(builder);
+
+        let mut signature = jit_module.make_signature();
+        signature.params.push(AbiParam::new(jit_module.target_config().pointer_type()));
+        signature.params.push(AbiParam::new(types::I32));
+        signature.returns.push(AbiParam::new(types::I32));
+        let run_op = jit_module.declare_function("jit_run_op", Linkage::Import, &signature).unwrap();
+        let run_binary = jit_module.declare_function("jit_run_binary", Linkage::Import, &signature).unwrap();
+
+        let mut starts: Vec<usize> = module.table.functions.iter()
+            .filter(|function_info| function_info.pointed_module == 0)
+            .filter_map(|function_info| program.index_of(function_info.pointed_opcode as usize))
+            .collect();
+        starts.sort();
+        starts.dedup();
+
+        Self {
+            module: jit_module,
+            run_op,
+            run_binary,
+            threshold,
+            functions: HashMap::new(),
+            starts,
+            fault: None,
+            fault_offset: None,
+        }
+    }
+
+    // Compiles instructions from entry up to the next function. Returns None if Cranelift failed.
+    fn compile(&mut self, program: &DecodedProgram, entry: usize) -> Option<NativeFunction>
+    {
+        let end = self.starts.iter().copied().find(|start| *start > entry).unwrap_or(program.ops.len());
+        if entry >= end
+        {
+            return None
+        }
+
+        let pointer = self.module.target_config().pointer_type();
+        let mut context = self.module.make_context();
+        context.func.signature.params.push(AbiParam::new(pointer));
+        context.func.signature.returns.push(AbiParam::new(types::I32));
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let run_op = self.module.declare_func_in_func(self.run_op, builder.func);
+        let run_binary = self.module.declare_func_in_func(self.run_binary, builder.func);
+
+        let start = builder.create_block();
+        builder.append_block_params_for_function_params(start);
+        builder.switch_to_block(start);
+
+        let context_value = builder.block_params(start)[0];
+        let vm = builder.ins().load(pointer, MemFlags::trusted(), context_value, offset_of!(JitContext, vm) as i32);
+        let bytes = builder.ins().iadd_imm(vm, BYTES as i64);
+
+        let (stack_pointer, base_pointer) = (Variable::from_u32(0), Variable::from_u32(1));
+        builder.declare_var(stack_pointer, types::I32);
+        builder.declare_var(base_pointer, types::I32);
+
+        let blocks = (entry..end).map(|_| builder.create_block()).collect();
+        let mut translator = Translator {
+            builder,
+            program,
+            pointer,
+            context: context_value,
+            vm,
+            bytes,
+            stack_pointer,
+            base_pointer,
+            run_op,
+            run_binary,
+            entry,
+            blocks,
+            exits: Vec::new(),
+            slow: None,
+        };
+
+        translator.reload();
+        translator.goto(entry);
+        for index in entry..end
+        {
+            translator.translate(index);
+        }
+        translator.emit_exits();
+        translator.builder.seal_all_blocks();
+        translator.builder.finalize();
+
+        let id = self.module.declare_anonymous_function(&context.func.signature).ok()?;
+        self.module.define_function(id, &mut context).ok()?;
+        self.module.clear_context(&mut context);
+        self.module.finalize_definitions().ok()?;
+
+        let code = self.module.get_finalized_function(id);
+        Some(unsafe { std::mem::transmute::<*const u8, NativeFunction>(code) })
+    }
+}
+
+struct Translator<'a>
+{
+    builder: FunctionBuilder<'a>,
+    program: &'a DecodedProgram,
+    pointer: Type,
+    context: Value,
+    vm: Value,
+    bytes: Value,
+    stack_pointer: Variable,
+    base_pointer: Variable,
+    run_op: FuncRef,
+    run_binary: FuncRef,
+    entry: usize,
+    // Block of each compiled instruction
+    blocks: Vec<Block>,
+    // Blocks which leave compiled code at given instruction index, filled in the end
+    exits: Vec<(usize, Block)>,
+    // Block running current instruction with interpreter, if it was needed
+    slow: Option<Block>,
+}
+
+enum Flow
+{
+    // Compiled, continues with the next instruction
+    Next,
+    // Compiled together with its jumps
+    Done,
+    // Run by interpreter
+    Interpret,
+}
+
+// Guest memory accesses are checked before, so they can't trap
+fn flags() -> MemFlags
+{
+    MemFlags::new().with_notrap()
+}
+
+fn int_type(size: u8) -> Type
+{
+    match size
+    {
+        1 => types::I8,
+        2 => types::I16,
+        4 => types::I32,
+        _ => types::I64,
+    }
+}
+
+fn is_number(size: u8) -> bool
+{
+    matches!(size, 1 | 2 | 4 | 8)
+}
+
+fn min_value(size: u8) -> i64
+{
+    match size
+    {
+        1 => i8::MIN as i64,
+        2 => i16::MIN as i64,
+        4 => i32::MIN as i64,
+        _ => i64::MIN,
+    }
+}
+
+// Splits size into loads of 8, 4, 2 and 1 bytes: (offset, size)
+fn parts(size: i32) -> Vec<(i32, u8)>
+{
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    while offset < size
+    {
+        let len = [8, 4, 2, 1].into_iter().find(|len| *len <= size - offset).unwrap();
+        parts.push((offset, len as u8));
+        offset += len;
+    }
+    parts
+}
+
+impl Translator<'_>
+{
+    fn translate(&mut self, index: usize)
+    {
+        self.builder.switch_to_block(self.blocks[index - self.entry]);
+        let program = self.program;
+
+        let flow = match &program.ops[index]
+        {
+            Op::Nop => Flow::Next,
+            Op::AllocateStack { value } => {
+                let sp = self.builder.use_var(self.stack_pointer);
+                let ok = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, sp, (Memory::STACK_SIZE - value.len as i32) as i64);
+                self.guard(ok);
+
+                self.store_bytes(sp, program.bytes(*value));
+                self.allocate(sp, value.len as i32);
+                Flow::Next
+            },
+            Op::PushVariable { variable, size } => {
+                let sp = self.builder.use_var(self.stack_pointer);
+                let variable = self.rbp(*variable);
+                let mut ok = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, sp, (Memory::STACK_SIZE - *size as i32) as i64);
+                ok = self.check(ok, variable, *size as i32);
+                self.guard(ok);
+
+                self.copy(variable, sp, *size as i32);
+                self.allocate(sp, *size as i32);
+                Flow::Next
+            },
+            Op::DeallocateStack { size } => {
+                let sp = self.builder.use_var(self.stack_pointer);
+                self.allocate(sp, -*size);
+                Flow::Next
+            },
+            Op::CheckStack { size } => {
+                let sp = self.builder.use_var(self.stack_pointer);
+                let ok = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, sp, (Memory::STACK_SIZE - *size) as i64);
+                self.guard(ok);
+                Flow::Next
+            },
+            Op::Prologue => {
+                let (sp, bp) = (self.builder.use_var(self.stack_pointer), self.builder.use_var(self.base_pointer));
+                let ok = self.in_bounds(sp, 4);
+                self.guard(ok);
+
+                let address = self.native(sp);
+                self.builder.ins().store(flags(), bp, address, 0);
+                let sp = self.builder.ins().iadd_imm(sp, 4);
+                self.builder.def_var(self.stack_pointer, sp);
+                self.builder.def_var(self.base_pointer, sp);
+                Flow::Next
+            },
+            Op::Epilogue => {
+                let bp = self.builder.use_var(self.base_pointer);
+                let sp = self.builder.ins().iadd_imm(bp, -4);
+                let ok = self.in_bounds(sp, 4);
+                self.guard(ok);
+
+                let bp = self.load(sp, 4);
+                self.builder.def_var(self.stack_pointer, sp);
+                self.builder.def_var(self.base_pointer, bp);
+                Flow::Next
+            },
+            Op::Jump { target } => {
+                self.goto(*target as usize);
+                Flow::Done
+            },
+            Op::JumpIfFalse { target, condition, size } if is_number(*size) => {
+                let condition = self.rbp(*condition);
+                let ok = self.in_bounds(condition, *size as i32);
+                self.guard(ok);
+
+                let value = self.load(condition, *size);
+                self.branch(value, index + 1, *target as usize);
+                Flow::Done
+            },
+            Op::Exit => {
+                self.goto(program.ops.len());
+                Flow::Done
+            },
+            Op::Mov { dst, src: source } => {
+                let dst = match dst
+                {
+                    Dst::Rbp(offset) => self.rbp(*offset),
+                    Dst::Deref(offset) => self.deref(*offset),
+                };
+                let (src, size) = match source
+                {
+                    Src::Rbp(offset, size) => (Some(self.rbp(*offset)), *size as i32),
+                    Src::Abs(address, size) => (Some(self.builder.ins().iconst(types::I32, *address as i64)), *size as i32),
+                    Src::Const(value) => (None, value.len as i32),
+                };
+
+                let mut ok = self.in_bounds(dst, size);
+                if let Some(src) = src
+                {
+                    ok = self.check(ok, src, size);
+                }
+                self.guard(ok);
+
+                match (src, source)
+                {
+                    (Some(src), _) => self.copy(src, dst, size),
+                    (None, Src::Const(value)) => self.store_bytes(dst, program.bytes(*value)),
+                    _ => unreachable!()
+                }
+                Flow::Next
+            },
+            Op::Binary { op, a, b, result, size } if is_number(*size) => {
+                let slow = self.slow();
+                self.binary(*op, *a, *b, *result, *size, slow);
+                Flow::Next
+            },
+            Op::Unary { op, value, size } if is_number(*size) => {
+                self.unary(*op, *value, *size);
+                Flow::Next
+            },
+            Op::UnaryJump { op, value, size, target } if is_number(*size) => {
+                self.unary(*op, *value, *size);
+                self.goto(*target as usize);
+                Flow::Done
+            },
+            Op::Compare { a, b, size, result, op } if is_number(*size) && *op <= 5 => {
+                self.compare(*a, *b, *size, *result, *op);
+                Flow::Next
+            },
+            Op::CompareJumpIfFalse { a, b, size, result, op, target } if is_number(*size) && *op <= 5 => {
+                let value = self.compare(*a, *b, *size, *result, *op);
+                self.branch(value, index + 1, *target as usize);
+                Flow::Done
+            },
+            Op::Negate { a, result, size } if is_number(*size) => {
+                let (a, result) = (self.rbp(*a), self.rbp(*result));
+                let mut ok = self.in_bounds(a, *size as i32);
+                ok = self.check(ok, result, *size as i32);
+                self.guard(ok);
+
+                // -MIN overflows
+                let value = self.load(a, *size);
+                let ok = self.builder.ins().icmp_imm(IntCC::NotEqual, value, min_value(*size));
+                self.guard(ok);
+
+                let value = self.builder.ins().ineg(value);
+                self.store(result, value);
+                Flow::Next
+            },
+            Op::ToPtrValueType { variable, result } => {
+                let (variable, result) = (self.rbp(*variable), self.rbp(*result));
+                let ok = self.in_bounds(result, 4);
+                self.guard(ok);
+
+                self.store(result, variable);
+                Flow::Next
+            },
+            Op::PtrGet { pointer, result, size } => {
+                let pointer = self.deref(*pointer);
+                let result = self.rbp(*result);
+                let mut ok = self.in_bounds(pointer, *size as i32);
+                ok = self.check(ok, result, *size as i32);
+                self.guard(ok);
+
+                self.copy(pointer, result, *size as i32);
+                Flow::Next
+            },
+            Op::PtrSet { pointer, value, size } => {
+                let pointer = self.deref(*pointer);
+                let value = self.rbp(*value);
+                let mut ok = self.in_bounds(value, *size as i32);
+                ok = self.check(ok, pointer, *size as i32);
+                self.guard(ok);
+
+                self.copy(value, pointer, *size as i32);
+                Flow::Next
+            },
+            Op::MovBinary { dst, src, mov_size, op, a, b, result, size } if is_number(*size) => {
+                let (dst, src) = (self.rbp(*dst), self.rbp(*src));
+                let mut ok = self.in_bounds(src, *mov_size as i32);
+                ok = self.check(ok, dst, *mov_size as i32);
+                self.guard(ok);
+                self.copy(src, dst, *mov_size as i32);
+
+                // Move is already done, so only the binary part can be run by interpreter
+                let binary_slow = self.builder.create_block();
+                self.binary(*op, *a, *b, *result, *size, binary_slow);
+                self.goto(index + 1);
+
+                self.builder.switch_to_block(binary_slow);
+                self.interpret(self.run_binary, index);
+                Flow::Done
+            },
+            _ => Flow::Interpret
+        };
+
+        match flow
+        {
+            Flow::Next => self.goto(index + 1),
+            Flow::Done => {},
+            Flow::Interpret => self.interpret(self.run_op, index),
+        }
+
+        if let Some(slow) = self.slow.take()
+        {
+            self.builder.switch_to_block(slow);
+            self.interpret(self.run_op, index);
+        }
+    }
+
+    // Runs instruction by interpreter. It continues at the next instruction or its jump target,
+    // anything else (return, exit, error) leaves compiled code.
+    fn interpret(&mut self, function: FuncRef, index: usize)
+    {
+        self.flush();
+        let index_value = self.builder.ins().iconst(types::I32, index as i64);
+        let call = self.builder.ins().call(function, &[self.context, index_value]);
+        let next = self.builder.inst_results(call)[0];
+        self.reload();
+
+        let (next_block, leave) = (self.block(index + 1), self.builder.create_block());
+        let is_next = self.builder.ins().icmp_imm(IntCC::Equal, next, (index + 1) as i64);
+        self.builder.ins().brif(is_next, next_block, &[], leave, &[]);
+        self.builder.switch_to_block(leave);
+
+        if let Some(target) = self.program.ops[index].target()
+        {
+            let (target_block, other) = (self.block(target as usize), self.builder.create_block());
+            let is_target = self.builder.ins().icmp_imm(IntCC::Equal, next, target as i64);
+            self.builder.ins().brif(is_target, target_block, &[], other, &[]);
+            self.builder.switch_to_block(other);
+        }
+        self.builder.ins().return_(&[next]);
+    }
+
+    fn binary(&mut self, op: u8, a: i32, b: i32, result: i32, size: u8, slow: Block)
+    {
+        let (a, b, result) = (self.rbp(a), self.rbp(b), self.rbp(result));
+        let mut ok = self.in_bounds(a, size as i32);
+        ok = self.check(ok, b, size as i32);
+        ok = self.check(ok, result, size as i32);
+        self.guard_to(ok, slow);
+
+        let (a, b) = (self.load(a, size), self.load(b, size));
+        // Rust arithmetic panics on overflow in debug build and wraps in release,
+        // so overflows, division by zero and too big shifts are left to interpreter
+        let (value, is_invalid) = match op
+        {
+            0 => self.builder.ins().sadd_overflow(a, b),
+            1 => self.builder.ins().ssub_overflow(a, b),
+            2 => self.builder.ins().smul_overflow(a, b),
+            3 | 4 => {
+                // Dividing by -1 overflows for MIN. Divisor is replaced, so division itself never traps.
+                let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, b, -1);
+                let is_invalid = self.builder.ins().bor(is_zero, is_minus_one);
+                let one = self.builder.ins().iconst(int_type(size), 1);
+                let divisor = self.builder.ins().select(is_invalid, one, b);
+                let value = match op
+                {
+                    3 => self.builder.ins().sdiv(a, divisor),
+                    _ => self.builder.ins().srem(a, divisor),
+                };
+                (value, is_invalid)
+            },
+            5 | 6 => {
+                let is_invalid = self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, b, size as i64 * 8);
+                let value = match op
+                {
+                    5 => self.builder.ins().ishl(a, b),
+                    _ => self.builder.ins().sshr(a, b),
+                };
+                (value, is_invalid)
+            },
+            7 => (self.builder.ins().band(a, b), self.builder.ins().iconst(types::I8, 0)),
+            _ => (self.builder.ins().bor(a, b), self.builder.ins().iconst(types::I8, 0)),
+        };
+        let ok = self.builder.ins().icmp_imm(IntCC::Equal, is_invalid, 0);
+        self.guard_to(ok, slow);
+
+        self.store(result, value);
+    }
+
+    fn unary(&mut self, op: u8, value: i32, size: u8)
+    {
+        let address = self.rbp(value);
+        let ok = self.in_bounds(address, size as i32);
+        self.guard(ok);
+
+        let value = self.load(address, size);
+        let one = self.builder.ins().iconst(int_type(size), 1);
+        let (value, overflow) = match op
+        {
+            0 => self.builder.ins().sadd_overflow(value, one),
+            _ => self.builder.ins().ssub_overflow(value, one),
+        };
+        let ok = self.builder.ins().icmp_imm(IntCC::Equal, overflow, 0);
+        self.guard(ok);
+
+        self.store(address, value);
+    }
+
+    // Returns written result, 0 or 1
+    fn compare(&mut self, a: i32, b: i32, size: u8, result: i32, op: u8) -> Value
+    {
+        let (a, b, result) = (self.rbp(a), self.rbp(b), self.rbp(result));
+        let mut ok = self.in_bounds(a, size as i32);
+        ok = self.check(ok, b, size as i32);
+        ok = self.check(ok, result, 1);
+        self.guard(ok);
+
+        let (a, b) = (self.load(a, size), self.load(b, size));
+        let condition = match op
+        {
+            0 => IntCC::Equal,
+            1 => IntCC::NotEqual,
+            2 => IntCC::SignedGreaterThan,
+            3 => IntCC::SignedGreaterThanOrEqual,
+            4 => IntCC::SignedLessThan,
+            _ => IntCC::SignedLessThanOrEqual,
+        };
+        let value = self.builder.ins().icmp(condition, a, b);
+
+        self.store(result, value);
+        value
+    }
+
+    // Reads address stored in rbp-relative variable
+    fn deref(&mut self, offset: i32) -> Value
+    {
+        let variable = self.rbp(offset);
+        let ok = self.in_bounds(variable, 4);
+        self.guard(ok);
+
+        self.load(variable, 4)
+    }
+
+    fn rbp(&mut self, offset: i32) -> Value
+    {
+        let bp = self.builder.use_var(self.base_pointer);
+        self.builder.ins().iadd_imm(bp, offset as i64)
+    }
+
+    fn allocate(&mut self, sp: Value, size: i32)
+    {
+        let sp = self.builder.ins().iadd_imm(sp, size as i64);
+        self.builder.def_var(self.stack_pointer, sp);
+    }
+
+    // True if [address, address + size) is inside memory
+    fn in_bounds(&mut self, address: Value, size: i32) -> Value
+    {
+        self.builder.ins().icmp_imm(IntCC::UnsignedLessThanOrEqual, address, (Memory::STACK_SIZE - size) as i64)
+    }
+    fn check(&mut self, ok: Value, address: Value, size: i32) -> Value
+    {
+        let in_bounds = self.in_bounds(address, size);
+        self.builder.ins().band(ok, in_bounds)
+    }
+
+    // Continues in a new block if ok, otherwise runs instruction by interpreter
+    fn guard(&mut self, ok: Value)
+    {
+        let slow = self.slow();
+        self.guard_to(ok, slow);
+    }
+    fn guard_to(&mut self, ok: Value, slow: Block)
+    {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(ok, next, &[], slow, &[]);
+        self.builder.switch_to_block(next);
+    }
+    fn slow(&mut self) -> Block
+    {
+        match self.slow
+        {
+            Some(slow) => slow,
+            None => {
+                let slow = self.builder.create_block();
+                self.slow = Some(slow);
+                slow
+            }
+        }
+    }
+
+    fn native(&mut self, address: Value) -> Value
+    {
+        let offset = self.builder.ins().uextend(self.pointer, address);
+        self.builder.ins().iadd(self.bytes, offset)
+    }
+    fn load(&mut self, address: Value, size: u8) -> Value
+    {
+        let address = self.native(address);
+        self.builder.ins().load(int_type(size), flags(), address, 0)
+    }
+    fn store(&mut self, address: Value, value: Value)
+    {
+        let address = self.native(address);
+        self.builder.ins().store(flags(), value, address, 0);
+    }
+
+    fn store_bytes(&mut self, address: Value, bytes: &[u8])
+    {
+        let address = self.native(address);
+        for (offset, size) in parts(bytes.len() as i32)
+        {
+            let mut value = [0u8; 8];
+            value[..size as usize].copy_from_slice(&bytes[offset as usize..offset as usize + size as usize]);
+            let value = self.builder.ins().iconst(int_type(size), i64::from_ne_bytes(value));
+            self.builder.ins().store(flags(), value, address, offset);
+        }
+    }
+
+    // Everything is read before written, source and destination may overlap
+    fn copy(&mut self, src: Value, dst: Value, size: i32)
+    {
+        let (src, dst) = (self.native(src), self.native(dst));
+        let values: Vec<(i32, Value)> = parts(size).into_iter()
+            .map(|(offset, size)| (offset, self.builder.ins().load(int_type(size), flags(), src, offset)))
+            .collect();
+
+        for (offset, value) in values
+        {
+            self.builder.ins().store(flags(), value, dst, offset);
+        }
+    }
+
+    // Block of instruction at index, or one which leaves compiled code there
+    fn block(&mut self, index: usize) -> Block
+    {
+        if index >= self.entry && index < self.entry + self.blocks.len()
+        {
+            return self.blocks[index - self.entry]
+        }
+        if let Some((_, block)) = self.exits.iter().find(|(exit, _)| *exit == index)
+        {
+            return *block
+        }
+
+        let block = self.builder.create_block();
+        self.exits.push((index, block));
+        block
+    }
+    fn goto(&mut self, index: usize)
+    {
+        let block = self.block(index);
+        self.builder.ins().jump(block, &[]);
+    }
+    // Continues at next if value is not zero, otherwise jumps to target
+    fn branch(&mut self, value: Value, next: usize, target: usize)
+    {
+        let (next, target) = (self.block(next), self.block(target));
+        self.builder.ins().brif(value, next, &[], target, &[]);
+    }
+
+    fn emit_exits(&mut self)
+    {
+        for (index, block) in std::mem::take(&mut self.exits)
+        {
+            self.builder.switch_to_block(block);
+            self.flush();
+            let index = self.builder.ins().iconst(types::I32, index as i64);
+            self.builder.ins().return_(&[index]);
+        }
+    }
+
+    fn flush(&mut self)
+    {
+        let (sp, bp) = (self.builder.use_var(self.stack_pointer), self.builder.use_var(self.base_pointer));
+        self.builder.ins().store(MemFlags::trusted(), sp, self.vm, STACK_POINTER);
+        self.builder.ins().store(MemFlags::trusted(), bp, self.vm, BASE_POINTER);
+    }
+    fn reload(&mut self)
+    {
+        let sp = self.builder.ins().load(types::I32, MemFlags::trusted(), self.vm, STACK_POINTER);
+        let bp = self.builder.ins().load(types::I32, MemFlags::trusted(), self.vm, BASE_POINTER);
+        self.builder.def_var(self.stack_pointer, sp);
+        self.builder.def_var(self.base_pointer, bp);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::value::Value;
+
+    #[test]
+    fn host_call_is_compiled()
+    {
+        let mut vm = VM::new(call_module(0));
+        vm.enable_jit(1);
+
+        let result = vm.call("Program.Mix", &[Value::Int(5), Value::Int(300)]).unwrap();
+        assert!(matches!(result, Value::Int(49)));
+
+        let jit = vm.jit.as_ref().unwrap();
+        assert!(matches!(jit.functions.get(&jit.starts[0]), Some(JitFunction::Compiled(_))));
+    }
+}
\ No newline at end of file
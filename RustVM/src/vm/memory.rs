@@ -5,11 +5,24 @@
     pub base_pointer: i32,
     pub heap_pointer: i32,
     pub data_section_size: i32,
+
+    // When enabled, every write is saved with old and new bytes until someone takes them
+    pub record_writes: bool,
+    pub writes: Vec<MemoryWrite>,
+}
+
+#[derive(Clone)]
+pub struct MemoryWrite
+{
+    pub address: i32,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
 }
 
 impl Memory
 {
     pub const STACK_SIZE: i32 = 512;
+    pub const HEAP_START: i32 = Memory::STACK_SIZE / 2;
 
     pub fn new() -> Self
     {
@@ -17,8 +30,10 @@ impl Memory
             bytes: [0; Memory::STACK_SIZE as usize],
             stack_pointer: 0,
             base_pointer: 0,
-            heap_pointer: Memory::STACK_SIZE / 2,
+            heap_pointer: Memory::HEAP_START,
             data_section_size: 0,
+            record_writes: false,
+            writes: Vec::new(),
         }
     }
 
@@ -34,12 +49,61 @@ impl Memory
 
         pointer
     }
+
+    // Fails the same way as allocate_stack, but doesn't move stack pointer
+    pub fn check_stack(&self, bytes_to_allocate: i32)
+    {
+        if self.stack_pointer + bytes_to_allocate >= Memory::STACK_SIZE
+        {
+            panic!("Failed to allocate {bytes_to_allocate} bytes on stack due to stack overflow")
+        }
+    }
     pub fn allocate_heap(&mut self, bytes_to_allocate: i32) -> i32
     {
         let pointer = self.heap_pointer;
+
+        if bytes_to_allocate < 0 || pointer as i64 + bytes_to_allocate as i64 > Memory::STACK_SIZE as i64
+        {
+            panic!("Failed to allocate {bytes_to_allocate} bytes on heap: only {} bytes are left", Memory::STACK_SIZE - pointer)
+        }
+
         self.heap_pointer += bytes_to_allocate;
         pointer
     }
+    pub fn heap_left(&self) -> i32
+    {
+        Memory::STACK_SIZE - self.heap_pointer
+    }
+    // None if string doesn't fit into what is left of heap
+    pub fn try_allocate_heap_string(&mut self, value: &str) -> Option<i32>
+    {
+        match value.len() as i64 + 4 <= self.heap_left() as i64
+        {
+            true => Some(self.allocate_heap_string(value)),
+            false => None
+        }
+    }
+    // Allocates Astra string on heap: int length followed by utf8 bytes
+    pub fn allocate_heap_string(&mut self, value: &str) -> i32
+    {
+        let bytes = value.as_bytes();
+        let pointer = self.allocate_heap(4 + bytes.len() as i32);
+
+        self.write_int(pointer, bytes.len() as i32);
+        self.write_slice(pointer + 4, bytes);
+
+        pointer
+    }
+    // Data section is written at the beginning of memory, stack and heap are shifted behind it
+    pub fn place_data_section(&mut self, data: &[u8])
+    {
+        self.write_slice(0, data);
+
+        self.data_section_size = data.len() as i32;
+        self.stack_pointer += self.data_section_size;
+        self.heap_pointer += self.data_section_size;
+        self.base_pointer += self.data_section_size;
+    }
     pub fn deallocate_stack(&mut self, bytes_to_deallocate: i32)
     {
         self.stack_pointer -= bytes_to_deallocate;
@@ -58,38 +122,94 @@ impl Memory
 
     pub fn write_slice(&mut self, address: i32, bytes: &[u8])
     {
+        self.record_write(address, bytes);
         self.slice(address, bytes.len() as i32).copy_from_slice(bytes);
     }
     pub fn write_vec(&mut self, address: i32, bytes: Vec<u8>)
     {
-        self.slice(address, bytes.len() as i32).copy_from_slice(&*bytes);
+        self.record_write(address, &bytes);
+        self.slice(address, bytes.len() as i32).copy_from_slice(&bytes);
     }
     pub fn write_int(&mut self, address: i32, value: i32)
     {
+        self.record_write(address, &value.to_ne_bytes());
         self.slice(address, 4).copy_from_slice(value.to_ne_bytes().as_slice());
     }
     pub fn write_byte(&mut self, address: i32, value: u8)
     {
+        self.record_write(address, &[value]);
         self.bytes[address as usize] = value;
     }
+    // Fixed size values are written in place without allocation
+    pub fn write_array<const N: usize>(&mut self, address: i32, value: [u8; N])
+    {
+        self.record_write(address, &value);
+        self.slice(address, N as i32).copy_from_slice(&value);
+    }
+    pub fn fill(&mut self, address: i32, count: i32, value: u8)
+    {
+        if self.record_writes
+        {
+            self.record_write(address, &vec![value; count as usize]);
+        }
+        self.slice(address, count).fill(value);
+    }
+
+    #[inline(always)]
+    fn record_write(&mut self, address: i32, new: &[u8])
+    {
+        if self.record_writes && !new.is_empty()
+        {
+            let old = self.read(address, new.len() as i32).to_vec();
+            self.writes.push(MemoryWrite {
+                address,
+                old,
+                new: new.to_vec(),
+            });
+        }
+    }
+    pub fn take_writes(&mut self) -> Vec<MemoryWrite>
+    {
+        std::mem::take(&mut self.writes)
+    }
 
     pub fn read(&self, address: i32, count: i32) -> &[u8]
     {
         &self.bytes[(address as usize)..((address + count) as usize)]
     }
-    pub fn slice(&mut self, address: i32, count: i32) -> &mut [u8]
+    fn slice(&mut self, address: i32, count: i32) -> &mut [u8]
     {
         &mut self.bytes[(address as usize)..((address + count) as usize)]
     }
+    // Writes bytes without recording, for changes which are already logged
+    pub fn write_slice_unrecorded(&mut self, address: i32, bytes: &[u8])
+    {
+        self.slice(address, bytes.len() as i32).copy_from_slice(bytes);
+    }
     pub fn read_int(&self, address: i32) -> i32
     {
         i32::from_ne_bytes(self.read(address, 4).try_into().unwrap())
     }
+    pub fn read_array<const N: usize>(&self, address: i32) -> [u8; N]
+    {
+        self.read(address, N as i32).try_into().unwrap()
+    }
 
     pub fn copy(&mut self, src_address: i32, dst_address: i32, count: i32)
     {
-        let src_slice = self.read(src_address, count).to_vec();
-        self.slice(dst_address, count).copy_from_slice(&*src_slice);
+        if self.record_writes
+        {
+            let src_slice = self.read(src_address, count).to_vec();
+            self.record_write(dst_address, &src_slice);
+        }
+
+        let src_address = src_address as usize;
+        self.bytes.copy_within(src_address..src_address + count as usize, dst_address as usize);
+    }
+
+    pub fn is_valid_range(&self, address: i32, count: i32) -> bool
+    {
+        address >= 0 && count >= 0 && address as i64 + count as i64 <= Memory::STACK_SIZE as i64
     }
 
     pub fn to_abs(&self, rbp_offset: i32) -> i32
@@ -1,21 +1,65 @@
 mod binary_file;
 mod compiled_module;
 mod opcodes;
+#[allow(clippy::module_inception)]
 mod vm;
 mod memory;
 mod functions;
 mod winframework;
+mod file_sandbox;
+mod options;
+mod clock;
+mod random;
+mod entry_point;
+mod value;
+mod error;
+mod host_call;
+mod stack_trace;
+mod debugger;
+mod dap;
+mod watchpoints;
+mod disassembler;
+mod tracer;
+mod debug_info;
+mod crash_dump;
+mod profiler;
+mod coverage;
+mod predecode;
+mod optimizer;
+#[cfg(feature = "jit")]
+mod jit;
+mod fuel;
+mod cancellation;
+mod snapshot;
+mod replay;
+mod time_travel;
+mod bench;
+mod aot;
 
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use num_enum::TryFromPrimitive;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
 use stopwatch::Stopwatch;
-use binary_file::BinaryFile;
-use compiled_module::deserialize_module_from_bytes;
+use clock::{Clock, SystemClock, VirtualClock};
+use compiled_module::load_module;
+use bench::bench;
+use aot::translate_command;
+use crash_dump::{inspect_dump, report_fatal_error};
+use dap::{DapFrontend, DapTransport};
+use debugger::{ConsoleFrontend, Debugger, PauseReason};
+use entry_point::{enter_function, entry_name, read_exit_code};
+use fuel::{RunStatus, StopReason};
 use functions::get_functions;
-use memory::Memory;
+use file_sandbox::FileSandbox;
+use options::VMOptions;
+use profiler::Profiler;
+use coverage::Coverage;
+use random::Random;
+use stack_trace::{run_guarded, run_instruction};
+use value::Value;
 use vm::VM;
+use watchpoints::{WatchAction, Watchpoints};
 #[macro_export] macro_rules! debug_log {
     ($($arg:tt)*) => {
 		// #[cfg(debug_assertions)]
@@ -27,97 +71,372 @@ use vm::VM;
 
 pub fn vm_start()
 {
-    let mut asc_path = "C:/Users/REDIZIT/Documents/GitHub/Astra Projects/Desktop/bin/project.asc";
-    let mut opcodes_limit = -1;
-
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1
+    if args.get(1).map(|a| a.as_str()) == Some("inspect-dump")
+    {
+        match (args.get(2), args.get(3))
+        {
+            (Some(dump_path), Some(module_path)) => inspect_dump(Path::new(dump_path), Path::new(module_path)),
+            _ => println!("Usage: inspect-dump <dump> <module.asc>")
+        }
+        return;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("bench")
     {
-        asc_path = args[1].as_str();
-        println!("asc_path = {asc_path}")
+        bench(&args[2..]);
+        return;
     }
-    if args.len() > 2
+    if args.get(1).map(|a| a.as_str()) == Some("translate")
     {
-        opcodes_limit = args[2].parse().unwrap();
-        println!("opcodes_limit = {opcodes_limit}")
+        translate_command(&args[2..]);
+        return;
     }
 
+    let options = VMOptions::parse(&args);
 
-    let file = File::open(asc_path);
-    let mut buffer = Vec::new();
-    file.unwrap().read_to_end(&mut buffer).unwrap();
+    // With DAP over stdio stdout belongs to the protocol
+    let quiet = options.dap == Some(DapTransport::Stdio);
 
-    let mut module = deserialize_module_from_bytes(&buffer);
-    winframework::apply(&mut module);
+    let asc_path = options.asc_path.as_str();
+    let opcodes_limit = options.opcodes_limit;
+    if !quiet
+    {
+        println!("asc_path = {asc_path}");
+        if opcodes_limit != -1
+        {
+            println!("opcodes_limit = {opcodes_limit}")
+        }
+    }
 
-    let functions = get_functions();
 
-    let byte_code = BinaryFile::new(&module.managed_code.bytes);
-    let mut total_opcodes_completed = 0;
+    let mut module = load_module(Path::new(asc_path));
+    winframework::apply(&mut module);
 
-    let mut vm = VM {
-        byte_code: Box::from(byte_code),
-        memory: Memory::new(),
-        module: Box::from(module)
-    };
+    let mut vm = VM::new(module);
+    if opcodes_limit != -1
+    {
+        vm.set_fuel(Some(opcodes_limit as u64));
+    }
+    // Optimizer merges instructions, it's skipped when the limit is set
+    if options.optimize
+    {
+        vm.optimize_program();
+    }
+    #[cfg(feature = "jit")]
+    if options.jit
+    {
+        vm.enable_jit(options.jit_threshold);
+    }
+    vm.files = FileSandbox::new(options.fs_root, options.fs_read, options.fs_write);
+    if options.virtual_time
+    {
+        vm.clock = Box::from(VirtualClock::new(SystemClock::new().unix_time_ms()));
+    }
+    if let Some(seed) = options.seed
+    {
+        vm.random = Random::new(seed);
+    }
+    vm.env_allow_list = options.env_allow_list;
+    vm.place_args(&options.guest_args);
+    vm.dump_path = options.dump_path;
     
     winframework::set_vm(&mut vm);
 
     let mut w = Stopwatch::start_new();
 
-    while vm.byte_code.can_next()
+    // Restored program continues where it has stopped, entry function was already called then
+    if let Some(path) = &options.restore_snapshot
     {
-        if opcodes_limit != -1 && total_opcodes_completed > opcodes_limit {
-            panic!("Too many opcodes completed. Seems there is an infinite loop.")
+        if options.entry.is_some()
+        {
+            panic!("Snapshot can't be restored together with --entry")
         }
-        total_opcodes_completed += 1;
+        vm.restore_snapshot(path);
+    }
 
-        let byte_opcode = vm.byte_code.next();
-        
-        // let opcode = OpCode::try_from(byte_opcode).expect("Invalid opcode");
-        // println!("opcode = {:?}", opcode);
+    let entry = match options.restore_snapshot
+    {
+        None => entry_name(&vm.module, options.entry),
+        Some(_) => None
+    };
+    let entry_frame = entry.map(|entry| enter_function(&mut vm, &entry, &options.entry_args));
 
-        functions[byte_opcode as usize](&mut vm);
+    // Recording starts when entry function is called, replay restores that state
+    if let Some(path) = &options.replay
+    {
+        if options.record.is_some()
+        {
+            panic!("Program can't be recorded and replayed at the same time")
+        }
+        vm.start_replay(path);
+    }
+    if options.record.is_some()
+    {
+        vm.start_recording();
     }
 
-    w.stop();
+    let mut debugger = if let Some(transport) = options.dap
+    {
+        let frontend = DapFrontend::connect(transport);
+        vm.output = Box::from(frontend.output());
+        Some(Debugger::new(Box::from(frontend)))
+    }
+    else if options.debug
+    {
+        Some(Debugger::new(Box::from(ConsoleFrontend)))
+    }
+    else
+    {
+        None
+    };
+    if let Some(path) = &options.trace_path
+    {
+        vm.tracer.open(path, options.trace_format);
+        for name in &options.trace_functions
+        {
+            match vm.module.find_function(name)
+            {
+                Some(function_index) => vm.tracer.functions.push(function_index),
+                None => panic!("Failed to trace unknown function '{name}'")
+            }
+        }
+        if let Some(range) = &options.trace_memory
+        {
+            vm.tracer.memory_range = Some(Watchpoints::parse_range(&vm.memory, range).unwrap_or_else(|| panic!("Invalid trace memory range '{range}'")));
+        }
+        vm.set_tracing(true);
+    }
+    for range in &options.watchpoints
+    {
+        match Watchpoints::parse_range(&vm.memory, range)
+        {
+            Some((address, size)) => vm.add_watchpoint(address, size, WatchAction::Pause),
+            None => panic!("Failed to set watchpoint at invalid range '{range}'")
+        };
+    }
+    // Reverse execution logs every instruction, so it's enabled only on request
+    if options.time_travel
+    {
+        if debugger.is_none()
+        {
+            panic!("Time travel needs --debug or --dap")
+        }
+        vm.enable_time_travel();
+    }
+    if let Some(debugger) = &mut debugger
+    {
+        for location in &options.breakpoints
+        {
+            if debugger.add_breakpoint(&vm, location).is_none()
+            {
+                panic!("Failed to set breakpoint at unknown location '{location}'")
+            }
+        }
+        debugger.on_start(&mut vm);
+    }
 
-    let exit_code = vm.memory.read_int(vm.memory.data_section_size);
-    println!("Successful executed in {} ms with exit code {}", w.elapsed_ms(), exit_code);
-}
+    let mut profiler = match options.profile || options.profile_folded.is_some()
+    {
+        true => Some(Profiler::new()),
+        false => None
+    };
 
-pub fn interrupt(vm: &mut VM, new_current: i32)
-{
-    // println!("Interrupt {} -> {}", vm.byte_code.current, new_current);
+    let mut coverage = match options.coverage.is_some() || options.coverage_lcov.is_some()
+    {
+        true => Some(Coverage::new(&vm)),
+        false => None
+    };
 
-    let prev_current = vm.byte_code.current;
-    let prev_base = vm.memory.base_pointer;
-    let prev_stack = vm.memory.stack_pointer;
+    vm.set_timeout(options.timeout_ms.map(Duration::from_millis));
 
-    vm.byte_code.current = new_current as usize;
-    vm.memory.base_pointer = vm.memory.stack_pointer;
+    // Without instrumentation the whole program runs in pre-decoded form
+    let is_instrumented = debugger.is_some() || profiler.is_some() || coverage.is_some();
+    let mut status = RunStatus::Finished;
+    if options.predecode && !is_instrumented && vm.can_run_decoded()
+    {
+        status = vm.run_decoded();
+    }
 
+    if matches!(status, RunStatus::Finished)
+    {
+        status = run_guarded(&mut vm, |vm| run_instrumented(vm, &mut debugger, &mut profiler, &mut coverage));
+    }
 
+    // Stopped program can be continued from snapshot by the next run
+    let is_snapshot_saved = match (&options.save_snapshot, &status)
+    {
+        (Some(path), RunStatus::OutOfFuel | RunStatus::TimedOut(_) | RunStatus::Cancelled(_)) => {
+            vm.save_snapshot(path).unwrap_or_else(|e| panic!("Failed to write snapshot to {}: {e}", path.display()));
+            eprintln!("Snapshot is written to {}", path.display());
+            true
+        },
+        _ => false
+    };
+
+    if let Some(path) = &options.record
+    {
+        vm.save_recording(path).unwrap_or_else(|e| panic!("Failed to write recording to {}: {e}", path.display()));
+        eprintln!("Recording is written to {}", path.display());
+    }
+    if options.replay.is_some() && matches!(status, RunStatus::Finished)
+    {
+        match vm.finish_replay()
+        {
+            Ok(instructions) => eprintln!("Replay matches recording ({instructions} instructions)"),
+            Err(message) => panic!("Replay diverged: {message}")
+        }
+    }
+
+    // Nobody adds more fuel when running from command line
+    if matches!(status, RunStatus::OutOfFuel) && !is_snapshot_saved
+    {
+        let message = "Too many opcodes completed. Seems there is an infinite loop.";
+        report_fatal_error(&vm, vm.byte_code.current, message);
+        panic!("{message}")
+    }
+
+    w.stop();
+    vm.tracer.flush();
+
+    if let Some(profiler) = &mut profiler
+    {
+        profiler.finish();
+        if options.profile
+        {
+            print!("{}", profiler.report(&vm));
+        }
+        if let Some(path) = &options.profile_folded
+        {
+            fs::write(path, profiler.folded_stacks(&vm)).unwrap_or_else(|e| panic!("Failed to write folded stacks to {}: {e}", path.display()));
+        }
+    }
+
+    if let Some(coverage) = &coverage
+    {
+        if let Some(path) = &options.coverage
+        {
+            fs::write(path, coverage.report(&vm)).unwrap_or_else(|e| panic!("Failed to write coverage to {}: {e}", path.display()));
+        }
+        if let Some(path) = &options.coverage_lcov
+        {
+            match coverage.lcov(&vm)
+            {
+                Some(lcov) => fs::write(path, lcov).unwrap_or_else(|e| panic!("Failed to write lcov to {}: {e}", path.display())),
+                None => eprintln!("Module has no line table, lcov coverage is not written")
+            }
+        }
+    }
+
+    if let RunStatus::TimedOut(stack_trace) = &status
+    {
+        eprint!("Execution timed out after {} ms at 0x{:04X}\nGuest stack trace:\n{}", w.elapsed_ms(), vm.byte_code.current, stack_trace);
+    }
+    // Only debugger can cancel command line run
+    if let RunStatus::Cancelled(stack_trace) = &status
+    {
+        eprint!("Execution is stopped by debugger at 0x{:04X}\nGuest stack trace:\n{}", vm.byte_code.current, stack_trace);
+    }
+
+    let exit_code = match &entry_frame
+    {
+        Some(frame) => read_exit_code(&vm, frame),
+        None => vm.memory.read_int(vm.memory.data_section_size)
+    };
+    if let Some(debugger) = &mut debugger
+    {
+        debugger.on_exit(exit_code);
+    }
+    if !quiet && matches!(status, RunStatus::Finished)
+    {
+        println!("Successful executed in {} ms with exit code {}", w.elapsed_ms(), exit_code);
+    }
+}
+
+// Runs instructions one by one with debugger, profiler and coverage hooks
+fn run_instrumented(vm: &mut VM, debugger: &mut Option<Debugger>, profiler: &mut Option<Profiler>, coverage: &mut Option<Coverage>) -> RunStatus
+{
     let functions = get_functions();
 
     while vm.byte_code.can_next()
     {
-        let byte_opcode = vm.byte_code.next();
-        functions[byte_opcode as usize](vm);
-        
-        // println!("current base = {}, current stack = {}, prev_base = {}, prev_stack = {}", vm.memory.base_pointer, vm.memory.stack_pointer, prev_base, prev_stack);
+        if let Some(reason) = vm.stop_reason()
+        {
+            return vm.run_status(Some(reason))
+        }
 
-        if vm.memory.stack_pointer == prev_stack
+        if let Some(debugger) = debugger
         {
-            // println!("Interrupt done");
+            debugger.on_instruction(vm);
+            if debugger.stop_requested
+            {
+                return vm.run_status(Some(StopReason::Cancelled))
+            }
+        }
 
-            vm.byte_code.current = prev_current;
-            vm.memory.base_pointer = prev_base;
-            vm.memory.stack_pointer = prev_stack;
+        let offset = vm.byte_code.current;
 
-            return;
+        // After going back in time instructions are taken from log until execution gets back to present
+        if let Some(writes) = vm.time_travel_step()
+        {
+            let hits = vm.check_watchpoints(&writes, offset);
+            vm.report_watch_hits(&hits);
+            pause_on_watchpoint(vm, debugger.as_mut());
+            continue;
         }
+
+        if let Some(profiler) = profiler
+        {
+            profiler.on_instruction(vm, offset);
+        }
+        let trace_start = vm.tracer.begin(&vm.memory, offset);
+        let byte_opcode = vm.byte_code.next();
+
+        // let opcode = OpCode::try_from(byte_opcode).expect("Invalid opcode");
+        // println!("opcode = {:?}", opcode);
+
+        run_instruction(vm, functions, byte_opcode, offset);
+
+        if let Some(coverage) = coverage
+        {
+            coverage.record(vm, offset);
+        }
+
+        if vm.memory.record_writes
+        {
+            let writes = vm.memory.take_writes();
+            vm.watchpoints.checked_writes = 0;
+            if let Some(start) = trace_start
+            {
+                vm.tracer.record(&vm.module, &vm.byte_code.bytes, &vm.memory, start, &writes, vm.byte_code.current);
+            }
+            // Logged before watchpoints can pause, debugger may go back from there
+            vm.finish_step(&writes);
+        }
+        // Watchpoints were checked by every instruction including nested ones
+        pause_on_watchpoint(vm, debugger.as_mut());
     }
 
-    panic!("Interrupt have reached the end of byte code.")
+    RunStatus::Finished
+}
+
+// Stops in debugger (if there is one) when pausing watchpoint was hit
+fn pause_on_watchpoint(vm: &mut VM, debugger: Option<&mut Debugger>)
+{
+    if vm.take_watch_pause()
+        && let Some(debugger) = debugger
+    {
+        debugger.pause(vm, PauseReason::Watchpoint);
+    }
+}
+
+// Runs guest function pointed by given opcode on top of the current stack with given arguments
+// and returns its result. Used by window callbacks.
+#[allow(dead_code)]
+pub fn interrupt(vm: &mut VM, new_current: i32, args: &[Value]) -> Value
+{
+    let function_index = vm.module.table.functions.iter()
+        .position(|f| f.pointed_module == 0 && f.pointed_opcode == new_current as u32)
+        .unwrap_or_else(|| panic!("Interrupt target {new_current} is not a function"));
+
+    vm.call_index(function_index, args).unwrap_or_else(|error| panic!("Interrupt failed: {error}"))
 }
\ No newline at end of file
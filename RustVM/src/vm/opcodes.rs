@@ -1,7 +1,9 @@
 ﻿use num_enum::TryFromPrimitive;
 
+// Names are the same as in the compiler
 #[derive(Debug, TryFromPrimitive)]
 #[repr(u8)]
+#[allow(non_camel_case_types)]
 pub enum OpCode
 {
     Invalid,
@@ -56,6 +58,7 @@ pub enum OpCode
 
 #[derive(Debug, TryFromPrimitive)]
 #[repr(u8)]
+#[allow(non_camel_case_types, dead_code)]
 pub enum Allocate_Stack_Mode
 {
     WithDefaultValue = 0,
@@ -64,9 +67,30 @@ pub enum Allocate_Stack_Mode
 
 #[derive(Debug, TryFromPrimitive)]
 #[repr(u8)]
+#[allow(non_camel_case_types)]
 pub enum VMCommand_Cmd
 {
     Print,
     CreateWindow,
     Sleep,
+
+    FileOpen,
+    FileRead,
+    FileWrite,
+    FileClose,
+    FileSeek,
+    FileExists,
+    FileDelete,
+
+    TimeNow,
+    TimeMonotonicMs,
+    TimeMonotonicNs,
+    TimeElapsed,
+
+    RandomInt,
+    RandomBytes,
+    RandomSeed,
+
+    GetArgs,
+    GetEnv,
 }
\ No newline at end of file
@@ -0,0 +1,332 @@
+﻿use std::rc::Rc;
+use crate::vm::compiled_module::CompiledModule;
+use crate::vm::predecode::{DecodedProgram, Dst, Op, Src, NOT_INSTRUCTION};
+use crate::vm::vm::VM;
+
+// Peephole optimizer over pre-decoded program.
+// Instructions which can be entered not only from the previous one (jump targets, functions, return addresses)
+// are never removed as part of a pair and never merged into the previous instruction.
+
+// Looks at instruction at given index. Returns how many instructions are replaced and by what (None to remove them),
+// or None to keep the instruction as is.
+type Rule = fn(&DecodedProgram, &[Op], usize, &[bool]) -> Option<(usize, Option<Op>)>;
+
+// Returns optimized copy of program or error if it doesn't pass verification
+pub fn optimize(program: &DecodedProgram, module: &CompiledModule) -> Result<DecodedProgram, String>
+{
+    let mut optimized = program.clone();
+    rewrite(&mut optimized, module, remove_redundant);
+    rewrite(&mut optimized, module, fuse);
+
+    verify(&optimized, module)?;
+    Ok(optimized)
+}
+
+impl VM
+{
+    // Program which optimizer has broken still runs, just unoptimized.
+    // Fuel counts instructions as they are in byte code, so program with limited fuel isn't optimized (set fuel first).
+    pub fn optimize_program(&mut self)
+    {
+        if self.fuel.is_some()
+        {
+            return
+        }
+        if let Some(program) = &self.program
+        {
+            match optimize(program, &self.module)
+            {
+                Ok(optimized) => self.program = Some(Rc::new(optimized)),
+                Err(error) => eprintln!("Optimized program is invalid, running it unoptimized: {error}")
+            }
+        }
+    }
+}
+
+fn rewrite(program: &mut DecodedProgram, module: &CompiledModule, rule: Rule)
+{
+    let entries = entry_points(program, module);
+    let old = std::mem::take(&mut program.ops);
+
+    let mut ops = Vec::new();
+    let mut offsets = Vec::new();
+    // Old index => new index. Removed instruction continues at the next kept one, merged ones can't be entered.
+    let mut map = vec![NOT_INSTRUCTION; old.len() + 1];
+
+    let mut i = 0;
+    while i < old.len()
+    {
+        map[i] = ops.len() as u32;
+
+        let (count, op) = rule(program, &old, i, &entries).unwrap_or_else(|| (1, Some(old[i].clone())));
+        if let Some(op) = op
+        {
+            ops.push(op);
+            offsets.push(program.offsets[i]);
+        }
+        i += count;
+    }
+    map[old.len()] = ops.len() as u32;
+    offsets.push(program.offsets[old.len()]);
+
+    for op in &mut ops
+    {
+        if let Some(target) = op.target_mut()
+        {
+            *target = map[*target as usize];
+        }
+    }
+    for index in &mut program.indexes
+    {
+        if *index != NOT_INSTRUCTION
+        {
+            *index = map[*index as usize];
+        }
+    }
+
+    program.ops = ops;
+    program.offsets = offsets;
+}
+
+fn entry_points(program: &DecodedProgram, module: &CompiledModule) -> Vec<bool>
+{
+    let mut entries = vec![false; program.ops.len() + 1];
+    entries[0] = true;
+
+    for (i, op) in program.ops.iter().enumerate()
+    {
+        if let Some(target) = op.target()
+        {
+            entries[target as usize] = true;
+        }
+        // Return continues right after Call
+        if let Op::Call { .. } = op
+        {
+            entries[i + 1] = true;
+        }
+    }
+
+    // Functions are also entered by host calls and interrupts
+    for function_info in &module.table.functions
+    {
+        if function_info.pointed_module == 0
+            && let Some(index) = program.index_of(function_info.pointed_opcode as usize)
+        {
+            entries[index] = true;
+        }
+    }
+
+    entries
+}
+
+fn remove_redundant(program: &DecodedProgram, ops: &[Op], i: usize, entries: &[bool]) -> Option<(usize, Option<Op>)>
+{
+    let next = ops.get(i + 1).filter(|_| !entries[i + 1]);
+
+    match (&ops[i], next)
+    {
+        (Op::Nop, _) => Some((1, None)),
+        (Op::DeallocateStack { size: 0 }, _) => Some((1, None)),
+        (Op::Mov { dst: Dst::Rbp(dst), src: Src::Rbp(src, _) }, _) if dst == src => Some((1, None)),
+
+        // Stack space freed right away. Only bytes above stack pointer are lost, overflow is still checked.
+        (Op::AllocateStack { value }, Some(Op::DeallocateStack { size })) if value.len as i32 == *size => Some((2, Some(Op::CheckStack { size: *size }))),
+        (Op::PushVariable { size, .. }, Some(Op::DeallocateStack { size: deallocated })) if *size as i32 == *deallocated => Some((2, Some(Op::CheckStack { size: *deallocated }))),
+
+        // The same move twice, the second one changes nothing unless it reads what the first one wrote
+        (Op::Mov { dst, src }, Some(Op::Mov { dst: next_dst, src: next_src })) if dst == next_dst && is_same_source(program, src, next_src) && is_repeatable(dst, src) => {
+            Some((2, Some(ops[i].clone())))
+        },
+        _ => None
+    }
+}
+
+// Constants are compared by value, equal bytes may be stored twice in data pool
+fn is_same_source(program: &DecodedProgram, a: &Src, b: &Src) -> bool
+{
+    match (a, b)
+    {
+        (Src::Const(a), Src::Const(b)) => program.bytes(*a) == program.bytes(*b),
+        _ => a == b
+    }
+}
+
+fn is_repeatable(dst: &Dst, src: &Src) -> bool
+{
+    match (dst, src)
+    {
+        (Dst::Rbp(dst), Src::Rbp(src, size)) => dst + *size as i32 <= *src || src + *size as i32 <= *dst,
+        (Dst::Rbp(_), Src::Const(_)) => true,
+        _ => false
+    }
+}
+
+fn fuse(_program: &DecodedProgram, ops: &[Op], i: usize, entries: &[bool]) -> Option<(usize, Option<Op>)>
+{
+    let next = ops.get(i + 1).filter(|_| !entries[i + 1])?;
+
+    let op = match (&ops[i], next)
+    {
+        (Op::Compare { a, b, size, result, op }, Op::JumpIfFalse { target, condition, size: 1 }) if result == condition => {
+            Op::CompareJumpIfFalse { a: *a, b: *b, size: *size, result: *result, op: *op, target: *target }
+        },
+        (Op::Unary { op, value, size }, Op::Jump { target }) => {
+            Op::UnaryJump { op: *op, value: *value, size: *size, target: *target }
+        },
+        (Op::Mov { dst: Dst::Rbp(dst), src: Src::Rbp(src, mov_size) }, Op::Binary { op, a, b, result, size }) => {
+            Op::MovBinary { dst: *dst, src: *src, mov_size: *mov_size, op: *op, a: *a, b: *b, result: *result, size: *size }
+        },
+        _ => return None
+    };
+
+    Some((2, Some(op)))
+}
+
+// Checks that program can be run: offsets and indexes agree, jumps, calls and returns land on instructions
+pub fn verify(program: &DecodedProgram, module: &CompiledModule) -> Result<(), String>
+{
+    let count = program.ops.len();
+    if program.offsets.len() != count + 1
+    {
+        return Err(format!("{} offsets for {} instructions", program.offsets.len(), count))
+    }
+
+    for (i, op) in program.ops.iter().enumerate()
+    {
+        let offset = program.offsets[i];
+        if offset >= program.offsets[i + 1]
+        {
+            return Err(format!("Offsets are not increasing at instruction {i} (0x{:04X})", offset))
+        }
+        if program.indexes[offset] as usize != i
+        {
+            return Err(format!("Offset 0x{:04X} doesn't point to its instruction {i}", offset))
+        }
+
+        if let Some(target) = op.target()
+            && target as usize > count
+        {
+            return Err(format!("Instruction {i} (0x{:04X}) jumps out of program to {target}", offset))
+        }
+        if let Op::Call { .. } = op
+            && program.index_of(offset + 5).is_none()
+        {
+            return Err(format!("Call at 0x{:04X} returns to the middle of instruction", offset))
+        }
+    }
+
+    if let Some(index) = program.indexes.iter().find(|index| **index != NOT_INSTRUCTION && **index as usize > count)
+    {
+        return Err(format!("Offset points to unknown instruction {index}"))
+    }
+
+    for function_info in &module.table.functions
+    {
+        if function_info.pointed_module == 0 && program.index_of(function_info.pointed_opcode as usize).is_none()
+        {
+            return Err(format!("Function '{}' starts in the middle of instruction", function_info.name))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::{call_module, loop_module};
+    use crate::vm::binary_file::SharedBytes;
+    use crate::vm::compiled_module::ManagedCode;
+    use crate::vm::opcodes::OpCode;
+    use crate::vm::predecode::decode_program;
+
+    // Instruction of optimized program which runs original one, merged instructions are found in the previous one
+    fn find(optimized: &DecodedProgram, program: &DecodedProgram, index: usize) -> usize
+    {
+        optimized.index_of(program.offsets[index])
+            .or_else(|| optimized.index_of(program.offsets[index - 1]))
+            .unwrap()
+    }
+
+    #[test]
+    fn optimized_program_keeps_entries()
+    {
+        for module in [loop_module(10), call_module(10)]
+        {
+            let program = decode_program(&module.managed_code.bytes).unwrap();
+            let optimized = optimize(&program, &module).unwrap();
+            assert!(optimized.ops.len() < program.ops.len());
+
+            for (i, op) in program.ops.iter().enumerate()
+            {
+                if let Some(target) = op.target()
+                {
+                    let target = optimized.index_of(program.offsets[target as usize]).unwrap();
+                    assert_eq!(optimized.ops[find(&optimized, &program, i)].target(), Some(target as u32));
+                }
+                if let Op::Call { .. } = op
+                {
+                    assert!(optimized.index_of(program.offsets[i + 1]).is_some(), "Return site after call is lost");
+                }
+            }
+
+            for function_info in &module.table.functions
+            {
+                let entry = optimized.index_of(function_info.pointed_opcode as usize).unwrap();
+                assert_eq!(optimized.offsets[entry], function_info.pointed_opcode as usize);
+            }
+        }
+    }
+
+    // Two allocations kept on stack, then the third one is freed right away
+    fn stack_module(size: u8) -> CompiledModule
+    {
+        let mut code = vec![OpCode::Section as u8, 0, 0, 0, 0, 0, 0, 0];
+        for _ in 0..3
+        {
+            code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, size]);
+            code.extend(vec![7; size as usize]);
+        }
+        code.push(OpCode::Deallocate_Stack as u8);
+        code.extend_from_slice(&(size as i32).to_ne_bytes());
+        code.extend_from_slice(&[OpCode::Exit as u8, OpCode::Exit as u8]);
+
+        CompiledModule { managed_code: ManagedCode { bytes: SharedBytes::from(code) }, ..loop_module(0) }
+    }
+
+    fn optimized_vm(module: CompiledModule) -> VM
+    {
+        let mut vm = VM::new(module);
+        let count = vm.program.as_ref().unwrap().ops.len();
+        vm.optimize_program();
+        assert!(vm.program.as_ref().unwrap().ops.len() < count);
+        vm
+    }
+
+    #[test]
+    fn freed_allocation_fits_stack()
+    {
+        let mut vm = optimized_vm(stack_module(100));
+        assert!(vm.program.as_ref().unwrap().ops.iter().any(|op| matches!(op, Op::CheckStack { size: 100 })));
+        vm.run_decoded();
+        assert_eq!(vm.memory.stack_pointer, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to allocate 200 bytes on stack due to stack overflow")]
+    fn freed_allocation_still_checks_overflow()
+    {
+        optimized_vm(stack_module(200)).run_decoded();
+    }
+
+    #[test]
+    fn program_with_fuel_is_not_optimized()
+    {
+        let mut vm = VM::new(loop_module(3));
+        vm.set_fuel(Some(1000));
+        let count = vm.program.as_ref().unwrap().ops.len();
+        vm.optimize_program();
+        assert_eq!(vm.program.as_ref().unwrap().ops.len(), count);
+    }
+}
\ No newline at end of file
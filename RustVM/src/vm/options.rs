@@ -0,0 +1,262 @@
+﻿use std::path::PathBuf;
+use crate::vm::dap::DapTransport;
+use crate::vm::tracer::TraceFormat;
+
+pub struct VMOptions
+{
+    pub asc_path: String,
+    pub opcodes_limit: i32,
+    // Wall-clock limit of execution
+    pub timeout_ms: Option<u64>,
+
+    pub fs_root: Option<PathBuf>,
+    pub fs_read: bool,
+    pub fs_write: bool,
+
+    pub virtual_time: bool,
+    pub seed: Option<u64>,
+
+    pub guest_args: Vec<String>,
+    pub env_allow_list: Vec<String>,
+
+    pub entry: Option<String>,
+    pub entry_args: Vec<String>,
+
+    pub debug: bool,
+    pub breakpoints: Vec<String>,
+    pub dap: Option<DapTransport>,
+    pub time_travel: bool,
+    pub watchpoints: Vec<String>,
+
+    pub trace_path: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_functions: Vec<String>,
+    pub trace_memory: Option<String>,
+
+    pub dump_path: Option<PathBuf>,
+    // Written when execution stops because of opcodes limit or timeout
+    pub save_snapshot: Option<PathBuf>,
+    pub restore_snapshot: Option<PathBuf>,
+    // Log of nondeterministic inputs
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+
+    pub profile: bool,
+    pub profile_folded: Option<PathBuf>,
+
+    pub coverage: Option<PathBuf>,
+    pub coverage_lcov: Option<PathBuf>,
+
+    pub predecode: bool,
+    pub optimize: bool,
+    #[cfg(feature = "jit")]
+    pub jit: bool,
+    #[cfg(feature = "jit")]
+    pub jit_threshold: u32,
+}
+
+impl VMOptions
+{
+    // Usage: RustVM [asc_path] [opcodes_limit] [--fs-root <dir>] [--fs-read] [--fs-write] [--virtual-time] [--seed <n>] [--timeout <ms>] [--env-allow <NAME,...>] [--entry <Type.Function>] [--entry-arg <value>]...
+    //              (entry is Main if module has it, otherwise byte code runs from offset 0)
+    //              [--debug] [--break <offset|Type.Function>]... [--dap] [--dap-port <port>] [--time-travel]
+    //              [--watch <addr[:size]>]... [--trace <file>] [--trace-format text|json] [--trace-function <Type.Function>]... [--trace-memory <addr[:size]>]
+    //              [--dump <file>] [--no-dump] [--save-snapshot <file>] [--restore-snapshot <file>] [--record <file>] [--replay <file>] [--profile] [--profile-folded <file>]
+    //              [--coverage <file>] [--coverage-lcov <file>] [--no-predecode] [--no-optimize] [--no-jit] [--jit-threshold <calls>] [-- guest args...]
+    //       RustVM inspect-dump <dump> <module.asc>
+    //       RustVM bench [module.asc] [runs]
+    //       RustVM translate <module.asc> <out.rs> [--entry <Type.Function>] [--env-allow <NAME,...>]
+    pub fn parse(args: &[String]) -> Self
+    {
+        let mut options = Self {
+            asc_path: String::from("C:/Users/REDIZIT/Documents/GitHub/Astra Projects/Desktop/bin/project.asc"),
+            opcodes_limit: -1,
+            timeout_ms: None,
+            fs_root: None,
+            fs_read: false,
+            fs_write: false,
+            virtual_time: false,
+            seed: None,
+            guest_args: Vec::new(),
+            env_allow_list: Vec::new(),
+            entry: None,
+            entry_args: Vec::new(),
+            debug: false,
+            breakpoints: Vec::new(),
+            dap: None,
+            time_travel: false,
+            watchpoints: Vec::new(),
+            trace_path: None,
+            trace_format: TraceFormat::Text,
+            trace_functions: Vec::new(),
+            trace_memory: None,
+            dump_path: Some(PathBuf::from("astra-crash.dump")),
+            save_snapshot: None,
+            restore_snapshot: None,
+            record: None,
+            replay: None,
+            profile: false,
+            profile_folded: None,
+            coverage: None,
+            coverage_lcov: None,
+            predecode: true,
+            optimize: true,
+            #[cfg(feature = "jit")]
+            jit: true,
+            #[cfg(feature = "jit")]
+            jit_threshold: 100,
+        };
+
+        let mut positional_index = 0;
+        let mut i = 1;
+
+        while i < args.len()
+        {
+            let arg = args[i].as_str();
+
+            match arg
+            {
+                "--fs-root" => {
+                    options.fs_root = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--fs-read" => options.fs_read = true,
+                "--fs-write" => options.fs_write = true,
+                "--virtual-time" => options.virtual_time = true,
+                "--env-allow" => {
+                    options.env_allow_list.extend(Self::value(args, i).split(',').map(String::from));
+                    i += 1;
+                },
+                "--entry" => {
+                    options.entry = Some(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--entry-arg" => {
+                    options.entry_args.push(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--debug" => options.debug = true,
+                "--break" => {
+                    options.breakpoints.push(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--watch" => {
+                    options.watchpoints.push(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--trace" => {
+                    options.trace_path = Some(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--trace-format" => {
+                    options.trace_format = match Self::value(args, i)
+                    {
+                        "text" => TraceFormat::Text,
+                        "json" => TraceFormat::JsonLines,
+                        format => panic!("Unknown trace format '{format}', expected 'text' or 'json'")
+                    };
+                    i += 1;
+                },
+                "--trace-function" => {
+                    options.trace_functions.push(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--trace-memory" => {
+                    options.trace_memory = Some(String::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--dump" => {
+                    options.dump_path = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--no-dump" => options.dump_path = None,
+                "--save-snapshot" => {
+                    options.save_snapshot = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--restore-snapshot" => {
+                    options.restore_snapshot = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--record" => {
+                    options.record = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--replay" => {
+                    options.replay = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--profile" => options.profile = true,
+                "--profile-folded" => {
+                    options.profile_folded = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--coverage" => {
+                    options.coverage = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--coverage-lcov" => {
+                    options.coverage_lcov = Some(PathBuf::from(Self::value(args, i)));
+                    i += 1;
+                },
+                "--no-predecode" => options.predecode = false,
+                "--no-optimize" => options.optimize = false,
+                #[cfg(feature = "jit")]
+                "--no-jit" => options.jit = false,
+                #[cfg(feature = "jit")]
+                "--jit-threshold" => {
+                    options.jit_threshold = Self::value(args, i).parse().unwrap();
+                    i += 1;
+                },
+                "--dap" => options.dap = Some(DapTransport::Stdio),
+                "--time-travel" => options.time_travel = true,
+                "--dap-port" => {
+                    options.dap = Some(DapTransport::Tcp(Self::value(args, i).parse().unwrap()));
+                    i += 1;
+                },
+                "--" => {
+                    // Everything after '--' belongs to guest program
+                    options.guest_args = args[i + 1..].to_vec();
+                    break;
+                },
+                "--timeout" => {
+                    options.timeout_ms = Some(Self::value(args, i).parse().unwrap());
+                    i += 1;
+                },
+                "--seed" => {
+                    options.seed = Some(Self::value(args, i).parse().unwrap());
+                    i += 1;
+                },
+                _ if arg.starts_with("--") => panic!("Unknown option '{arg}'"),
+                _ => {
+                    match positional_index
+                    {
+                        0 => options.asc_path = String::from(arg),
+                        1 => options.opcodes_limit = arg.parse().unwrap(),
+                        _ => panic!("Unexpected argument '{arg}'")
+                    }
+                    positional_index += 1;
+                }
+            }
+
+            i += 1;
+        }
+
+        // Passing entry arguments without entry name means they are for Main
+        if options.entry.is_none() && !options.entry_args.is_empty()
+        {
+            options.entry = Some(String::from("Main"));
+        }
+
+        options
+    }
+
+    fn value(args: &[String], option_index: usize) -> &str
+    {
+        match args.get(option_index + 1)
+        {
+            Some(value) => value.as_str(),
+            None => panic!("Option '{}' requires a value", args[option_index])
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,564 @@
+﻿use std::rc::Rc;
+use crate::vm::disassembler::Reader;
+use crate::vm::fuel::{RunStatus, StopReason};
+use crate::vm::functions::compare_functions::compare_at;
+use crate::vm::functions::math_functions::*;
+use crate::vm::functions::negate_function::negate_at;
+use crate::vm::functions::vm_command_functions::{run_vm_command, VMCmdArgument};
+use crate::vm::functions::{cast_at, field_access_at, HOST_RETURN_ADDRESS};
+use crate::vm::opcodes::OpCode;
+use crate::vm::stack_trace::{catch_run, fail_run};
+use crate::vm::vm::VM;
+use crate::vm::winframework;
+
+// Byte code decoded once at load time, so the interpreter loop doesn't read operands byte by byte.
+// Jump targets are indexes of instructions, constant bytes are kept in a single pool.
+// Operand layout must match the one read by functions in vm/functions.
+
+#[derive(Clone)]
+pub struct DecodedProgram
+{
+    pub ops: Vec<Op>,
+    // Byte code offset of each instruction, plus byte code length for the end
+    pub offsets: Vec<usize>,
+    // Instruction index by byte code offset (NOT_INSTRUCTION if offset is in the middle of one)
+    pub indexes: Vec<u32>,
+    data: Vec<u8>,
+}
+
+type BinaryOp = fn(&mut VM, i32, i32, i32, u8);
+type UnaryOp = fn(&mut VM, i32, u8);
+
+// Indexed by opcode - OpCode::Add (or OpCode::Increment), kept as byte so instructions stay small
+const BINARY_OPS: [BinaryOp; 9] = [add_at, sub_at, mul_at, div_at, div_remainder_at, left_bit_shift_at, right_bit_shift_at, bit_and_at, bit_or_at];
+const UNARY_OPS: [UnaryOp; 2] = [increment_at, decrement_at];
+
+// Bytes in program's data pool
+#[derive(Clone, Copy, PartialEq)]
+pub struct Const
+{
+    start: u32,
+    pub len: u32,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Dst
+{
+    Rbp(i32),
+    // Address stored in rbp-relative variable
+    Deref(i32),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Src
+{
+    Rbp(i32, u8),
+    Const(Const),
+    Abs(i32, u8),
+}
+
+#[derive(Clone)]
+pub enum Op
+{
+    Nop,
+    AllocateStack { value: Const },
+    PushVariable { variable: i32, size: u8 },
+    AllocateHeap { storage: i32, size: i32 },
+    DeallocateStack { size: i32 },
+    Prologue,
+    Epilogue,
+    Call { function: u32 },
+    Return,
+    Jump { target: u32 },
+    JumpIfFalse { target: u32, condition: i32, size: u8 },
+    Exit,
+    Mov { dst: Dst, src: Src },
+    Binary { op: u8, a: i32, b: i32, result: i32, size: u8 },
+    Unary { op: u8, value: i32, size: u8 },
+    Compare { a: i32, b: i32, size: u8, result: i32, op: u8 },
+    Negate { a: i32, result: i32, size: u8 },
+    ToPtrValueType { variable: i32, result: i32 },
+    ToPtrRefType { variable: i32, result: i32 },
+    PtrGet { pointer: i32, result: i32, size: u8 },
+    PtrSet { pointer: i32, value: i32, size: u8 },
+    PtrShift { pointer: i32, shift: i32 },
+    PtrShiftBy { pointer: i32, shift: i32, additional: i32 },
+    FieldAccess { base: i32, field_offset: i32, size: u8, is_getter: u8, result: i32 },
+    Legacy,
+    Cast { variable: i32, variable_size: u8, result: i32, result_size: u8 },
+    Section { data: Option<Const> },
+    VMCommand { cmd: u8, arguments: Box<[VMCmdArgument]> },
+
+    // Superinstructions made by optimizer, errors are reported at offset of their first instruction
+    CompareJumpIfFalse { a: i32, b: i32, size: u8, result: i32, op: u8, target: u32 },
+    UnaryJump { op: u8, value: i32, size: u8, target: u32 },
+    MovBinary { dst: i32, src: i32, mov_size: u8, op: u8, a: i32, b: i32, result: i32, size: u8 },
+    // Stack allocation which is freed right away, only its overflow check is left
+    CheckStack { size: i32 },
+}
+
+pub const NOT_INSTRUCTION: u32 = u32::MAX;
+
+// Returns None if byte code can't be fully decoded, then the usual interpreter should be used
+pub fn decode_program(bytes: &[u8]) -> Option<DecodedProgram>
+{
+    let mut program = DecodedProgram {
+        ops: Vec::new(),
+        offsets: Vec::new(),
+        indexes: vec![NOT_INSTRUCTION; bytes.len() + 1],
+        data: Vec::new(),
+    };
+
+    // Interpreter stops before the last byte, so does decoding
+    let end = bytes.len().saturating_sub(1);
+    let mut offset = 0;
+
+    while offset < end
+    {
+        let mut r = Reader::new(bytes, offset);
+        let op = decode_op(&mut r, &mut program.data)?;
+
+        program.indexes[offset] = program.ops.len() as u32;
+        program.offsets.push(offset);
+        program.ops.push(op);
+        offset = r.current;
+    }
+
+    let count = program.ops.len() as u32;
+    for i in end..program.indexes.len()
+    {
+        program.indexes[i] = count;
+    }
+    program.offsets.push(bytes.len());
+
+    // Byte offsets of jump targets become instruction indexes
+    for i in 0..program.ops.len()
+    {
+        if let Some(target) = program.ops[i].target()
+        {
+            let index = program.index_of(target as usize)?;
+            *program.ops[i].target_mut().unwrap() = index as u32;
+        }
+    }
+
+    Some(program)
+}
+
+// Negative target is not an instruction, such byte code is left to the interpreter
+fn jump_target(r: &mut Reader) -> Option<u32>
+{
+    u32::try_from(r.int()?).ok()
+}
+
+fn constant(r: &mut Reader, data: &mut Vec<u8>, len: usize) -> Option<Const>
+{
+    let start = data.len() as u32;
+    data.extend_from_slice(r.range(len)?);
+    Some(Const { start, len: len as u32 })
+}
+
+fn decode_op(r: &mut Reader, data: &mut Vec<u8>) -> Option<Op>
+{
+    let opcode = OpCode::try_from(r.byte()?).ok()?;
+
+    let op = match opcode
+    {
+        OpCode::Invalid => Op::Nop,
+        OpCode::Allocate_Stack => match r.byte()?
+        {
+            0 => {
+                let size = r.byte()? as usize;
+                Op::AllocateStack { value: constant(r, data, size)? }
+            },
+            1 => Op::PushVariable { variable: r.int()?, size: r.byte()? },
+            _ => return None
+        },
+        OpCode::Allocate_Heap => match r.byte()?
+        {
+            0 => Op::AllocateHeap { storage: r.int()?, size: r.int()? },
+            _ => {
+                r.int()?;
+                Op::Nop
+            }
+        },
+        OpCode::Deallocate_Stack => Op::DeallocateStack { size: r.int()? },
+        OpCode::FunctionPrologue => Op::Prologue,
+        OpCode::FunctionEpilogue => Op::Epilogue,
+        OpCode::Call => Op::Call { function: r.int()? as u32 },
+        OpCode::Return => Op::Return,
+        OpCode::Jump => Op::Jump { target: jump_target(r)? },
+        OpCode::JumpIfFalse => Op::JumpIfFalse { target: jump_target(r)?, condition: r.int()?, size: r.byte()? },
+        OpCode::Exit => Op::Exit,
+        OpCode::Mov => {
+            let dst = match r.byte()?
+            {
+                1 => Dst::Rbp(r.int()?),
+                2 => Dst::Deref(r.int()?),
+                _ => return None
+            };
+            let src = match r.byte()?
+            {
+                1 | 3 => Src::Rbp(r.int()?, r.byte()?),
+                2 => {
+                    let size = r.byte()? as usize;
+                    Src::Const(constant(r, data, size)?)
+                },
+                4 => Src::Abs(r.int()?, r.byte()?),
+                _ => return None
+            };
+            Op::Mov { dst, src }
+        },
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::DivRemainder |
+        OpCode::LeftBitShift | OpCode::RightBitShift | OpCode::BitAnd | OpCode::BitOr => {
+            Op::Binary { op: opcode as u8 - OpCode::Add as u8, a: r.int()?, b: r.int()?, result: r.int()?, size: r.byte()? }
+        },
+        OpCode::Compare => Op::Compare { a: r.int()?, b: r.int()?, size: r.byte()?, result: r.int()?, op: r.byte()? },
+        OpCode::Negate => Op::Negate { a: r.int()?, result: r.int()?, size: r.byte()? },
+        OpCode::Increment | OpCode::Decrement => Op::Unary { op: opcode as u8 - OpCode::Increment as u8, value: r.int()?, size: r.byte()? },
+        OpCode::ToPtr_ValueType => Op::ToPtrValueType { variable: r.int()?, result: r.int()? },
+        OpCode::ToPtr_RefType => Op::ToPtrRefType { variable: r.int()?, result: r.int()? },
+        OpCode::PtrGet => Op::PtrGet { pointer: r.int()?, result: r.int()?, size: r.byte()? },
+        OpCode::PtrSet => Op::PtrSet { pointer: r.int()?, value: r.int()?, size: r.byte()? },
+        OpCode::PtrShift => match r.byte()?
+        {
+            0 => Op::PtrShift { pointer: r.int()?, shift: r.int()? },
+            _ => {
+                let op = Op::PtrShiftBy { pointer: r.int()?, shift: r.int()?, additional: r.int()? };
+                r.byte()?; // size
+                op
+            }
+        },
+        OpCode::FieldAccess => Op::FieldAccess { base: r.int()?, field_offset: r.int()?, size: r.byte()?, is_getter: r.byte()?, result: r.int()? },
+        OpCode::AllocateRSPSaver | OpCode::RestoreRSPSaver | OpCode::DeallocateRSPSaver => Op::Legacy,
+        OpCode::Cast => Op::Cast { variable: r.int()?, variable_size: r.byte()?, result: r.int()?, result_size: r.byte()? },
+        OpCode::Section => {
+            let data = match r.byte()?
+            {
+                0 => {
+                    let size = r.int()?;
+                    Some(constant(r, data, size.max(0) as usize)?)
+                },
+                _ => None
+            };
+            // Next section opcode and mode
+            r.range(2)?;
+            Op::Section { data }
+        },
+        OpCode::VMCommand => {
+            let cmd = r.byte()?;
+            let count = r.int()?;
+
+            let mut arguments = Vec::new();
+            for _ in 0..count
+            {
+                arguments.push(VMCmdArgument {
+                    rbp: r.int()?,
+                    size_in_bytes: r.byte()?,
+                    type_index: r.byte()?
+                });
+            }
+            Op::VMCommand { cmd, arguments: arguments.into_boxed_slice() }
+        },
+        OpCode::Last => return None
+    };
+
+    Some(op)
+}
+
+impl Op
+{
+    pub fn target(&self) -> Option<u32>
+    {
+        match self
+        {
+            Op::Jump { target } | Op::JumpIfFalse { target, .. } | Op::CompareJumpIfFalse { target, .. } | Op::UnaryJump { target, .. } => Some(*target),
+            _ => None
+        }
+    }
+    pub fn target_mut(&mut self) -> Option<&mut u32>
+    {
+        match self
+        {
+            Op::Jump { target } | Op::JumpIfFalse { target, .. } | Op::CompareJumpIfFalse { target, .. } | Op::UnaryJump { target, .. } => Some(target),
+            _ => None
+        }
+    }
+}
+
+impl DecodedProgram
+{
+    // Offsets at and behind the last byte are the end of program
+    pub fn index_of(&self, offset: usize) -> Option<usize>
+    {
+        match self.indexes.get(offset.min(self.indexes.len() - 1))
+        {
+            Some(&NOT_INSTRUCTION) | None => None,
+            Some(index) => Some(*index as usize)
+        }
+    }
+
+    pub fn bytes(&self, value: Const) -> &[u8]
+    {
+        &self.data[value.start as usize..(value.start + value.len) as usize]
+    }
+}
+
+impl VM
+{
+    // Pre-decoded program skips per-instruction hooks, so it's used only when nobody needs them
+    pub fn can_run_decoded(&self) -> bool
+    {
+        match &self.program
+        {
+            Some(program) => self.watchpoints.is_empty() && !self.tracer.is_active() && self.replay.is_none() && program.index_of(self.byte_code.current).is_some(),
+            None => false
+        }
+    }
+
+    // Runs pre-decoded program from the current offset until exit, return to host or end of fuel
+    pub fn run_decoded(&mut self) -> RunStatus
+    {
+        let program = Rc::clone(self.program.as_ref().unwrap());
+        let mut index = program.index_of(self.byte_code.current).unwrap();
+
+        let reason = match catch_run(self, |vm| {
+            #[cfg(feature = "jit")]
+            if let Some(next) = crate::vm::jit::enter_host_call(vm, &program, index)
+            {
+                index = next;
+            }
+            execute(vm, &program, &mut index, None)
+        })
+        {
+            Ok(reason) => reason,
+            Err(payload) => {
+                let offset = program.offsets[index];
+                // Compiled code knows which instruction inside of it has failed
+                #[cfg(feature = "jit")]
+                let offset = self.jit.as_mut().and_then(|jit| jit.fault_offset.take()).unwrap_or(offset);
+                // History isn't kept on this path, crash dump gets at least the failed instruction
+                self.history.push(offset);
+                fail_run(self, Some(offset), payload)
+            }
+        };
+
+        self.byte_code.current = program.offsets[index];
+        self.run_status(reason)
+    }
+}
+
+// Returns why it has stopped before the end
+pub fn execute(vm: &mut VM, program: &DecodedProgram, index: &mut usize, frame_stack_pointer: Option<i32>) -> Option<StopReason>
+{
+    let end = program.ops.len();
+
+    while *index < end
+    {
+        if let Some(reason) = vm.stop_reason()
+        {
+            return Some(reason)
+        }
+
+        let next = execute_op(vm, program, *index);
+
+        // Nested run ends when the call it started returns
+        if let Some(stack_pointer) = frame_stack_pointer
+            && vm.memory.stack_pointer == stack_pointer && matches!(program.ops[*index], Op::Call { .. } | Op::Return)
+        {
+            *index = next;
+            return None;
+        }
+
+        *index = next;
+    }
+
+    None
+}
+
+// Runs instruction at given index and returns index of the next one
+#[inline(always)]
+pub fn execute_op(vm: &mut VM, program: &DecodedProgram, index: usize) -> usize
+{
+    let end = program.ops.len();
+    let mut next = index + 1;
+
+    match &program.ops[index]
+    {
+        Op::Nop => {},
+        Op::AllocateStack { value } => {
+            let address = vm.memory.allocate_stack(value.len as i32);
+            vm.memory.write_slice(address, program.bytes(*value));
+        },
+        Op::PushVariable { variable, size } => {
+            let variable_address = vm.memory.to_abs(*variable);
+            let address = vm.memory.allocate_stack(*size as i32);
+            vm.memory.copy(variable_address, address, *size as i32);
+        },
+        Op::AllocateHeap { storage, size } => {
+            let storage_address = vm.memory.to_abs(*storage);
+            let pointer = vm.memory.allocate_heap(*size);
+            vm.memory.write_int(storage_address, pointer);
+        },
+        Op::DeallocateStack { size } => vm.memory.deallocate_stack(*size),
+        Op::CheckStack { size } => vm.memory.check_stack(*size),
+        Op::Prologue => {
+            vm.memory.push_int(vm.memory.base_pointer);
+            vm.memory.base_pointer = vm.memory.stack_pointer;
+        },
+        Op::Epilogue => {
+            vm.memory.stack_pointer = vm.memory.base_pointer;
+            vm.memory.base_pointer = vm.memory.pop_int();
+        },
+        Op::Call { function } => {
+            // Return address is byte code offset, so frames look the same as for the usual interpreter
+            let offset = program.offsets[index];
+            vm.memory.push_int(offset as i32);
+
+            let function_info = &vm.module.table.functions[*function as usize];
+            if function_info.pointed_module == 0
+            {
+                next = program.index_of(function_info.pointed_opcode as usize)
+                    .unwrap_or_else(|| panic!("Function '{}' points to the middle of instruction", function_info.name));
+
+                #[cfg(feature = "jit")]
+                if let Some(jit_next) = crate::vm::jit::enter(vm, program, next)
+                {
+                    next = jit_next;
+                }
+            }
+            else
+            {
+                // Native code may call back into guest, which continues from the current offset
+                vm.byte_code.current = program.offsets[next];
+                winframework::call(vm, function_info);
+            }
+        },
+        Op::Return => {
+            let call_op_code_pointer = vm.memory.pop_int();
+            next = match call_op_code_pointer
+            {
+                HOST_RETURN_ADDRESS => end,
+                _ => program.index_of((call_op_code_pointer + 1 + 4) as usize)
+                    .unwrap_or_else(|| panic!("Invalid return address {call_op_code_pointer}"))
+            };
+        },
+        Op::Jump { target } => next = *target as usize,
+        Op::JumpIfFalse { target, condition, size } => {
+            let condition_address = vm.memory.to_abs(*condition);
+            if vm.memory.read(condition_address, *size as i32).iter().all(|b| *b == 0)
+            {
+                next = *target as usize;
+            }
+        },
+        Op::Exit => next = end,
+        Op::Mov { dst, src } => {
+            let dst_address = match dst
+            {
+                Dst::Rbp(offset) => vm.memory.to_abs(*offset),
+                Dst::Deref(offset) => vm.memory.read_int(vm.memory.to_abs(*offset)),
+            };
+            match src
+            {
+                Src::Rbp(offset, size) => vm.memory.copy(vm.memory.to_abs(*offset), dst_address, *size as i32),
+                Src::Const(value) => vm.memory.write_slice(dst_address, program.bytes(*value)),
+                Src::Abs(address, size) => vm.memory.copy(*address, dst_address, *size as i32),
+            }
+        },
+        Op::Binary { op, a, b, result, size } => run_binary(vm, *op, *a, *b, *result, *size),
+        Op::Unary { op, value, size } => {
+            let value = vm.memory.to_abs(*value);
+            UNARY_OPS[*op as usize](vm, value, *size);
+        },
+        Op::Compare { a, b, size, result, op } => {
+            let (a, b, result) = (vm.memory.to_abs(*a), vm.memory.to_abs(*b), vm.memory.to_abs(*result));
+            compare_at(vm, a, b, *size, result, *op);
+        },
+        Op::Negate { a, result, size } => {
+            let (a, result) = (vm.memory.to_abs(*a), vm.memory.to_abs(*result));
+            negate_at(vm, a, result, *size);
+        },
+        Op::ToPtrValueType { variable, result } => vm.memory.write_int(vm.memory.to_abs(*result), vm.memory.to_abs(*variable)),
+        Op::ToPtrRefType { variable, result } => {
+            let value_address = vm.memory.read_int(vm.memory.to_abs(*variable));
+            vm.memory.write_int(vm.memory.to_abs(*result), value_address);
+        },
+        Op::PtrGet { pointer, result, size } => {
+            let depointed_address = vm.memory.read_int(vm.memory.to_abs(*pointer));
+            vm.memory.copy(depointed_address, vm.memory.to_abs(*result), *size as i32);
+        },
+        Op::PtrSet { pointer, value, size } => {
+            let depointed_address = vm.memory.read_int(vm.memory.to_abs(*pointer));
+            vm.memory.copy(vm.memory.to_abs(*value), depointed_address, *size as i32);
+        },
+        Op::PtrShift { pointer, shift } => {
+            let pointer_address = vm.memory.to_abs(*pointer);
+            vm.memory.write_int(pointer_address, vm.memory.read_int(pointer_address) + shift);
+        },
+        Op::PtrShiftBy { pointer, shift, additional } => {
+            let pointer_address = vm.memory.to_abs(*pointer);
+            let shift_value = vm.memory.read_int(vm.memory.to_abs(*shift)) + additional;
+            vm.memory.write_int(pointer_address, vm.memory.read_int(pointer_address) + shift_value);
+        },
+        Op::FieldAccess { base, field_offset, size, is_getter, result } => {
+            let result = vm.memory.to_abs(*result);
+            field_access_at(vm, *base, *field_offset, *size, *is_getter, result);
+        },
+        Op::Legacy => panic!("Legacy method"),
+        Op::Cast { variable, variable_size, result, result_size } => {
+            let (variable, result) = (vm.memory.to_abs(*variable), vm.memory.to_abs(*result));
+            cast_at(vm, variable, *variable_size, result, *result_size);
+        },
+        Op::Section { data } => {
+            if let Some(data) = data
+            {
+                vm.memory.place_data_section(program.bytes(*data));
+            }
+        },
+        Op::VMCommand { cmd, arguments } => run_vm_command(vm, *cmd, arguments),
+        Op::CompareJumpIfFalse { a, b, size, result, op, target } => {
+            let (a, b, result) = (vm.memory.to_abs(*a), vm.memory.to_abs(*b), vm.memory.to_abs(*result));
+            compare_at(vm, a, b, *size, result, *op);
+            if vm.memory.bytes[result as usize] == 0
+            {
+                next = *target as usize;
+            }
+        },
+        Op::UnaryJump { op, value, size, target } => {
+            let value = vm.memory.to_abs(*value);
+            UNARY_OPS[*op as usize](vm, value, *size);
+            next = *target as usize;
+        },
+        Op::MovBinary { dst, src, mov_size, op, a, b, result, size } => {
+            vm.memory.copy(vm.memory.to_abs(*src), vm.memory.to_abs(*dst), *mov_size as i32);
+            run_binary(vm, *op, *a, *b, *result, *size);
+        },
+    }
+
+    next
+}
+
+#[inline(always)]
+pub fn run_binary(vm: &mut VM, op: u8, a: i32, b: i32, result: i32, size: u8)
+{
+    let (a, b, result) = (vm.memory.to_abs(a), vm.memory.to_abs(b), vm.memory.to_abs(result));
+    BINARY_OPS[op as usize](vm, a, b, result, size);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::loop_module;
+
+    #[test]
+    fn negative_jump_target_is_not_decoded()
+    {
+        let mut bytes = loop_module(3).managed_code.bytes.to_vec();
+        assert!(decode_program(&bytes).is_some());
+
+        // Jump back to the loop start is right before two Exit opcodes
+        let jump = bytes.len() - 2 - 4;
+        bytes[jump..jump + 4].copy_from_slice(&(-1i32).to_ne_bytes());
+        assert!(decode_program(&bytes).is_none());
+    }
+}
\ No newline at end of file
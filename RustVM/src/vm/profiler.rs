@@ -0,0 +1,291 @@
+﻿use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use crate::vm::opcodes::OpCode;
+use crate::vm::vm::VM;
+
+// Counts executed opcodes and attributes instructions and wall time to guest functions.
+// Calls are tracked as a tree of call paths, so the same data gives both per-function report and folded stacks.
+
+struct ProfileNode
+{
+    // None for code outside of functions
+    function: Option<usize>,
+    parent: usize,
+    children: HashMap<usize, usize>,
+
+    calls: u64,
+    instructions: u64,
+    // Time spent in this call path, excluding callees
+    time: Duration,
+}
+
+pub struct Profiler
+{
+    opcode_counts: [u64; 256],
+    nodes: Vec<ProfileNode>,
+    current: usize,
+    last_switch: Instant,
+    started: bool,
+}
+
+#[derive(Default, Clone)]
+struct FunctionStats
+{
+    calls: u64,
+    self_instructions: u64,
+    total_instructions: u64,
+    self_time: Duration,
+    total_time: Duration,
+}
+
+const ROOT: usize = 0;
+
+impl Profiler
+{
+    pub fn new() -> Self
+    {
+        Self {
+            opcode_counts: [0; 256],
+            nodes: vec![ProfileNode {
+                function: None,
+                parent: ROOT,
+                children: HashMap::new(),
+                calls: 1,
+                instructions: 0,
+                time: Duration::ZERO,
+            }],
+            current: ROOT,
+            last_switch: Instant::now(),
+            started: false,
+        }
+    }
+
+    // Called right before instruction at given offset is executed
+    pub fn on_instruction(&mut self, vm: &VM, offset: usize)
+    {
+        // Execution may start right inside function (entry point or host call)
+        if !self.started
+        {
+            self.started = true;
+            if let Some(function_index) = vm.module.function_at(offset)
+            {
+                self.enter(function_index);
+            }
+        }
+
+        let byte_opcode = vm.byte_code.bytes[offset];
+        self.opcode_counts[byte_opcode as usize] += 1;
+        self.nodes[self.current].instructions += 1;
+
+        if byte_opcode == OpCode::Call as u8
+        {
+            let function_index = i32::from_ne_bytes(vm.byte_code.bytes[offset + 1..offset + 5].try_into().unwrap()) as usize;
+            if vm.module.table.functions[function_index].pointed_module == 0
+            {
+                self.enter(function_index);
+            }
+        }
+        else if byte_opcode == OpCode::Return as u8 && self.current != ROOT
+        {
+            self.switch_to(self.nodes[self.current].parent);
+        }
+    }
+
+    fn enter(&mut self, function_index: usize)
+    {
+        let child = match self.nodes[self.current].children.get(&function_index)
+        {
+            Some(child) => *child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(ProfileNode {
+                    function: Some(function_index),
+                    parent: self.current,
+                    children: HashMap::new(),
+                    calls: 0,
+                    instructions: 0,
+                    time: Duration::ZERO,
+                });
+                self.nodes[self.current].children.insert(function_index, child);
+                child
+            }
+        };
+
+        self.nodes[child].calls += 1;
+        self.switch_to(child);
+    }
+
+    fn switch_to(&mut self, node: usize)
+    {
+        let now = Instant::now();
+        self.nodes[self.current].time += now - self.last_switch;
+        self.last_switch = now;
+        self.current = node;
+    }
+
+    // Closes time of currently running function, should be called once execution is over
+    pub fn finish(&mut self)
+    {
+        self.switch_to(self.current);
+    }
+
+    fn function_name(vm: &VM, function: Option<usize>) -> String
+    {
+        match function
+        {
+            Some(function_index) => vm.module.function_full_name(function_index),
+            None => String::from("<startup>")
+        }
+    }
+
+    fn path(&self, node: usize) -> Vec<usize>
+    {
+        let mut path = vec![node];
+        let mut node = node;
+        while node != ROOT
+        {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    fn function_stats(&self) -> HashMap<Option<usize>, FunctionStats>
+    {
+        let mut stats: HashMap<Option<usize>, FunctionStats> = HashMap::new();
+
+        // Totals of call path include its subtree
+        let mut total_instructions = vec![0u64; self.nodes.len()];
+        let mut total_time = vec![Duration::ZERO; self.nodes.len()];
+        for i in (0..self.nodes.len()).rev()
+        {
+            total_instructions[i] += self.nodes[i].instructions;
+            total_time[i] += self.nodes[i].time;
+            if i != ROOT
+            {
+                let parent = self.nodes[i].parent;
+                let (instructions, time) = (total_instructions[i], total_time[i]);
+                total_instructions[parent] += instructions;
+                total_time[parent] += time;
+            }
+        }
+
+        for (i, node) in self.nodes.iter().enumerate()
+        {
+            let function_stats = stats.entry(node.function).or_default();
+            function_stats.calls += node.calls;
+            function_stats.self_instructions += node.instructions;
+            function_stats.self_time += node.time;
+
+            // Recursive calls are already counted in the outermost one
+            let is_recursive = i != ROOT && self.path(self.nodes[i].parent).iter().any(|p| self.nodes[*p].function == node.function);
+            if !is_recursive
+            {
+                function_stats.total_instructions += total_instructions[i];
+                function_stats.total_time += total_time[i];
+            }
+        }
+
+        stats
+    }
+
+    pub fn report(&self, vm: &VM) -> String
+    {
+        let total_instructions: u64 = self.opcode_counts.iter().sum();
+        let total_time: Duration = self.nodes.iter().map(|n| n.time).sum();
+        let mut text = String::new();
+
+        writeln!(text, "Profile: {} instructions in {:.3} ms", total_instructions, total_time.as_secs_f64() * 1000.0).unwrap();
+        writeln!(text).unwrap();
+
+        let mut functions: Vec<(Option<usize>, FunctionStats)> = self.function_stats().into_iter().collect();
+        functions.sort_by(|a, b| b.1.self_instructions.cmp(&a.1.self_instructions).then(b.1.total_instructions.cmp(&a.1.total_instructions)));
+
+        writeln!(text, "{:>10} {:>12} {:>12} {:>10} {:>10}  function", "calls", "self instr", "total instr", "self ms", "total ms").unwrap();
+        for (function, stats) in &functions
+        {
+            writeln!(text, "{:>10} {:>12} {:>12} {:>10.3} {:>10.3}  {}", stats.calls, stats.self_instructions, stats.total_instructions,
+                stats.self_time.as_secs_f64() * 1000.0, stats.total_time.as_secs_f64() * 1000.0, Self::function_name(vm, *function)).unwrap();
+        }
+        writeln!(text).unwrap();
+
+        let mut opcodes: Vec<(usize, u64)> = self.opcode_counts.iter().copied().enumerate().filter(|(_, count)| *count > 0).collect();
+        opcodes.sort_by_key(|(_, count)| Reverse(*count));
+
+        writeln!(text, "{:>12} {:>7}  opcode", "count", "%").unwrap();
+        for (byte_opcode, count) in opcodes
+        {
+            let name = match OpCode::try_from(byte_opcode as u8)
+            {
+                Ok(opcode) => format!("{:?}", opcode),
+                Err(_) => format!("<invalid {}>", byte_opcode)
+            };
+            writeln!(text, "{:>12} {:>6.2}%  {}", count, count as f64 * 100.0 / total_instructions as f64, name).unwrap();
+        }
+
+        text
+    }
+
+    // One line per call path: "Type.A;Type.B <instructions>", input format of flamegraph.pl and similar tools
+    pub fn folded_stacks(&self, vm: &VM) -> String
+    {
+        let mut text = String::new();
+
+        for (i, node) in self.nodes.iter().enumerate()
+        {
+            if node.instructions == 0
+            {
+                continue;
+            }
+
+            let names: Vec<String> = self.path(i).iter().map(|n| Self::function_name(vm, self.nodes[*n].function)).collect();
+            writeln!(text, "{} {}", names.join(";"), node.instructions).unwrap();
+        }
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    fn profile(iterations: i32) -> (VM, Profiler)
+    {
+        let mut vm = VM::new(call_module(iterations));
+        let mut profiler = Some(Profiler::new());
+        run_instrumented(&mut vm, &mut None, &mut profiler, &mut None);
+
+        let mut profiler = profiler.unwrap();
+        profiler.finish();
+        (vm, profiler)
+    }
+
+    #[test]
+    fn instructions_are_attributed_to_call_paths()
+    {
+        let (vm, profiler) = profile(3);
+        assert_eq!(profiler.folded_stacks(&vm), "<startup> 41\n<startup>;Program.Mix 21\n");
+
+        let stats = profiler.function_stats();
+        let mix = &stats[&Some(0)];
+        assert_eq!((mix.calls, mix.self_instructions, mix.total_instructions), (3, 21, 21));
+        let startup = &stats[&None];
+        assert_eq!((startup.self_instructions, startup.total_instructions), (41, 62));
+    }
+
+    #[test]
+    fn report_counts_opcodes()
+    {
+        let (vm, profiler) = profile(3);
+        let report = profiler.report(&vm);
+        assert!(report.starts_with("Profile: 62 instructions"));
+        assert!(report.lines().any(|line| line.trim_start().starts_with("3 ") && line.ends_with("  Call")));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,134 @@
+﻿use std::time::{SystemTime, UNIX_EPOCH};
+
+// xoshiro256** seeded through splitmix64. The same seed always gives the same sequence on every platform.
+pub struct Random
+{
+    state: [u64; 4],
+}
+
+impl Random
+{
+    pub fn new(seed: u64) -> Self
+    {
+        let mut random = Self {
+            state: [0; 4]
+        };
+        random.reseed(seed);
+        random
+    }
+
+    pub fn from_time() -> Self
+    {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Self::new(nanos)
+    }
+
+    // Continues the sequence from saved state
+    pub fn from_state(state: [u64; 4]) -> Self
+    {
+        Self {
+            state
+        }
+    }
+
+    pub fn state(&self) -> [u64; 4]
+    {
+        self.state
+    }
+
+    pub fn reseed(&mut self, seed: u64)
+    {
+        let mut splitmix = seed;
+        for i in 0..4
+        {
+            splitmix = splitmix.wrapping_add(0x9E3779B97F4A7C15);
+
+            let mut z = splitmix;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            self.state[i] = z ^ (z >> 31);
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64
+    {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+
+    // Uniform value in [min, max). Rejection sampling avoids modulo bias.
+    pub fn next_range(&mut self, min: i64, max: i64) -> i64
+    {
+        if max <= min
+        {
+            return min
+        }
+
+        let span = max.wrapping_sub(min) as u64;
+        let zone = u64::MAX - (u64::MAX % span);
+
+        loop
+        {
+            let value = self.next_u64();
+            if value < zone
+            {
+                return min.wrapping_add((value % span) as i64)
+            }
+        }
+    }
+
+    pub fn fill(&mut self, bytes: &mut [u8])
+    {
+        for chunk in bytes.chunks_mut(8)
+        {
+            let value = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn seed_gives_fixed_sequence()
+    {
+        let mut random = Random::new(42);
+        assert_eq!(random.next_u64(), 0x15780b2e0c2ec716);
+        assert_eq!(random.next_u64(), 0x6104d9866d113a7e);
+
+        // Saved state continues the same sequence
+        let mut restored = Random::from_state(random.state());
+        assert_eq!(restored.next_u64(), 0xae17533239e499a1);
+
+        random.reseed(42);
+        let mut bytes = [0; 10];
+        random.fill(&mut bytes);
+        assert_eq!(bytes[..8], 0x15780b2e0c2ec716u64.to_le_bytes());
+        assert_eq!(bytes[8..], 0x6104d9866d113a7eu64.to_le_bytes()[..2]);
+    }
+
+    #[test]
+    fn range_stays_in_bounds()
+    {
+        let mut random = Random::new(7);
+        for _ in 0..1000
+        {
+            assert!((-3..4).contains(&random.next_range(-3, 4)));
+        }
+        assert_eq!(random.next_range(5, 5), 5);
+        assert_eq!(random.next_range(i64::MIN, i64::MIN + 1), i64::MIN);
+    }
+}
\ No newline at end of file
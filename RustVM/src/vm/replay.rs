@@ -0,0 +1,412 @@
+﻿use std::fs;
+use std::io;
+use std::path::Path;
+use crate::vm::binary_file::BinaryFile;
+use crate::vm::memory::Memory;
+use crate::vm::snapshot::Snapshot;
+use crate::vm::vm::VM;
+
+// Record and replay of nondeterministic inputs.
+// Recording starts from snapshot of VM and logs what every nondeterministic VM command (time, random, environment,
+// files, sleep) and native call has changed in memory. Replay restores the snapshot and applies logged changes
+// instead of running commands, so the program gets the same inputs and host isn't touched.
+// Every event keeps the number of instructions executed before it and hash of their offsets,
+// replay fails as soon as the program takes another path.
+// Instructions are run one by one in both modes, pre-decoded program and JIT are not used.
+// Windows calling guest back from their own thread are not recorded.
+// Guest code run by native call inside an event is part of that event: its instructions aren't counted
+// and its own events aren't logged, because replay applies the outer event without running it.
+
+// Log layout (little-endian): "ARPL" [version: int] [snapshot size: int] [snapshot] [event count: int] (event)... [instructions: u64] [hash: u64]
+// Event: [offset: uint] [instructions: u64] [hash: u64] [rsp: int] [rbp: int] [heap: int] [change count: int] ([address: int] [size: int] [bytes])...
+
+const MAGIC: &[u8; 4] = b"ARPL";
+const VERSION: i32 = 1;
+
+const HASH_START: u64 = 0xcbf29ce484222325;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplayMode
+{
+    Record,
+    Replay,
+}
+
+// Changes made by one nondeterministic instruction
+struct Event
+{
+    offset: u32,
+    instructions: u64,
+    hash: u64,
+    stack_pointer: i32,
+    base_pointer: i32,
+    heap_pointer: i32,
+    changes: Vec<(i32, Vec<u8>)>,
+}
+
+pub struct Replay
+{
+    pub mode: ReplayMode,
+    snapshot: Vec<u8>,
+    events: Vec<Event>,
+    // Next event to replay
+    next: usize,
+
+    // Executed instructions and FNV-1a hash of their offsets
+    instructions: u64,
+    hash: u64,
+    offset: usize,
+    // Recorded events which are running now
+    depth: u32,
+
+    // Of the whole recording, checked when replay ends
+    total_instructions: u64,
+    total_hash: u64,
+}
+
+impl Replay
+{
+    fn new(mode: ReplayMode, snapshot: Vec<u8>) -> Self
+    {
+        Self {
+            mode,
+            snapshot,
+            events: Vec::new(),
+            next: 0,
+            instructions: 0,
+            hash: HASH_START,
+            offset: 0,
+            depth: 0,
+            total_instructions: 0,
+            total_hash: HASH_START,
+        }
+    }
+
+    #[inline]
+    pub fn on_instruction(&mut self, offset: usize)
+    {
+        if self.depth > 0
+        {
+            return
+        }
+
+        self.instructions += 1;
+        for byte in (offset as u32).to_le_bytes()
+        {
+            self.hash ^= byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+        self.offset = offset;
+    }
+
+    fn serialize(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.snapshot.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&self.snapshot);
+
+        bytes.extend_from_slice(&(self.events.len() as i32).to_le_bytes());
+        for event in &self.events
+        {
+            bytes.extend_from_slice(&event.offset.to_le_bytes());
+            bytes.extend_from_slice(&event.instructions.to_le_bytes());
+            bytes.extend_from_slice(&event.hash.to_le_bytes());
+            bytes.extend_from_slice(&event.stack_pointer.to_le_bytes());
+            bytes.extend_from_slice(&event.base_pointer.to_le_bytes());
+            bytes.extend_from_slice(&event.heap_pointer.to_le_bytes());
+
+            bytes.extend_from_slice(&(event.changes.len() as i32).to_le_bytes());
+            for (address, changed) in &event.changes
+            {
+                bytes.extend_from_slice(&address.to_le_bytes());
+                bytes.extend_from_slice(&(changed.len() as i32).to_le_bytes());
+                bytes.extend_from_slice(changed);
+            }
+        }
+
+        bytes.extend_from_slice(&self.instructions.to_le_bytes());
+        bytes.extend_from_slice(&self.hash.to_le_bytes());
+
+        bytes
+    }
+
+    fn deserialize(buffer: &[u8]) -> Self
+    {
+        let mut file = BinaryFile::new(buffer);
+
+        if file.bytes.get(0..4) != Some(MAGIC.as_slice())
+        {
+            panic!("File is not a replay log")
+        }
+        file.next_range(4);
+
+        let version = next_int(&mut file);
+        if version != VERSION
+        {
+            panic!("Unsupported replay log version {version}")
+        }
+
+        let snapshot_size = next_int(&mut file);
+        let mut replay = Self::new(ReplayMode::Replay, file.next_range(snapshot_size as usize).to_vec());
+
+        let count = next_int(&mut file);
+        replay.events = (0..count).map(|_| Event {
+            offset: next_uint(&mut file),
+            instructions: next_u64(&mut file),
+            hash: next_u64(&mut file),
+            stack_pointer: next_int(&mut file),
+            base_pointer: next_int(&mut file),
+            heap_pointer: next_int(&mut file),
+            changes: {
+                let count = next_int(&mut file);
+                (0..count).map(|_| {
+                    let address = next_int(&mut file);
+                    let size = next_int(&mut file);
+                    (address, file.next_range(size as usize).to_vec())
+                }).collect()
+            },
+        }).collect();
+
+        replay.total_instructions = next_u64(&mut file);
+        replay.total_hash = next_u64(&mut file);
+        replay
+    }
+}
+
+// Unlike byte code, log is read the same way on every machine
+fn next_int(file: &mut BinaryFile<&[u8]>) -> i32
+{
+    i32::from_le_bytes(file.next_range(4).try_into().unwrap())
+}
+
+fn next_uint(file: &mut BinaryFile<&[u8]>) -> u32
+{
+    u32::from_le_bytes(file.next_range(4).try_into().unwrap())
+}
+
+fn next_u64(file: &mut BinaryFile<&[u8]>) -> u64
+{
+    u64::from_le_bytes(file.next_range(8).try_into().unwrap())
+}
+
+impl VM
+{
+    // Recording starts from the current state
+    pub fn start_recording(&mut self)
+    {
+        self.replay = Some(Box::from(Replay::new(ReplayMode::Record, Snapshot::capture(self).serialize())));
+    }
+
+    pub fn save_recording(&self, path: &Path) -> io::Result<()>
+    {
+        match &self.replay
+        {
+            Some(replay) if replay.mode == ReplayMode::Record => fs::write(path, replay.serialize()),
+            _ => panic!("VM is not recording")
+        }
+    }
+
+    // Restores state the recording has started from
+    pub fn start_replay(&mut self, path: &Path)
+    {
+        let buffer = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        let replay = Replay::deserialize(&buffer);
+
+        Snapshot::deserialize(&replay.snapshot).restore(self);
+        self.replay = Some(Box::from(replay));
+    }
+
+    // Checks that replayed program has run the same instructions as the recorded one
+    pub fn finish_replay(&self) -> Result<u64, String>
+    {
+        let replay = match &self.replay
+        {
+            Some(replay) if replay.mode == ReplayMode::Replay => replay,
+            _ => panic!("VM is not replaying")
+        };
+
+        if replay.next != replay.events.len()
+        {
+            return Err(format!("Only {} of {} recorded events were replayed", replay.next, replay.events.len()))
+        }
+        if replay.instructions != replay.total_instructions || replay.hash != replay.total_hash
+        {
+            return Err(format!("Replay has run {} instructions, recording has {} (or they differ)", replay.instructions, replay.total_instructions))
+        }
+
+        Ok(replay.instructions)
+    }
+}
+
+// Runs instruction with nondeterministic result. While recording its changes are saved,
+// while replaying saved changes are applied instead of running it.
+pub fn run_nondeterministic(vm: &mut VM, run: impl FnOnce(&mut VM))
+{
+    let mode = match &vm.replay
+    {
+        Some(replay) if replay.depth > 0 => return run(vm),
+        Some(replay) => replay.mode,
+        None => return run(vm)
+    };
+
+    match mode
+    {
+        ReplayMode::Record => {
+            let before = vm.memory.bytes;
+            vm.replay.as_mut().unwrap().depth += 1;
+            run(vm);
+
+            let replay = vm.replay.as_mut().unwrap();
+            replay.depth -= 1;
+            replay.events.push(Event {
+                offset: replay.offset as u32,
+                instructions: replay.instructions,
+                hash: replay.hash,
+                stack_pointer: vm.memory.stack_pointer,
+                base_pointer: vm.memory.base_pointer,
+                heap_pointer: vm.memory.heap_pointer,
+                changes: changes(&before, &vm.memory),
+            });
+        },
+        ReplayMode::Replay => {
+            let replay = vm.replay.as_mut().unwrap();
+            let event = match replay.events.get(replay.next)
+            {
+                Some(event) => event,
+                None => panic!("Replay diverged: instruction at 0x{:04X} is not in recording", replay.offset)
+            };
+            if event.offset as usize != replay.offset || event.instructions != replay.instructions || event.hash != replay.hash
+            {
+                panic!("Replay diverged: recorded instruction at 0x{:04X} after {} instructions, but 0x{:04X} is run after {}",
+                    event.offset, event.instructions, replay.offset, replay.instructions)
+            }
+            replay.next += 1;
+
+            for (address, changed) in &event.changes
+            {
+                vm.memory.write_slice(*address, changed);
+            }
+            vm.memory.stack_pointer = event.stack_pointer;
+            vm.memory.base_pointer = event.base_pointer;
+            vm.memory.heap_pointer = event.heap_pointer;
+        }
+    }
+}
+
+// Ranges of bytes which differ
+fn changes(before: &[u8], memory: &Memory) -> Vec<(i32, Vec<u8>)>
+{
+    let mut changes = Vec::new();
+    let mut i = 0;
+
+    while i < before.len()
+    {
+        if before[i] == memory.bytes[i]
+        {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < before.len() && before[i] != memory.bytes[i]
+        {
+            i += 1;
+        }
+        changes.push((start as i32, memory.bytes[start..i].to_vec()));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::env;
+    use std::path::PathBuf;
+    use super::*;
+    use crate::vm::bench::loop_module;
+    use crate::vm::binary_file::SharedBytes;
+    use crate::vm::clock::VirtualClock;
+    use crate::vm::compiled_module::{CompiledModule, ManagedCode};
+    use crate::vm::opcodes::{OpCode, VMCommand_Cmd};
+
+    // Reads time into rbp+0. Extra allocations are made before or after that.
+    fn time_module(before: usize, after: usize) -> CompiledModule
+    {
+        let allocate = [OpCode::Allocate_Stack as u8, 0, 1, 0];
+        let mut code = vec![OpCode::Section as u8, 0, 0, 0, 0, 0, 0, 0];
+        code.extend_from_slice(&[OpCode::Allocate_Stack as u8, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        code.extend(allocate.repeat(before));
+
+        code.extend_from_slice(&[OpCode::VMCommand as u8, VMCommand_Cmd::TimeNow as u8]);
+        code.extend_from_slice(&1i32.to_ne_bytes());
+        code.extend_from_slice(&0i32.to_ne_bytes());
+        code.extend_from_slice(&[8, 4]);
+
+        code.extend(allocate.repeat(after));
+        code.extend_from_slice(&[OpCode::Exit as u8, OpCode::Exit as u8]);
+
+        CompiledModule { managed_code: ManagedCode { bytes: SharedBytes::from(code) }, ..loop_module(0) }
+    }
+
+    fn record(name: &str) -> PathBuf
+    {
+        let path = env::temp_dir().join(format!("rustvm-{name}-{}.arpl", std::process::id()));
+        let mut vm = VM::new(time_module(0, 0));
+        vm.clock = Box::from(VirtualClock::new(1000));
+        vm.start_recording();
+        vm.run();
+        vm.save_recording(&path).unwrap();
+        path
+    }
+
+    // Log is read and removed before running
+    fn replay(path: &Path, module: CompiledModule) -> VM
+    {
+        let mut vm = VM::new(module);
+        vm.clock = Box::from(VirtualClock::new(5000));
+        vm.start_replay(path);
+        fs::remove_file(path).unwrap();
+        vm.run();
+        vm
+    }
+
+    fn read_long(vm: &VM) -> i64
+    {
+        i64::from_ne_bytes(vm.memory.read_array(0))
+    }
+
+    #[test]
+    fn replay_gives_recorded_inputs()
+    {
+        let path = record("replay-same");
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[4..8], VERSION.to_le_bytes());
+        let log = Replay::deserialize(&bytes);
+        assert_eq!((log.events.len(), log.events[0].changes.len(), log.total_instructions), (1, 1, 4));
+
+        let vm = replay(&path, time_module(0, 0));
+        assert_eq!(read_long(&vm), 1000);
+        assert_eq!(vm.finish_replay(), Ok(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "Replay diverged: recorded instruction at 0x0013 after 3 instructions, but 0x0017 is run after 4")]
+    fn other_path_before_event_diverges()
+    {
+        let path = record("replay-before");
+        replay(&path, time_module(1, 0));
+    }
+
+    #[test]
+    fn other_path_after_events_is_found_at_end()
+    {
+        let path = record("replay-after");
+        let vm = replay(&path, time_module(0, 1));
+        assert_eq!(read_long(&vm), 1000);
+        assert_eq!(vm.finish_replay(), Err(String::from("Replay has run 5 instructions, recording has 4 (or they differ)")));
+    }
+}
\ No newline at end of file
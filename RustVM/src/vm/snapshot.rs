@@ -0,0 +1,254 @@
+﻿use std::fs;
+use std::io;
+use std::path::Path;
+use crate::vm::binary_file::BinaryFile;
+use crate::vm::crash_dump::InstructionHistory;
+use crate::vm::file_sandbox::SavedFile;
+use crate::vm::random::Random;
+use crate::vm::vm::VM;
+
+// Snapshot layout (little-endian): "ASNP" [version: int] [module hash: u64] [pc: uint] [rsp: int] [rbp: int] [heap: int] [data section size: int]
+//                  [args address: int] [random state: 4 x u64] [memory size: int] [memory bytes]
+//                  [next handle: int] [file count: int] ([handle: int] [mode: int] [guest path: string] [position: u64])...
+// Snapshot is taken between instructions, for example after run has stopped because of fuel or timeout.
+// Clock is not saved, restored program continues with time of the new VM.
+
+const MAGIC: &[u8; 4] = b"ASNP";
+const VERSION: i32 = 1;
+
+pub struct Snapshot
+{
+    pub module_hash: u64,
+    pub pc: u32,
+    pub stack_pointer: i32,
+    pub base_pointer: i32,
+    pub heap_pointer: i32,
+    pub data_section_size: i32,
+    pub args_address: i32,
+    pub random_state: [u64; 4],
+    pub memory: Vec<u8>,
+    pub next_handle: i32,
+    pub files: Vec<SavedFile>,
+}
+
+impl Snapshot
+{
+    pub fn capture(vm: &VM) -> Self
+    {
+        Self {
+            module_hash: vm.module.hash,
+            pc: vm.byte_code.current as u32,
+            stack_pointer: vm.memory.stack_pointer,
+            base_pointer: vm.memory.base_pointer,
+            heap_pointer: vm.memory.heap_pointer,
+            data_section_size: vm.memory.data_section_size,
+            args_address: vm.args_address,
+            random_state: vm.random.state(),
+            memory: vm.memory.bytes.to_vec(),
+            next_handle: vm.files.next_handle(),
+            files: vm.files.save(),
+        }
+    }
+
+    // VM must be made for the same module, files are opened again in its sandbox
+    pub fn restore(&self, vm: &mut VM)
+    {
+        if vm.module.hash != self.module_hash
+        {
+            panic!("Snapshot was made for module {:016x}, but VM runs module {:016x}", self.module_hash, vm.module.hash)
+        }
+        if self.memory.len() != vm.memory.bytes.len()
+        {
+            panic!("Snapshot has {} bytes of memory, but VM has {}", self.memory.len(), vm.memory.bytes.len())
+        }
+
+        vm.byte_code.current = self.pc as usize;
+        vm.memory.bytes.copy_from_slice(&self.memory);
+        vm.memory.stack_pointer = self.stack_pointer;
+        vm.memory.base_pointer = self.base_pointer;
+        vm.memory.heap_pointer = self.heap_pointer;
+        vm.memory.data_section_size = self.data_section_size;
+        vm.args_address = self.args_address;
+        vm.random = Random::from_state(self.random_state);
+        vm.files.restore(&self.files, self.next_handle);
+        vm.history = InstructionHistory::new();
+    }
+
+    pub fn serialize(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.module_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend_from_slice(&self.stack_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.base_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.heap_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.data_section_size.to_le_bytes());
+        bytes.extend_from_slice(&self.args_address.to_le_bytes());
+        for value in &self.random_state
+        {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.memory.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+
+        bytes.extend_from_slice(&self.next_handle.to_le_bytes());
+        bytes.extend_from_slice(&(self.files.len() as i32).to_le_bytes());
+        for file in &self.files
+        {
+            bytes.extend_from_slice(&file.handle.to_le_bytes());
+            bytes.extend_from_slice(&file.mode.to_le_bytes());
+            bytes.extend_from_slice(&(file.guest_path.len() as i32).to_le_bytes());
+            bytes.extend_from_slice(file.guest_path.as_bytes());
+            bytes.extend_from_slice(&file.position.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn deserialize(buffer: &[u8]) -> Self
+    {
+        let mut file = BinaryFile::new(buffer);
+
+        if file.bytes.get(0..4) != Some(MAGIC.as_slice())
+        {
+            panic!("File is not a snapshot")
+        }
+        file.next_range(4);
+
+        let version = next_int(&mut file);
+        if version != VERSION
+        {
+            panic!("Unsupported snapshot version {version}")
+        }
+
+        Self {
+            module_hash: next_u64(&mut file),
+            pc: next_uint(&mut file),
+            stack_pointer: next_int(&mut file),
+            base_pointer: next_int(&mut file),
+            heap_pointer: next_int(&mut file),
+            data_section_size: next_int(&mut file),
+            args_address: next_int(&mut file),
+            random_state: [next_u64(&mut file), next_u64(&mut file), next_u64(&mut file), next_u64(&mut file)],
+            memory: {
+                let count = next_int(&mut file);
+                file.next_range(count as usize).to_vec()
+            },
+            next_handle: next_int(&mut file),
+            files: {
+                let count = next_int(&mut file);
+                (0..count).map(|_| SavedFile {
+                    handle: next_int(&mut file),
+                    mode: next_int(&mut file),
+                    guest_path: next_string(&mut file),
+                    position: next_u64(&mut file),
+                }).collect()
+            },
+        }
+    }
+}
+
+// Unlike byte code, snapshot is read the same way on every machine
+fn next_int(file: &mut BinaryFile<&[u8]>) -> i32
+{
+    i32::from_le_bytes(file.next_range(4).try_into().unwrap())
+}
+
+fn next_uint(file: &mut BinaryFile<&[u8]>) -> u32
+{
+    u32::from_le_bytes(file.next_range(4).try_into().unwrap())
+}
+
+fn next_u64(file: &mut BinaryFile<&[u8]>) -> u64
+{
+    u64::from_le_bytes(file.next_range(8).try_into().unwrap())
+}
+
+fn next_string(file: &mut BinaryFile<&[u8]>) -> String
+{
+    let length = next_int(file);
+    String::from_utf8(file.next_range(length as usize).to_vec()).unwrap()
+}
+
+impl VM
+{
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()>
+    {
+        fs::write(path, Snapshot::capture(self).serialize())
+    }
+
+    pub fn restore_snapshot(&mut self, path: &Path)
+    {
+        let buffer = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        Snapshot::deserialize(&buffer).restore(self);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::env;
+    use std::io::{Seek, SeekFrom};
+    use super::*;
+    use crate::vm::bench::loop_module;
+    use crate::vm::file_sandbox::FileSandbox;
+    use crate::vm::fuel::RunStatus;
+
+    // VM with file sandbox in given folder, it has "data.txt" in it
+    fn sandboxed_vm(folder: &Path) -> VM
+    {
+        let mut vm = VM::new(loop_module(10_000));
+        vm.files = FileSandbox::new(Some(folder.to_path_buf()), true, false);
+        vm
+    }
+
+    #[test]
+    fn restored_run_continues_the_same_way()
+    {
+        let folder = env::temp_dir().join(format!("rustvm-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("data.txt"), "0123456789").unwrap();
+
+        let mut vm = sandboxed_vm(&folder);
+        vm.random = Random::new(7);
+        let file = vm.files.open("data.txt", 0, false).unwrap();
+        let handle = vm.files.add_handle(file, "data.txt", 0);
+        vm.files.get_handle(handle).unwrap().seek(SeekFrom::Start(4)).unwrap();
+        vm.set_fuel(Some(5000));
+        assert!(matches!(vm.run(), RunStatus::OutOfFuel));
+
+        let bytes = Snapshot::capture(&vm).serialize();
+        assert_eq!(bytes[4..8], VERSION.to_le_bytes());
+        assert_eq!(Snapshot::deserialize(&bytes).serialize(), bytes);
+
+        let mut restored = sandboxed_vm(&folder);
+        Snapshot::deserialize(&bytes).restore(&mut restored);
+        assert_eq!(restored.byte_code.current, vm.byte_code.current);
+        assert_eq!(restored.random.next_u64(), vm.random.next_u64());
+        assert_eq!(restored.files.get_handle(handle).unwrap().stream_position().unwrap(), 4);
+        assert_eq!(restored.files.next_handle(), handle + 1);
+
+        vm.set_fuel(None);
+        vm.run();
+        restored.run();
+        assert_eq!(restored.memory.bytes.to_vec(), vm.memory.bytes.to_vec());
+        assert_eq!((restored.memory.stack_pointer, restored.memory.base_pointer), (vm.memory.stack_pointer, vm.memory.base_pointer));
+
+        drop((vm, restored));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Snapshot was made for module")]
+    fn snapshot_of_other_module_is_rejected()
+    {
+        let vm = VM::new(loop_module(10));
+        let mut snapshot = Snapshot::deserialize(&Snapshot::capture(&vm).serialize());
+        snapshot.module_hash += 1;
+        snapshot.restore(&mut VM::new(loop_module(10)));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,258 @@
+﻿use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use crate::vm::crash_dump::report_fatal_error;
+use crate::vm::functions::HOST_RETURN_ADDRESS;
+use crate::vm::opcodes::OpCode;
+use crate::vm::vm::VM;
+
+pub struct GuestFrame
+{
+    // Byte code offset of current instruction (for callers it is offset of their Call)
+    pub offset: usize,
+    pub base_pointer: i32,
+    pub function_index: Option<usize>,
+}
+
+pub struct StackTrace
+{
+    pub frames: Vec<GuestFrame>,
+    pub names: Vec<String>,
+    // Source positions of frames, if module has debug info
+    pub locations: Vec<Option<String>>,
+}
+
+// Deeper frames than this are considered as corrupted stack
+const MAX_FRAMES: usize = 256;
+
+// Reconstructs guest call stack from saved base pointers and return addresses.
+// Frame layout after Call and FunctionPrologue: [return address (offset of Call)][saved rbp] <- rbp
+pub fn walk_stack(vm: &VM, offset: usize) -> Vec<GuestFrame>
+{
+    let memory = &vm.memory;
+    let mut frames = Vec::new();
+
+    let mut offset = offset;
+    let mut base_pointer = memory.base_pointer;
+
+    frames.push(GuestFrame {
+        offset,
+        base_pointer,
+        function_index: vm.module.function_at(offset),
+    });
+
+    // Just called function has no own frame yet and returning function has already released it,
+    // return address is on top of the stack for both of them
+    let current_opcode = vm.byte_code.bytes.get(offset).copied().unwrap_or(0);
+    if current_opcode == OpCode::FunctionPrologue as u8 || current_opcode == OpCode::Return as u8
+    {
+        match read_return_address(vm, memory.stack_pointer - 4)
+        {
+            Some(return_address) => {
+                offset = return_address;
+                frames.push(GuestFrame {
+                    offset,
+                    base_pointer,
+                    function_index: vm.module.function_at(offset),
+                });
+            },
+            None => return frames
+        }
+    }
+
+    while frames.len() < MAX_FRAMES
+    {
+        // Bottom frame (code before the first Call) lives right above data section
+        if base_pointer - 8 < memory.data_section_size
+        {
+            break;
+        }
+
+        let saved_base_pointer = memory.read_int(base_pointer - 4);
+        let return_address = match read_return_address(vm, base_pointer - 8)
+        {
+            Some(return_address) => return_address,
+            None => break
+        };
+
+        if saved_base_pointer >= base_pointer || saved_base_pointer < 0
+        {
+            break;
+        }
+
+        base_pointer = saved_base_pointer;
+        frames.push(GuestFrame {
+            offset: return_address,
+            base_pointer,
+            function_index: vm.module.function_at(return_address),
+        });
+    }
+
+    frames
+}
+
+fn read_return_address(vm: &VM, address: i32) -> Option<usize>
+{
+    if !vm.memory.is_valid_range(address, 4)
+    {
+        return None
+    }
+
+    let return_address = vm.memory.read_int(address);
+    if return_address == HOST_RETURN_ADDRESS || return_address < 0 || return_address as usize >= vm.byte_code.bytes.len()
+    {
+        return None
+    }
+
+    Some(return_address as usize)
+}
+
+pub fn capture_stack_trace(vm: &VM, offset: usize) -> StackTrace
+{
+    let frames = walk_stack(vm, offset);
+    let names = frames.iter().map(|frame| match frame.function_index
+    {
+        Some(function_index) => vm.module.function_full_name(function_index),
+        None => String::from("<startup>")
+    }).collect();
+    let locations = frames.iter().map(|frame| vm.module.source_location(frame.offset)).collect();
+
+    StackTrace {
+        frames,
+        names,
+        locations,
+    }
+}
+
+// Runs handler of the opcode. Offset of the failed instruction is taken from VM when it panics.
+pub fn run_instruction(vm: &mut VM, functions: &[fn(&mut VM)], byte_opcode: u8, offset: usize)
+{
+    vm.history.push(offset);
+    if let Some(replay) = &mut vm.replay
+    {
+        replay.on_instruction(offset);
+    }
+
+    let handler = match functions.get(byte_opcode as usize)
+    {
+        Some(handler) => *handler,
+        None => |vm: &mut VM| panic!("Invalid opcode {}", vm.byte_code.bytes[vm.byte_code.current - 1])
+    };
+
+    // Writes made before belong to the outer instruction (native call running this one) or to host
+    let outer_offset = vm.instruction_offset;
+    let is_watching = !vm.watchpoints.is_empty();
+    if is_watching
+    {
+        vm.check_new_writes(outer_offset.unwrap_or(offset));
+    }
+    vm.instruction_offset = Some(offset);
+
+    handler(vm);
+
+    if is_watching
+    {
+        vm.check_new_writes(offset);
+    }
+    vm.instruction_offset = outer_offset;
+}
+
+// Every run of guest code goes through this, nested ones too (host calls and native callbacks start new runs).
+// Panic isn't caught for every instruction, failed one is found in VM.
+pub fn run_guarded<T>(vm: &mut VM, run: impl FnOnce(&mut VM) -> T) -> T
+{
+    match catch_run(vm, run)
+    {
+        Ok(value) => value,
+        Err(payload) => fail_run(vm, vm.instruction_offset, payload)
+    }
+}
+
+pub fn catch_run<T>(vm: &mut VM, run: impl FnOnce(&mut VM) -> T) -> Result<T, Box<dyn Any + Send>>
+{
+    vm.run_depth += 1;
+    let result = catch_unwind(AssertUnwindSafe(|| run(vm)));
+    vm.run_depth -= 1;
+    result
+}
+
+// Lets the panic go on. The innermost run remembers which instruction has failed, the outermost one reports it once.
+pub fn fail_run(vm: &mut VM, offset: Option<usize>, payload: Box<dyn Any + Send>) -> !
+{
+    if vm.fault_offset.is_none()
+    {
+        vm.fault_offset = offset;
+    }
+
+    if vm.run_depth == 0
+        && let Some(offset) = vm.fault_offset.take()
+    {
+        report_fatal_error(vm, offset, panic_message(&payload));
+    }
+
+    resume_unwind(payload)
+}
+
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> &str
+{
+    match payload.downcast_ref::<String>()
+    {
+        Some(message) => message.as_str(),
+        None => payload.downcast_ref::<&str>().copied().unwrap_or("unknown error")
+    }
+}
+
+impl Display for StackTrace
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        for (i, (frame, name)) in self.frames.iter().zip(&self.names).enumerate()
+        {
+            match &self.locations[i]
+            {
+                Some(location) => writeln!(f, "  #{i} {name} at {location} (0x{:04X}, rbp = {})", frame.offset, frame.base_pointer)?,
+                None => writeln!(f, "  #{i} {name} at 0x{:04X} (rbp = {})", frame.offset, frame.base_pointer)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    // Runs given number of instructions of the first loop iteration
+    fn stopped_vm(instructions: u64) -> VM
+    {
+        let mut vm = VM::new(call_module(1));
+        vm.set_fuel(Some(instructions));
+        run_instrumented(&mut vm, &mut None, &mut None, &mut None);
+        vm
+    }
+
+    #[test]
+    fn just_called_function_has_caller_frame()
+    {
+        // Right before FunctionPrologue of Mix
+        let vm = stopped_vm(11);
+        let trace = capture_stack_trace(&vm, vm.byte_code.current);
+        assert_eq!(trace.names, ["Program.Mix", "<startup>"]);
+        assert_eq!(trace.frames[1].offset, 0x4F);
+    }
+
+    #[test]
+    fn frames_are_walked_through_saved_base_pointers()
+    {
+        // Inside Mix after its prologue
+        let vm = stopped_vm(14);
+        let trace = capture_stack_trace(&vm, vm.byte_code.current);
+        assert_eq!(trace.names, ["Program.Mix", "<startup>"]);
+        assert_eq!((trace.frames[0].offset, trace.frames[0].base_pointer), (0x85, 33));
+        assert_eq!((trace.frames[1].offset, trace.frames[1].base_pointer), (0x4F, 0));
+        assert_eq!(trace.to_string(), "  #0 Program.Mix at 0x0085 (rbp = 33)\n  #1 <startup> at 0x004F (rbp = 0)\n");
+    }
+}
\ No newline at end of file
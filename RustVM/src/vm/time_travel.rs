@@ -0,0 +1,333 @@
+﻿use std::collections::VecDeque;
+use crate::vm::memory::{Memory, MemoryWrite};
+use crate::vm::vm::VM;
+
+// Reverse execution for debugger. Every instruction run in the main loop is logged with pointers before it
+// and old and new bytes of its writes, so memory can be rolled back and forward again without running anything.
+// Whole memory is saved every CHECKPOINT_INTERVAL instructions, long jumps back start from the nearest checkpoint.
+// Going back doesn't undo what host has done (prints, files), so when execution continues from the past
+// instructions are taken from the log until it gets back to present and only then run for real.
+// Only about HISTORY_LIMIT latest instructions are kept.
+
+const CHECKPOINT_INTERVAL: u64 = 4096;
+const HISTORY_LIMIT: usize = 1 << 20;
+
+// State before instruction which isn't in memory
+#[derive(Clone, Copy)]
+struct Registers
+{
+    pc: u32,
+    stack_pointer: i32,
+    base_pointer: i32,
+    heap_pointer: i32,
+    // Call depth tracked by debugger
+    depth: i32,
+}
+
+struct Step
+{
+    registers: Registers,
+    writes: Vec<MemoryWrite>,
+}
+
+struct Checkpoint
+{
+    // Memory before this step
+    step: u64,
+    memory: Vec<u8>,
+}
+
+pub struct TimeTravel
+{
+    steps: VecDeque<Step>,
+    checkpoints: VecDeque<Checkpoint>,
+    // Number of the first kept step since start
+    first: u64,
+    // Step VM is before, equals to the end of log in present
+    position: u64,
+    // Registers of present while VM is in the past
+    present: Option<Registers>,
+}
+
+impl Registers
+{
+    fn capture(vm: &VM, depth: i32) -> Self
+    {
+        Self {
+            pc: vm.byte_code.current as u32,
+            stack_pointer: vm.memory.stack_pointer,
+            base_pointer: vm.memory.base_pointer,
+            heap_pointer: vm.memory.heap_pointer,
+            depth,
+        }
+    }
+
+    fn apply(&self, vm: &mut VM)
+    {
+        vm.byte_code.current = self.pc as usize;
+        vm.memory.stack_pointer = self.stack_pointer;
+        vm.memory.base_pointer = self.base_pointer;
+        vm.memory.heap_pointer = self.heap_pointer;
+    }
+}
+
+impl TimeTravel
+{
+    pub fn new() -> Self
+    {
+        Self {
+            steps: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            first: 0,
+            position: 0,
+            present: None,
+        }
+    }
+
+    fn end(&self) -> u64
+    {
+        self.first + self.steps.len() as u64
+    }
+
+    pub fn is_present(&self) -> bool
+    {
+        self.position == self.end()
+    }
+
+    fn step(&self, number: u64) -> &Step
+    {
+        &self.steps[(number - self.first) as usize]
+    }
+
+    fn registers_at(&self, number: u64) -> Registers
+    {
+        match number == self.end()
+        {
+            true => self.present.expect("Present state is not saved"),
+            false => self.step(number).registers
+        }
+    }
+}
+
+impl VM
+{
+    pub fn enable_time_travel(&mut self)
+    {
+        self.time_travel = Some(Box::from(TimeTravel::new()));
+        self.update_write_recording();
+    }
+
+    // Called right before instruction runs for real
+    pub fn record_step(&mut self, depth: i32)
+    {
+        let registers = Registers::capture(self, depth);
+        let history = match &mut self.time_travel
+        {
+            Some(history) if history.is_present() => history,
+            _ => return
+        };
+
+        if history.position % CHECKPOINT_INTERVAL == 0
+        {
+            history.checkpoints.push_back(Checkpoint { step: history.position, memory: self.memory.bytes.to_vec() });
+        }
+        history.steps.push_back(Step { registers, writes: Vec::new() });
+        history.position += 1;
+
+        // Oldest checkpoint is dropped together with its steps
+        if history.steps.len() > HISTORY_LIMIT && history.checkpoints.len() > 1
+        {
+            history.checkpoints.pop_front();
+            let first = history.checkpoints[0].step;
+            history.steps.drain(..(first - history.first) as usize);
+            history.first = first;
+        }
+    }
+
+    // Called after instruction with writes it has made
+    pub fn finish_step(&mut self, writes: &[MemoryWrite])
+    {
+        if let Some(history) = &mut self.time_travel
+            && let Some(step) = history.steps.back_mut()
+        {
+            step.writes = writes.to_vec();
+        }
+    }
+
+    // Applies the current step from log instead of running it. Returns its writes or none in present.
+    pub fn time_travel_step(&mut self) -> Option<Vec<MemoryWrite>>
+    {
+        let history = self.time_travel.as_mut()?;
+        if history.is_present()
+        {
+            return None
+        }
+
+        let writes = history.step(history.position).writes.clone();
+        redo(&mut self.memory, &writes);
+        history.position += 1;
+
+        let registers = history.registers_at(history.position);
+        if history.is_present()
+        {
+            history.present = None;
+        }
+        registers.apply(self);
+
+        Some(writes)
+    }
+
+    // Moves VM to the state before given step. Takes call depth of the current state and returns one of the new.
+    pub fn travel_to(&mut self, target: u64, depth: i32) -> i32
+    {
+        let current = Registers::capture(self, depth);
+        let history = self.time_travel.as_mut().expect("Time travel is not enabled");
+        if history.is_present()
+        {
+            history.present = Some(current);
+        }
+        let target = target.clamp(history.first, history.end());
+
+        if target < history.position
+        {
+            let checkpoint = history.checkpoints.iter().rev().find(|checkpoint| checkpoint.step <= target).unwrap();
+            if target - checkpoint.step < history.position - target
+            {
+                self.memory.bytes.copy_from_slice(&checkpoint.memory);
+                history.position = checkpoint.step;
+            }
+        }
+        while history.position > target
+        {
+            history.position -= 1;
+            undo(&mut self.memory, &history.step(history.position).writes);
+        }
+        while history.position < target
+        {
+            redo(&mut self.memory, &history.step(history.position).writes);
+            history.position += 1;
+        }
+
+        let registers = history.registers_at(target);
+        if history.is_present()
+        {
+            history.present = None;
+        }
+        registers.apply(self);
+
+        registers.depth
+    }
+
+    // Latest step before the current one with instruction at offset (first argument) that made given writes
+    pub fn find_step_back(&self, predicate: impl Fn(usize, &[MemoryWrite]) -> bool) -> Option<u64>
+    {
+        let history = self.time_travel.as_ref()?;
+        (history.first..history.position).rev().find(|number| {
+            let step = history.step(*number);
+            predicate(step.registers.pc as usize, &step.writes)
+        })
+    }
+
+    // The oldest step still kept in log
+    pub fn first_step(&self) -> Option<u64>
+    {
+        self.time_travel.as_ref().map(|history| history.first)
+    }
+}
+
+// Writes are applied without recording, they are already in log
+fn redo(memory: &mut Memory, writes: &[MemoryWrite])
+{
+    for write in writes
+    {
+        memory.write_slice_unrecorded(write.address, &write.new);
+    }
+}
+
+fn undo(memory: &mut Memory, writes: &[MemoryWrite])
+{
+    for write in writes.iter().rev()
+    {
+        memory.write_slice_unrecorded(write.address, &write.old);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::debugger::{ConsoleFrontend, DebugFrontend, Debugger, PauseReason};
+    use crate::vm::fuel::RunStatus;
+    use crate::vm::run_instrumented;
+
+    // Call of Mix in the loop and address of the sum (rbp+8)
+    const CALL: usize = 0x4F;
+    const SUM: i32 = 8;
+
+    // Goes back to the previous hit once, on the third one. Keeps the sum seen at every pause.
+    struct ReverseOnce(Rc<RefCell<Vec<i32>>>);
+
+    impl DebugFrontend for ReverseOnce
+    {
+        fn on_pause(&mut self, debugger: &mut Debugger, vm: &mut VM, reason: PauseReason)
+        {
+            assert_eq!(reason, PauseReason::Breakpoint);
+            let mut sums = self.0.borrow_mut();
+            sums.push(vm.memory.read_int(SUM));
+            if sums.len() == 3
+            {
+                assert!(debugger.reverse_continue(vm));
+                assert_eq!(vm.byte_code.current, CALL);
+                sums.push(vm.memory.read_int(SUM));
+            }
+        }
+    }
+
+    #[test]
+    fn travel_back_and_forward_restores_state()
+    {
+        let mut vm = VM::new(call_module(3));
+        vm.enable_time_travel();
+        let mut debugger = Debugger::new(Box::from(ConsoleFrontend));
+        debugger.stop_on_entry = false;
+        run_instrumented(&mut vm, &mut Some(debugger), &mut None, &mut None);
+
+        let end = vm.find_step_back(|_, _| true).unwrap() + 1;
+        let memory = vm.memory.bytes.to_vec();
+        let pc = vm.byte_code.current;
+        assert_eq!(vm.memory.read_int(SUM), 3);
+
+        vm.travel_to(0, 0);
+        assert_eq!((vm.byte_code.current, vm.memory.read_int(SUM)), (0, 0));
+
+        // Before the last write into the sum it has only the first two values added
+        vm.travel_to(end, 0);
+        let last_write = vm.find_step_back(|_, writes| writes.iter().any(|write| write.address == SUM)).unwrap();
+        vm.travel_to(last_write, 0);
+        assert_eq!(vm.memory.read_int(SUM), 1);
+
+        vm.travel_to(end, 0);
+        assert!(vm.time_travel.as_ref().unwrap().is_present());
+        assert_eq!((vm.memory.bytes.to_vec(), vm.byte_code.current), (memory, pc));
+    }
+
+    #[test]
+    fn reverse_continue_replays_to_present()
+    {
+        let mut vm = VM::new(call_module(3));
+        vm.enable_time_travel();
+        let sums = Rc::new(RefCell::new(Vec::new()));
+        let mut debugger = Debugger::new(Box::from(ReverseOnce(sums.clone())));
+        debugger.stop_on_entry = false;
+        debugger.breakpoints.insert(CALL);
+
+        let status = run_instrumented(&mut vm, &mut Some(debugger), &mut None, &mut None);
+        assert!(matches!(status, RunStatus::Finished));
+        // Second hit is replayed from log, the third one is hit again
+        assert_eq!(*sums.borrow(), [0, 0, 1, 0, 1]);
+        assert_eq!(vm.memory.read_int(SUM), 3);
+    }
+}
\ No newline at end of file
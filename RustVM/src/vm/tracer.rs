@@ -0,0 +1,314 @@
+﻿use std::fs::File;
+use std::io::{BufWriter, Write};
+use serde_json::json;
+use crate::vm::compiled_module::CompiledModule;
+use crate::vm::disassembler::decode;
+use crate::vm::memory::{Memory, MemoryWrite};
+use crate::vm::vm::VM;
+
+// Logs every executed instruction with its memory side effects (port of AVM/MemoryLogger.cs)
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat
+{
+    Text,
+    JsonLines,
+}
+
+// Registers before instruction execution
+#[derive(Clone, Copy)]
+pub struct TraceStart
+{
+    pub offset: usize,
+    pub stack_pointer: i32,
+    pub base_pointer: i32,
+    pub heap_pointer: i32,
+}
+
+pub struct Tracer
+{
+    pub enabled: bool,
+    pub format: TraceFormat,
+    // Only instructions of these functions are traced (all if empty)
+    pub functions: Vec<usize>,
+    // Only instructions writing into this range are traced
+    pub memory_range: Option<(i32, i32)>,
+
+    output: Option<Box<dyn Write>>,
+}
+
+const PAD: usize = 30;
+
+impl Tracer
+{
+    pub fn new() -> Self
+    {
+        Self {
+            enabled: false,
+            format: TraceFormat::Text,
+            functions: Vec::new(),
+            memory_range: None,
+            output: None,
+        }
+    }
+
+    pub fn open(&mut self, path: &str, format: TraceFormat)
+    {
+        let file = File::create(path).unwrap_or_else(|e| panic!("Failed to create trace file '{path}': {e}"));
+        self.output = Some(Box::from(BufWriter::new(file)));
+        self.format = format;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>)
+    {
+        self.output = Some(output);
+    }
+
+    pub fn has_output(&self) -> bool
+    {
+        self.output.is_some()
+    }
+
+    pub fn is_active(&self) -> bool
+    {
+        self.enabled && self.output.is_some()
+    }
+
+    pub fn begin(&self, memory: &Memory, offset: usize) -> Option<TraceStart>
+    {
+        match self.is_active()
+        {
+            true => Some(TraceStart {
+                offset,
+                stack_pointer: memory.stack_pointer,
+                base_pointer: memory.base_pointer,
+                heap_pointer: memory.heap_pointer,
+            }),
+            false => None
+        }
+    }
+
+    // Logs instruction started at start.offset, which has made given writes and moved current to next_offset
+    pub fn record(&mut self, module: &CompiledModule, bytes: &[u8], memory: &Memory, start: TraceStart, writes: &[MemoryWrite], next_offset: usize)
+    {
+        let function_index = module.function_at(start.offset);
+
+        if !self.functions.is_empty() && function_index.is_none_or(|i| !self.functions.contains(&i))
+        {
+            return;
+        }
+        if let Some((address, size)) = self.memory_range
+            && writes.iter().all(|w| w.address >= address + size || w.address + w.new.len() as i32 <= address)
+        {
+            return;
+        }
+
+        let function_name = match function_index
+        {
+            Some(function_index) => module.function_full_name(function_index),
+            None => String::from("<startup>")
+        };
+        let instruction = decode(bytes, start.offset);
+
+        let text = match self.format
+        {
+            TraceFormat::Text => {
+                let mut text = match &instruction
+                {
+                    Some(instruction) => format!("0x{:04X} {:<40} in {}\n", start.offset, instruction.describe(module), function_name),
+                    None => format!("0x{:04X} <invalid {}> in {}\n", start.offset, bytes[start.offset], function_name)
+                };
+
+                for line in side_effects(memory, &start, writes)
+                {
+                    text.push_str("    ");
+                    text.push_str(&line);
+                    text.push('\n');
+                }
+                if instruction.is_some_and(|i| start.offset + i.size != next_offset)
+                {
+                    text.push_str(&format!("    Jump to 0x{:04X}\n", next_offset));
+                }
+                text
+            },
+            TraceFormat::JsonLines => {
+                let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+                let line = json!({
+                    "offset": start.offset,
+                    "function": function_name,
+                    "opcode": instruction.as_ref().map(|i| format!("{:?}", i.opcode)),
+                    "operands": instruction.as_ref().map(|i| i.operand_strings(module)),
+                    "writes": writes.iter().map(|w| json!({ "address": w.address, "old": hex(&w.old), "new": hex(&w.new) })).collect::<Vec<_>>(),
+                    "rsp": [start.stack_pointer, memory.stack_pointer],
+                    "rbp": [start.base_pointer, memory.base_pointer],
+                    "heap": [start.heap_pointer, memory.heap_pointer],
+                    "next": next_offset
+                });
+                format!("{}\n", line)
+            }
+        };
+
+        if let Some(output) = &mut self.output
+        {
+            output.write_all(text.as_bytes()).unwrap();
+        }
+    }
+
+    pub fn flush(&mut self)
+    {
+        if let Some(output) = &mut self.output
+        {
+            output.flush().unwrap();
+        }
+    }
+}
+
+impl VM
+{
+    // Tracing can be switched on and off while guest is running
+    pub fn set_tracing(&mut self, enabled: bool)
+    {
+        self.tracer.enabled = enabled;
+        if !enabled
+        {
+            self.tracer.flush();
+        }
+        self.update_write_recording();
+    }
+}
+
+// Describes pointer moves and writes the same way MemoryLogger does
+fn side_effects(memory: &Memory, start: &TraceStart, writes: &[MemoryWrite]) -> Vec<String>
+{
+    let mut lines = Vec::new();
+
+    if memory.stack_pointer > start.stack_pointer
+    {
+        lines.push(format!("Allocate stack {}..{}", start.stack_pointer, memory.stack_pointer));
+    }
+    else if memory.stack_pointer < start.stack_pointer
+    {
+        let range = format!("Deallocate stack {}..{}", memory.stack_pointer, start.stack_pointer);
+        lines.push(format!("{:<PAD$}{}", range, bytes_to_string(memory.read(memory.stack_pointer, start.stack_pointer - memory.stack_pointer))));
+    }
+    if memory.heap_pointer != start.heap_pointer
+    {
+        lines.push(format!("Allocate heap {}..{}", start.heap_pointer, memory.heap_pointer));
+    }
+    if memory.base_pointer != start.base_pointer
+    {
+        lines.push(format!("Base pointer {} => {}", start.base_pointer, memory.base_pointer));
+    }
+
+    for write in writes
+    {
+        let range = match write.new.len()
+        {
+            1 => format!("Write at {}:", write.address),
+            _ => format!("Write at {}..{}:", write.address, write.address + write.new.len() as i32)
+        };
+        lines.push(format!("{:<PAD$}{} => {}", range, bytes_to_string(&write.old), bytes_to_string(&write.new)));
+    }
+
+    lines
+}
+
+// Zero bytes are shown as "--" until the highest non-zero byte
+fn bytes_to_string(bytes: &[u8]) -> String
+{
+    let mut strings = vec![String::new(); bytes.len()];
+    let mut had_any_value = false;
+
+    for i in (0..bytes.len()).rev()
+    {
+        if bytes[i] != 0
+        {
+            had_any_value = true;
+        }
+
+        strings[i] = match !had_any_value && bytes[i] == 0
+        {
+            true => String::from("--"),
+            false => format!("{:02x}", bytes[i])
+        };
+    }
+
+    strings.join(" ")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use serde_json::Value as JsonValue;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput
+    {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+        {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat, setup: impl FnOnce(&mut Tracer)) -> String
+    {
+        let mut vm = VM::new(call_module(1));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        vm.tracer.set_output(Box::from(SharedOutput(output.clone())));
+        vm.tracer.format = format;
+        setup(&mut vm.tracer);
+        vm.set_tracing(true);
+
+        run_instrumented(&mut vm, &mut None, &mut None, &mut None);
+        vm.tracer.flush();
+        String::from_utf8(output.borrow().clone()).unwrap()
+    }
+
+    #[test]
+    fn zero_bytes_are_dashed_until_last_value()
+    {
+        assert_eq!(bytes_to_string(&[1, 0, 2, 0]), "01 00 02 --");
+        assert_eq!(bytes_to_string(&[0, 0]), "-- --");
+    }
+
+    #[test]
+    fn text_trace_shows_side_effects()
+    {
+        let text = trace(TraceFormat::Text, |_| {});
+        assert!(text.starts_with("0x0000 "));
+        assert!(text.contains("    Allocate stack 0..4\n"));
+        assert!(text.contains("    Jump to 0x"));
+        assert!(text.contains(" in Program.Mix\n"));
+    }
+
+    #[test]
+    fn functions_and_memory_range_filter_trace()
+    {
+        let text = trace(TraceFormat::Text, |tracer| tracer.functions.push(0));
+        let instructions: Vec<&str> = text.lines().filter(|line| !line.starts_with(' ')).collect();
+        assert!(!instructions.is_empty());
+        assert!(instructions.iter().all(|line| line.ends_with(" in Program.Mix")));
+
+        // Only writes of the loop counter at rbp+0
+        let text = trace(TraceFormat::JsonLines, |tracer| tracer.memory_range = Some((0, 4)));
+        for line in text.lines()
+        {
+            let line: JsonValue = serde_json::from_str(line).unwrap();
+            assert!(line["writes"].as_array().unwrap().iter().any(|write| write["address"].as_i64().unwrap() < 4));
+        }
+        assert_eq!(text.lines().count(), 2, "{text}");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,59 @@
+﻿use std::fmt::{Display, Formatter};
+
+// Value passed between host and guest code
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value
+{
+    Void,
+    Bool(bool),
+    Byte(u8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Ptr(i32),
+    Str(String),
+    // Value-type struct, copied as is
+    Bytes(Vec<u8>),
+    // Function with several returns
+    Tuple(Vec<Value>),
+}
+
+impl Value
+{
+    pub fn as_i64(&self) -> Option<i64>
+    {
+        match self
+        {
+            Value::Bool(v) => Some(*v as i64),
+            Value::Byte(v) => Some(*v as i64),
+            Value::Short(v) => Some(*v as i64),
+            Value::Int(v) => Some(*v as i64),
+            Value::Long(v) => Some(*v),
+            Value::Ptr(v) => Some(*v as i64),
+            _ => None
+        }
+    }
+}
+
+impl Display for Value
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Value::Void => write!(f, "void"),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Byte(v) => write!(f, "{v}"),
+            Value::Short(v) => write!(f, "{v}"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::Long(v) => write!(f, "{v}"),
+            Value::Ptr(v) => write!(f, "<0x{v:X}>"),
+            Value::Str(v) => write!(f, "\"{v}\""),
+            Value::Bytes(v) => write!(f, "{v:?}"),
+            Value::Tuple(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "({})", values.join(", "))
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -1,16 +1,129 @@
-﻿use crate::vm::binary_file::BinaryFile;
+﻿use std::io::{stdout, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
+use crate::vm::binary_file::{BinaryFile, SharedBytes};
+use crate::vm::cancellation::{CancellationToken, CHECK_INTERVAL};
+use crate::vm::clock::{Clock, SystemClock};
 use crate::vm::compiled_module::CompiledModule;
+use crate::vm::crash_dump::InstructionHistory;
+use crate::vm::file_sandbox::FileSandbox;
+use crate::vm::host_call::PendingCall;
+#[cfg(feature = "jit")]
+use crate::vm::jit::Jit;
 use crate::vm::memory::Memory;
+use crate::vm::predecode::{decode_program, DecodedProgram};
+use crate::vm::random::Random;
+use crate::vm::replay::Replay;
+use crate::vm::time_travel::TimeTravel;
+use crate::vm::tracer::Tracer;
+use crate::vm::watchpoints::Watchpoints;
 
 pub struct VM
 {
-    pub byte_code: Box<BinaryFile>,
+    pub byte_code: Box<BinaryFile<SharedBytes>>,
+    // Same byte code decoded at load time (none if it can't be decoded)
+    pub program: Option<Rc<DecodedProgram>>,
+    #[cfg(feature = "jit")]
+    pub jit: Option<Box<Jit>>,
+    // Instructions left to run (none if not limited)
+    pub fuel: Option<u64>,
+    pub cancellation: Option<CancellationToken>,
+    // Wall-clock time when execution is stopped
+    pub deadline: Option<Instant>,
+    // Instructions until the next check of cancellation and deadline
+    pub cancellation_countdown: u32,
+    // Host call paused by fuel (none if there is no such call)
+    pub pending_call: Option<PendingCall>,
     pub memory: Memory,
     pub module: Box<CompiledModule>,
+    pub files: FileSandbox,
+    pub clock: Box<dyn Clock>,
+    pub random: Random,
+    pub watchpoints: Watchpoints,
+    pub tracer: Tracer,
+    // Recording or replaying nondeterministic inputs (none if neither)
+    pub replay: Option<Box<Replay>>,
+    // Log of executed instructions for reverse debugging (none if disabled)
+    pub time_travel: Option<Box<TimeTravel>>,
+
+    pub history: InstructionHistory,
+    // Instruction which runs now, nested instructions (run by native calls) replace it while they run
+    pub instruction_offset: Option<usize>,
+    // Nesting of guest runs and the failed instruction which the outermost run reports
+    pub run_depth: u32,
+    pub fault_offset: Option<usize>,
+    // Where crash dump is written on fatal error (none if not set)
+    pub dump_path: Option<PathBuf>,
+
+    pub args_address: i32,
+    pub env_allow_list: Vec<String>,
+
+    // Where guest prints go
+    pub output: Box<dyn Write>,
 }
 
 impl VM
 {
+    pub fn new(module: CompiledModule) -> Self
+    {
+        Self {
+            byte_code: Box::from(BinaryFile::new(module.managed_code.bytes.clone())),
+            program: decode_program(&module.managed_code.bytes).map(Rc::new),
+            #[cfg(feature = "jit")]
+            jit: None,
+            fuel: None,
+            cancellation: None,
+            deadline: None,
+            cancellation_countdown: CHECK_INTERVAL,
+            pending_call: None,
+            memory: Memory::new(),
+            module: Box::from(module),
+            files: FileSandbox::disabled(),
+            clock: Box::from(SystemClock::new()),
+            random: Random::from_time(),
+            watchpoints: Watchpoints::new(),
+            tracer: Tracer::new(),
+            replay: None,
+            time_travel: None,
+            history: InstructionHistory::new(),
+            instruction_offset: None,
+            run_depth: 0,
+            fault_offset: None,
+            dump_path: None,
+            args_address: 0,
+            env_allow_list: Vec::new(),
+            output: Box::from(stdout()),
+        }
+    }
+
+    // Places guest arguments on heap as array: int count followed by pointers to strings
+    pub fn place_args(&mut self, args: &[String])
+    {
+        // Array and strings with their lengths
+        let size: i64 = 4 + args.iter().map(|arg| 8 + arg.len() as i64).sum::<i64>();
+        if size > self.memory.heap_left() as i64
+        {
+            panic!("Guest arguments need {size} bytes of heap, but only {} bytes are available", self.memory.heap_left())
+        }
+
+        let pointers: Vec<i32> = args.iter().map(|arg| self.memory.allocate_heap_string(arg)).collect();
+
+        self.args_address = self.memory.allocate_heap(4 + 4 * pointers.len() as i32);
+        self.memory.write_int(self.args_address, pointers.len() as i32);
+
+        for (i, pointer) in pointers.iter().enumerate()
+        {
+            self.memory.write_int(self.args_address + 4 + 4 * i as i32, *pointer);
+        }
+    }
+
+    // Memory writes are recorded only while somebody needs them
+    pub fn update_write_recording(&mut self)
+    {
+        self.memory.record_writes = !self.watchpoints.is_empty() || self.tracer.is_active() || self.time_travel.is_some();
+    }
+
     pub fn next_address(&mut self) -> i32
     {
         let rbp_offset = self.byte_code.next_int();
@@ -0,0 +1,274 @@
+﻿use std::io::Write;
+use std::mem;
+use crate::vm::memory::{Memory, MemoryWrite};
+use crate::vm::vm::VM;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchAction
+{
+    Pause,
+    Log,
+}
+
+pub struct Watchpoint
+{
+    pub id: u32,
+    pub address: i32,
+    pub size: i32,
+    pub action: WatchAction,
+}
+
+pub struct WatchHit
+{
+    pub watchpoint_id: u32,
+    pub action: WatchAction,
+    // Offset of instruction which has made the write
+    pub offset: usize,
+    pub address: i32,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+pub struct Watchpoints
+{
+    pub list: Vec<Watchpoint>,
+    next_id: u32,
+
+    // Recorded writes before this index are already checked
+    pub checked_writes: usize,
+    // Pausing watchpoint was hit, debugger hasn't stopped yet
+    pub is_pause_requested: bool,
+}
+
+impl Watchpoints
+{
+    pub fn new() -> Self
+    {
+        Self {
+            list: Vec::new(),
+            next_id: 1,
+            checked_writes: 0,
+            is_pause_requested: false,
+        }
+    }
+
+    // Range is "address[:size]" or "rbp+offset[:size]". Rbp-relative ranges are resolved using current rbp.
+    pub fn parse_range(memory: &Memory, text: &str) -> Option<(i32, i32)>
+    {
+        let (location, size) = match text.split_once(':')
+        {
+            Some((location, size)) => (location, size.parse().ok()?),
+            None => (text, 4)
+        };
+
+        let address = match location.strip_prefix("rbp")
+        {
+            Some("") => memory.base_pointer,
+            Some(offset) => memory.to_abs(offset.strip_prefix('+').unwrap_or(offset).parse().ok()?),
+            None => location.parse().ok()?
+        };
+
+        match memory.is_valid_range(address, size) && size > 0
+        {
+            true => Some((address, size)),
+            false => None
+        }
+    }
+
+    pub fn add(&mut self, address: i32, size: i32, action: WatchAction) -> u32
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.list.push(Watchpoint {
+            id,
+            address,
+            size,
+            action,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool
+    {
+        let count = self.list.len();
+        self.list.retain(|w| w.id != id);
+        self.list.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.list.is_empty()
+    }
+
+    pub fn check(&self, writes: &[MemoryWrite], offset: usize) -> Vec<WatchHit>
+    {
+        let mut hits = Vec::new();
+
+        for write in writes
+        {
+            let write_end = write.address + write.new.len() as i32;
+
+            for watchpoint in &self.list
+            {
+                // Writing the same bytes doesn't change anything
+                if write.address < watchpoint.address + watchpoint.size && watchpoint.address < write_end && write.old != write.new
+                {
+                    hits.push(WatchHit {
+                        watchpoint_id: watchpoint.id,
+                        action: watchpoint.action,
+                        offset,
+                        address: write.address,
+                        old: write.old.clone(),
+                        new: write.new.clone(),
+                    });
+                }
+            }
+        }
+
+        hits
+    }
+}
+
+impl WatchHit
+{
+    pub fn describe(&self) -> String
+    {
+        let to_hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ");
+
+        format!("Watchpoint #{} hit by instruction at 0x{:04X}: write {}..{}: {} => {}",
+            self.watchpoint_id, self.offset, self.address, self.address + self.new.len() as i32, to_hex(&self.old), to_hex(&self.new))
+    }
+}
+
+impl VM
+{
+    pub fn add_watchpoint(&mut self, address: i32, size: i32, action: WatchAction) -> u32
+    {
+        let id = self.watchpoints.add(address, size, action);
+        self.update_write_recording();
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: u32) -> bool
+    {
+        let removed = self.watchpoints.remove(id);
+        self.update_write_recording();
+        removed
+    }
+
+    // Returns watchpoints hit by writes made by instruction at given offset
+    pub fn check_watchpoints(&self, writes: &[MemoryWrite], offset: usize) -> Vec<WatchHit>
+    {
+        self.watchpoints.check(writes, offset)
+    }
+
+    // Checks writes recorded since the previous check, they are made by instruction at given offset
+    pub fn check_new_writes(&mut self, offset: usize)
+    {
+        let start = self.watchpoints.checked_writes;
+        self.watchpoints.checked_writes = self.memory.writes.len();
+
+        let hits = self.watchpoints.check(&self.memory.writes[start..], offset);
+        self.report_watch_hits(&hits);
+    }
+
+    // Prints hits right away, pausing is left for debugger
+    pub fn report_watch_hits(&mut self, hits: &[WatchHit])
+    {
+        for hit in hits
+        {
+            let function_name = match self.module.function_at(hit.offset)
+            {
+                Some(function_index) => self.module.function_full_name(function_index),
+                None => String::from("<startup>")
+            };
+            let line = format!("{} in {}\n", hit.describe(), function_name);
+            self.output.write_all(line.as_bytes()).unwrap();
+        }
+
+        if hits.iter().any(|hit| hit.action == WatchAction::Pause)
+        {
+            self.watchpoints.is_pause_requested = true;
+        }
+    }
+
+    // Whether pausing watchpoint was hit since the previous call
+    pub fn take_watch_pause(&mut self) -> bool
+    {
+        mem::take(&mut self.watchpoints.is_pause_requested)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::*;
+    use crate::vm::bench::call_module;
+    use crate::vm::run_instrumented;
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput
+    {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+        {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    fn write(address: i32, old: &[u8], new: &[u8]) -> MemoryWrite
+    {
+        MemoryWrite { address, old: old.to_vec(), new: new.to_vec() }
+    }
+
+    #[test]
+    fn only_changing_overlapping_writes_hit()
+    {
+        let mut watchpoints = Watchpoints::new();
+        let id = watchpoints.add(8, 4, WatchAction::Pause);
+
+        let writes = [
+            write(4, &[0; 4], &[1; 4]),
+            write(12, &[0; 4], &[1; 4]),
+            write(8, &[5; 4], &[5; 4]),
+            write(6, &[0; 4], &[1, 1, 1, 1]),
+        ];
+        let hits = watchpoints.check(&writes, 42);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].watchpoint_id, hits[0].offset, hits[0].address), (id, 42, 6));
+
+        assert!(watchpoints.remove(id));
+        assert!(watchpoints.is_empty());
+    }
+
+    #[test]
+    fn run_reports_writes_into_watched_variable()
+    {
+        let mut vm = VM::new(call_module(3));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        vm.output = Box::from(SharedOutput(output.clone()));
+
+        // Sum of the loop is at rbp+8
+        let id = vm.add_watchpoint(8, 4, WatchAction::Pause);
+        assert!(vm.memory.record_writes);
+        run_instrumented(&mut vm, &mut None, &mut None, &mut None);
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        let hits: Vec<&str> = output.lines().collect();
+        assert_eq!(hits.len(), 2, "{output}");
+        assert!(hits[1].starts_with(&format!("Watchpoint #{id} hit by instruction at")));
+        assert!(hits[1].contains("01 00 00 00 => 03 00 00 00"));
+
+        vm.remove_watchpoint(id);
+        assert!(!vm.memory.record_writes);
+    }
+}
\ No newline at end of file